@@ -0,0 +1,371 @@
+use bevy::prelude::*;
+use bevy::utils::HashMap;
+use clap::ValueEnum;
+use leafwing_input_manager::prelude::*;
+use lightyear::prelude::*;
+use rand::Rng;
+use serde::Deserialize;
+
+use crate::discovery::ServerInfo;
+use crate::game::minion::{MinionPosition, MinionTarget, UnitKind};
+use crate::game::player::{PlayerActions, PlayerId, PlayerPosition, Team};
+use crate::game::resource::{Apples, ItemPos};
+use crate::game::{ClaimSecret, ClientMessage, InputHandling, OwnedBy, Role};
+use crate::networking::IsServer;
+
+/// Computer controlled players. They live on the server and take a player slot like anyone else,
+/// but instead of a connection they have a [`Ai`] that sends the server the same messages and
+/// inputs a client would: joining, naming itself, spawning units, gathering apples with them,
+/// spending the apples on a barracks and the minions it trains, and sending them at the other
+/// team.
+pub struct AiPlugin;
+
+impl Plugin for AiPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<AiOpponents>()
+            .add_event::<AddAi>()
+            .add_systems(OnEnter(IsServer), add_configured_ais)
+            .add_systems(
+                FixedUpdate,
+                (spawn_ais, think)
+                    .chain()
+                    .before(InputHandling)
+                    .run_if(in_state(IsServer)),
+            )
+            .add_systems(OnExit(IsServer), remove_ais);
+    }
+}
+
+/// AI players get local client ids from here up, far from the host's own.
+const FIRST_AI_ID: u64 = 1 << 48;
+/// How far from the middle each team's AI players keep their player.
+const HOME_DISTANCE: f32 = 6.0;
+/// Units closer than this to where they were sent are done.
+const ARRIVED: f32 = 0.1;
+
+#[derive(ValueEnum, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Difficulty {
+    /// Slow to react, never attacks
+    Easy,
+    #[default]
+    Medium,
+    Hard,
+}
+
+impl Difficulty {
+    pub const ALL: [Difficulty; 3] = [Self::Easy, Self::Medium, Self::Hard];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Difficulty::Easy => "Easy",
+            Difficulty::Medium => "Medium",
+            Difficulty::Hard => "Hard",
+        }
+    }
+
+    fn tactics(self) -> Tactics {
+        match self {
+            Difficulty::Easy => Tactics {
+                think_interval: 2.0,
+                max_units: 5,
+                attack_from: None,
+                attack_share: 0.0,
+                aim_error: 1.5,
+                retarget: false,
+            },
+            Difficulty::Medium => Tactics {
+                think_interval: 1.0,
+                max_units: 12,
+                attack_from: Some(8),
+                attack_share: 0.5,
+                aim_error: 0.5,
+                retarget: false,
+            },
+            Difficulty::Hard => Tactics {
+                think_interval: 0.25,
+                max_units: 25,
+                attack_from: Some(5),
+                attack_share: 0.7,
+                aim_error: 0.0,
+                retarget: true,
+            },
+        }
+    }
+}
+
+/// How a difficulty plays.
+struct Tactics {
+    /// Seconds between decisions.
+    think_interval: f32,
+    /// Units it stops spawning at.
+    max_units: usize,
+    /// Units it needs before it sends any at the enemy, if it ever does.
+    attack_from: Option<usize>,
+    /// Fraction of its units that attack once it does, the rest keep gathering.
+    attack_share: f32,
+    /// How far off its orders land, in world units.
+    aim_error: f32,
+    /// Whether attackers follow enemies that moved, rather than only getting orders when idle.
+    retarget: bool,
+}
+
+/// AI players to add to every match this app hosts or serves. When continuing a saved match they
+/// get the slots of the AI players in it back, like clients reconnecting would.
+#[derive(Resource, Clone, Debug, Default)]
+pub struct AiOpponents(pub Vec<Difficulty>);
+
+/// Adds an AI player to the match being hosted.
+#[derive(Event, Clone, Copy, Debug)]
+pub struct AddAi(pub Difficulty);
+
+/// The mind of an AI player, on the server only.
+#[derive(Component, Debug)]
+pub struct Ai {
+    pub client_id: ClientId,
+    pub difficulty: Difficulty,
+    index: u64,
+    next_think: f32,
+}
+
+impl Ai {
+    fn name(&self) -> String {
+        format!("AI {} ({})", self.index + 1, self.difficulty.name())
+    }
+}
+
+fn add_configured_ais(opponents: Res<AiOpponents>, mut add: EventWriter<AddAi>) {
+    add.send_batch(opponents.0.iter().map(|&difficulty| AddAi(difficulty)));
+}
+
+fn spawn_ais(
+    mut commands: Commands,
+    mut events: EventReader<AddAi>,
+    ais: Query<&Ai>,
+    players: Query<&PlayerId, With<Replicating>>,
+    mut messages: EventWriter<ServerMessageEvent<ClientMessage>>,
+) {
+    // Numbered the same way every match, so they get their slots back in a saved one
+    let mut next_index = ais.iter().map(|ai| ai.index + 1).max().unwrap_or(0);
+    // AI players that haven't joined yet take their slot all the same
+    let humans = players
+        .iter()
+        .filter(|player_id| !ais.iter().any(|ai| ai.client_id == player_id.0))
+        .count();
+    let mut taken = humans + ais.iter().count();
+    for &AddAi(difficulty) in events.read() {
+        if taken >= ServerInfo::MAX_PLAYERS as usize {
+            warn!(?difficulty, "Not adding an AI player, the match is full");
+            continue;
+        }
+        taken += 1;
+        let ai = Ai {
+            client_id: ClientId::Local(FIRST_AI_ID + next_index),
+            difficulty,
+            index: next_index,
+            next_think: 0.0,
+        };
+        next_index += 1;
+        info!(client_id = %ai.client_id, ?difficulty, "Adding AI player");
+        // Named right away, like clients do, so the join is announced under the name
+        messages.send_batch([
+            // Their client id is enough to get their slot back, the secret goes unused
+            ServerMessageEvent::new(
+                ClientMessage::Join(Role::Player, ClaimSecret::default()),
+                ai.client_id,
+            ),
+            ServerMessageEvent::new(ClientMessage::SetName(ai.name()), ai.client_id),
+        ]);
+        commands.spawn((Name::new(ai.name()), ai));
+    }
+}
+
+fn remove_ais(mut commands: Commands, ais: Query<Entity, With<Ai>>) {
+    for ai in &ais {
+        commands.entity(ai).despawn();
+    }
+}
+
+fn think(
+    mut ais: Query<&mut Ai>,
+    mut players: Query<
+        (
+            &PlayerId,
+            &Team,
+            &PlayerPosition,
+            &mut ActionState<PlayerActions>,
+        ),
+        With<Replicating>,
+    >,
+    units: Query<(Entity, &MinionPosition, &MinionTarget, &UnitKind, &OwnedBy), With<Replicating>>,
+    items: Query<&ItemPos>,
+    apples: Query<&Apples, With<Replicating>>,
+    mut messages: EventWriter<ServerMessageEvent<ClientMessage>>,
+    time: Res<Time<Fixed>>,
+) {
+    let teams = players
+        .iter()
+        .map(|(id, &team, ..)| (id.0, team))
+        .collect::<HashMap<_, _>>();
+    let player_positions = players
+        .iter()
+        .map(|(id, _, position, _)| (id.0, position.0))
+        .collect::<Vec<_>>();
+    let rng = &mut rand::thread_rng();
+
+    for mut ai in &mut ais {
+        // Nothing to do until the server has let it join
+        let Some((_, &team, position, mut action_state)) =
+            players.iter_mut().find(|(id, ..)| id.0 == ai.client_id)
+        else {
+            continue;
+        };
+        let client_id = ai.client_id;
+
+        // Spawning happens on a fresh press, so let go of it between presses
+        action_state.release(&PlayerActions::Spawn);
+        let home = Vec2::new(
+            if team.0 == 0 {
+                -HOME_DISTANCE
+            } else {
+                HOME_DISTANCE
+            },
+            0.0,
+        );
+        action_state.set_axis_pair(
+            &PlayerActions::Move,
+            (home - position.0).clamp_length_max(1.0),
+        );
+
+        let now = time.elapsed_secs();
+        if now < ai.next_think {
+            continue;
+        }
+        let tactics = ai.difficulty.tactics();
+        ai.next_think = now + tactics.think_interval;
+        let mut aim = |target: Vec2| {
+            target
+                + Vec2::new(
+                    rng.gen_range(-1.0..=1.0) * tactics.aim_error,
+                    rng.gen_range(-1.0..=1.0) * tactics.aim_error,
+                )
+        };
+        let mut own = units
+            .iter()
+            .filter(|(.., owner)| owner.0 == client_id)
+            .collect::<Vec<_>>();
+        own.sort_by_key(|&(entity, ..)| entity);
+        let (buildings, mut own): (Vec<_>, Vec<_>) = own
+            .into_iter()
+            .partition(|(_, _, _, kind, _)| kind.is_building());
+
+        let mut apples = apples
+            .get_single()
+            .ok()
+            .and_then(|apples| apples.get(&client_id).copied())
+            .unwrap_or(0);
+
+        // Minions are spawned by hand only to get going, after that the apples are saved for a
+        // barracks
+        let spawn_cost = UnitKind::Minion.cost();
+        if (own.is_empty() || (!buildings.is_empty() && own.len() < tactics.max_units))
+            && apples >= spawn_cost
+        {
+            apples -= spawn_cost;
+            let cursor = aim(position.0 + Vec2::new(0.0, -1.0));
+            action_state.set_axis_pair(&PlayerActions::Cursor, cursor);
+            action_state.press(&PlayerActions::Spawn);
+        }
+
+        // Spend the apples like players do from the unit panel: on a barracks first, then on
+        // the minions it trains
+        let mut train = |producer: Entity, kind: UnitKind| {
+            if apples < kind.cost() {
+                return false;
+            }
+            apples -= kind.cost();
+            messages.send(ServerMessageEvent::new(
+                ClientMessage::Train(vec![producer], kind),
+                client_id,
+            ));
+            true
+        };
+        if buildings.is_empty() {
+            if let Some(&(builder, ..)) = own.first() {
+                train(builder, UnitKind::Barracks);
+            }
+        } else {
+            let mut trained = own.len();
+            for &(barracks, ..) in &buildings {
+                if trained < tactics.max_units && train(barracks, UnitKind::Minion) {
+                    trained += 1;
+                }
+            }
+        }
+        own.truncate(tactics.max_units);
+
+        // The oldest units gather, and once there are enough the newest attack
+        let attackers = match tactics.attack_from {
+            Some(attack_from) if own.len() >= attack_from => {
+                (own.len() as f32 * tactics.attack_share).round() as usize
+            }
+            _ => 0,
+        };
+        let (gatherers, attackers) = own.split_at(own.len() - attackers);
+        let mut order = |units: Vec<Entity>, target: Vec2| {
+            if !units.is_empty() {
+                messages.send(ServerMessageEvent::new(
+                    ClientMessage::Target(units, target),
+                    client_id,
+                ));
+            }
+        };
+
+        let apple = items
+            .iter()
+            .map(|item| item.0)
+            .min_by(|a, b| a.distance(home).total_cmp(&b.distance(home)));
+        if let Some(apple) = apple {
+            let idle_gatherers = gatherers
+                .iter()
+                .filter(|(_, position, target, ..)| is_idle(position, target))
+                .filter(|(_, position, ..)| position.distance(apple) >= 1.0)
+                .map(|&(entity, ..)| entity)
+                .collect();
+            order(idle_gatherers, aim(apple));
+        }
+
+        if attackers.is_empty() {
+            continue;
+        }
+        let center = attackers
+            .iter()
+            .map(|(_, position, ..)| position.0)
+            .sum::<Vec2>()
+            / attackers.len() as f32;
+        let is_enemy = |owner: ClientId| teams.get(&owner) != Some(&team);
+        let enemy = units
+            .iter()
+            .filter(|(.., owner)| is_enemy(owner.0))
+            .map(|(_, position, ..)| position.0)
+            .chain(
+                player_positions
+                    .iter()
+                    .filter(|&&(id, _)| is_enemy(id))
+                    .map(|&(_, position)| position),
+            )
+            .min_by(|a, b| a.distance(center).total_cmp(&b.distance(center)));
+        if let Some(enemy) = enemy {
+            let sent = attackers
+                .iter()
+                .filter(|(_, position, target, ..)| tactics.retarget || is_idle(position, target))
+                .map(|&(entity, ..)| entity)
+                .collect();
+            order(sent, aim(enemy));
+        }
+    }
+}
+
+fn is_idle(position: &MinionPosition, target: &MinionTarget) -> bool {
+    position.distance(target.0) < ARRIVED
+}
@@ -0,0 +1,112 @@
+use std::io::Write;
+use std::net::SocketAddr;
+use std::path::Path;
+
+use bevy::prelude::*;
+use lightyear::connection::netcode::{ConnectToken, Key, PRIVATE_KEY_BYTES};
+
+use crate::game::PROTOCOL_ID;
+
+/// Environment variable the private key can be given in, as hex. Takes precedence over the key
+/// file.
+pub const KEY_ENV: &str = "AOE_PRIVATE_KEY";
+/// How long an issued connect token can be used to start a connection, in seconds.
+const TOKEN_EXPIRY_SECS: i32 = 60;
+/// How long a connection made with an issued token can go without packets before it times out,
+/// in seconds.
+const TOKEN_TIMEOUT_SECS: i32 = 15;
+
+/// The private key connect tokens are signed with. Only the server needs it: clients connect with
+/// tokens the server (or something else holding the key) issued for them.
+#[derive(Resource, Clone, Copy)]
+pub struct ServerKey(pub Key);
+
+impl std::fmt::Debug for ServerKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("ServerKey(..)")
+    }
+}
+
+impl ServerKey {
+    /// Reads the key from [`KEY_ENV`] or the file at `path`, writing a newly generated key to
+    /// `path` if neither exists.
+    pub fn load_or_generate(path: &Path) -> Result<Self, String> {
+        if let Ok(hex) = std::env::var(KEY_ENV) {
+            return parse_key(&hex).map_err(|err| format!("Invalid {KEY_ENV}: {err}"));
+        }
+
+        match std::fs::read_to_string(path) {
+            Ok(hex) => parse_key(&hex).map_err(|err| format!("Invalid {}: {err}", path.display())),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                let key = Self(rand::random());
+                key.save(path)
+                    .map_err(|err| format!("Could not write {}: {err}", path.display()))?;
+                eprintln!("Generated a new private key in {}", path.display());
+                Ok(key)
+            }
+            Err(err) => Err(format!("Could not read {}: {err}", path.display())),
+        }
+    }
+
+    fn save(&self, path: &Path) -> std::io::Result<()> {
+        let mut options = std::fs::OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+        writeln!(options.open(path)?, "{}", encode_hex(&self.0))
+    }
+
+    /// A connect token that lets `client_id` connect to the server at `server_addr`.
+    pub fn issue_token(
+        &self,
+        server_addr: SocketAddr,
+        client_id: u64,
+    ) -> Result<ConnectToken, String> {
+        ConnectToken::build(server_addr, *PROTOCOL_ID, client_id, self.0)
+            .expire_seconds(TOKEN_EXPIRY_SECS)
+            .timeout_seconds(TOKEN_TIMEOUT_SECS)
+            .generate()
+            .map_err(|err| format!("Could not generate a connect token: {err}"))
+    }
+}
+
+/// A connect token handed to this client when it was launched, used instead of the shared key.
+#[derive(Resource, Clone, Debug, Default)]
+pub struct IssuedConnectToken(pub Option<Vec<u8>>);
+
+/// Encodes a connect token so it can be passed on the command line.
+pub fn encode_token(token: ConnectToken) -> Result<String, String> {
+    let bytes = token
+        .try_into_bytes()
+        .map_err(|err| format!("Could not encode connect token: {err}"))?;
+    Ok(encode_hex(&bytes))
+}
+
+fn parse_key(hex: &str) -> Result<ServerKey, String> {
+    let bytes = decode_hex(hex.trim())?;
+    let key = bytes.try_into().map_err(|bytes: Vec<u8>| {
+        format!("expected {PRIVATE_KEY_BYTES} bytes, got {}", bytes.len())
+    })?;
+    Ok(ServerKey(key))
+}
+
+pub fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+pub fn decode_hex(hex: &str) -> Result<Vec<u8>, String> {
+    if !hex.len().is_multiple_of(2) {
+        return Err("odd number of hex digits".into());
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| {
+            hex.get(i..i + 2)
+                .and_then(|byte| u8::from_str_radix(byte, 16).ok())
+                .ok_or_else(|| format!("invalid hex at position {i}"))
+        })
+        .collect()
+}
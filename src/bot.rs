@@ -0,0 +1,385 @@
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use bevy::prelude::*;
+use bevy::time::common_conditions::on_timer;
+use leafwing_input_manager::prelude::*;
+use lightyear::client::input::leafwing::InputSystemSet;
+use lightyear::prelude::client::{
+    ClientConnection, NetClient, NetworkingState as ClientNetworkingState, Predicted,
+};
+use lightyear::prelude::*;
+use rand::Rng;
+use rand::seq::SliceRandom;
+use serde::Deserialize;
+
+use crate::cli::{BotArgs, Settings};
+use crate::game::minion::{MinionPosition, Selected};
+use crate::game::player::{Ping, PlayerActions, PlayerId, PlayerPosition};
+use crate::game::{Channel1, ClientMessage, InputHandling, OwnedBy};
+use crate::netstats::{NetStats, format_rate};
+use crate::networking::{IsClient, LocalPlayerName, NetworkState};
+use crate::platform::{NoPlatform, Platform};
+
+/// Plays like a player would, only without anyone at the keyboard: it walks around, trains units
+/// and orders them about, at random or following a script. Many bots together put load on a
+/// server, see [`run`].
+pub struct BotPlugin(pub BotBehaviour);
+
+impl Plugin for BotPlugin {
+    fn build(&self, app: &mut App) {
+        app.insert_resource(self.0.clone())
+            .init_resource::<BotState>()
+            .init_resource::<BotStatus>()
+            .add_systems(
+                FixedPreUpdate,
+                act.before(InputSystemSet::BufferClientInputs)
+                    .in_set(InputHandling)
+                    .run_if(in_state(IsClient)),
+            )
+            .add_systems(
+                Update,
+                update_status.run_if(on_timer(Duration::from_secs(1))),
+            );
+    }
+}
+
+/// Half the width of the area bots send their units around in.
+const ARENA: f32 = 8.0;
+const REPORT_INTERVAL: Duration = Duration::from_secs(5);
+
+#[derive(Resource, Clone, Debug)]
+pub enum BotBehaviour {
+    Random(BotRates),
+    Script(BotScript),
+}
+
+/// How often a random bot does things, per second.
+#[derive(Clone, Copy, Debug)]
+pub struct BotRates {
+    pub spawn: f32,
+    pub order: f32,
+    pub select: f32,
+    /// Units a bot has before it stops training more.
+    pub max_units: usize,
+}
+
+/// Actions to take at set times after connecting, e.g.
+///
+/// ```toml
+/// repeat_after = 10.0
+///
+/// [[step]]
+/// at = 1.0
+/// action = "spawn"
+/// position = [2.0, 0.0]
+///
+/// [[step]]
+/// at = 2.0
+/// action = "order"
+/// ```
+#[derive(Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct BotScript {
+    /// Seconds after which the script starts over, if it does.
+    #[serde(default)]
+    pub repeat_after: Option<f32>,
+    #[serde(rename = "step")]
+    pub steps: Vec<ScriptStep>,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct ScriptStep {
+    /// Seconds since the bot connected, or since the script started over.
+    pub at: f32,
+    #[serde(flatten)]
+    pub action: BotAction,
+}
+
+/// Anything left out is picked at random.
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum BotAction {
+    /// Trains a unit where the cursor is.
+    Spawn { position: Option<Vec2> },
+    /// Keeps walking the player this way, zero to stand still.
+    Walk { direction: Option<Vec2> },
+    /// Sends the selected units somewhere, or all of them if none are selected.
+    Order { position: Option<Vec2> },
+    /// Selects this many of the bot's units.
+    Select { count: Option<usize> },
+}
+
+impl BotScript {
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|err| format!("Could not read {}: {err}", path.display()))?;
+        toml::from_str(&text).map_err(|err| format!("Invalid script {}: {err}", path.display()))
+    }
+}
+
+#[derive(Resource, Default)]
+struct BotState {
+    /// Seconds since the script started, or started over.
+    script_time: f32,
+    walk: Vec2,
+    walk_until: f32,
+}
+
+/// What a bot sees of the match, for the report.
+#[derive(Resource, Default, Clone, Copy, Debug)]
+pub struct BotStatus {
+    pub connected: bool,
+    pub rtt_ms: u32,
+    pub units: usize,
+}
+
+fn random_position(rng: &mut impl Rng) -> Vec2 {
+    Vec2::new(rng.gen_range(-ARENA..ARENA), rng.gen_range(-ARENA..ARENA))
+}
+
+/// The actions due this tick.
+fn due_actions(
+    behaviour: &BotBehaviour,
+    state: &mut BotState,
+    units: usize,
+    delta: f32,
+    rng: &mut impl Rng,
+) -> Vec<BotAction> {
+    match behaviour {
+        BotBehaviour::Random(rates) => {
+            let mut actions = Vec::new();
+            if state.script_time >= state.walk_until {
+                state.walk_until = state.script_time + rng.gen_range(1.0..3.0);
+                actions.push(BotAction::Walk { direction: None });
+            }
+            let mut roll = |rate: f32| rng.gen_bool((rate * delta).clamp(0.0, 1.0) as f64);
+            if units < rates.max_units && roll(rates.spawn) {
+                actions.push(BotAction::Spawn { position: None });
+            }
+            if roll(rates.select) {
+                actions.push(BotAction::Select { count: None });
+            }
+            if roll(rates.order) {
+                actions.push(BotAction::Order { position: None });
+            }
+            state.script_time += delta;
+            actions
+        }
+        BotBehaviour::Script(script) => {
+            let from = state.script_time;
+            let to = from + delta;
+            let actions = script
+                .steps
+                .iter()
+                .filter(|step| (from..to).contains(&step.at))
+                .map(|step| step.action)
+                .collect();
+            state.script_time = match script.repeat_after {
+                Some(repeat_after) if to >= repeat_after => 0.0,
+                _ => to,
+            };
+            actions
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn act(
+    mut commands: Commands,
+    behaviour: Res<BotBehaviour>,
+    mut state: ResMut<BotState>,
+    mut player: Query<
+        (&mut ActionState<PlayerActions>, &PlayerPosition),
+        With<InputMap<PlayerActions>>,
+    >,
+    units: Query<(Entity, &Predicted, &OwnedBy, Has<Selected>), With<MinionPosition>>,
+    connection: Res<ClientConnection>,
+    mut message_manager: ResMut<ClientConnectionManager>,
+    time: Res<Time<Fixed>>,
+) {
+    // Nothing to act with until our player shows up
+    let Ok((mut action_state, position)) = player.get_single_mut() else {
+        return;
+    };
+    let own = units
+        .iter()
+        .filter(|(_, _, owner, _)| owner.0 == connection.id())
+        .collect::<Vec<_>>();
+
+    let rng = &mut rand::thread_rng();
+    let state = &mut *state;
+    action_state.set_axis_pair(&PlayerActions::Move, state.walk);
+    for action in due_actions(&behaviour, state, own.len(), time.delta_secs(), rng) {
+        match action {
+            BotAction::Spawn { position: target } => {
+                let target = target.unwrap_or_else(|| {
+                    position.0 + Vec2::new(rng.gen_range(-1.0..1.0), rng.gen_range(-1.0..1.0))
+                });
+                action_state.set_axis_pair(&PlayerActions::Cursor, target);
+                action_state.press(&PlayerActions::Spawn);
+            }
+            BotAction::Walk { direction } => {
+                state.walk = direction
+                    .unwrap_or_else(|| Vec2::from_angle(rng.gen_range(0.0..std::f32::consts::TAU)))
+                    .clamp_length_max(1.0);
+                action_state.set_axis_pair(&PlayerActions::Move, state.walk);
+            }
+            BotAction::Order { position: target } => {
+                let target = target.unwrap_or_else(|| random_position(rng));
+                let any_selected = own.iter().any(|&(.., selected)| selected);
+                let ordered = own
+                    .iter()
+                    .filter(|&&(.., selected)| selected || !any_selected)
+                    .filter_map(|&(_, predicted, ..)| predicted.confirmed_entity)
+                    .collect::<Vec<_>>();
+                if ordered.is_empty() {
+                    continue;
+                }
+                // A bot that lost its connection keeps going, the report shows it disconnected
+                if let Err(err) = message_manager
+                    .send_message::<Channel1, _>(&ClientMessage::Target(ordered, target))
+                {
+                    warn!("Bot could not send an order: {err}");
+                }
+            }
+            BotAction::Select { count } => {
+                let count = count.unwrap_or_else(|| rng.gen_range(0..=own.len()));
+                // Some units picked at random, rather than always the first few
+                let picked = own
+                    .choose_multiple(rng, count)
+                    .map(|&(entity, ..)| entity)
+                    .collect::<Vec<_>>();
+                for &(entity, ..) in &own {
+                    if picked.contains(&entity) {
+                        commands.entity(entity).insert(Selected);
+                    } else {
+                        commands.entity(entity).remove::<Selected>();
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn update_status(
+    mut status: ResMut<BotStatus>,
+    state: Res<State<ClientNetworkingState>>,
+    connection: Res<ClientConnection>,
+    pings: Query<(&PlayerId, &Ping)>,
+    units: Query<&OwnedBy, (With<MinionPosition>, With<Predicted>)>,
+) {
+    let id = connection.id();
+    *status = BotStatus {
+        connected: *state.get() == ClientNetworkingState::Connected,
+        rtt_ms: pings
+            .iter()
+            .find(|(player_id, _)| player_id.0 == id)
+            .map_or(0, |(_, ping)| ping.rtt),
+        units: units.iter().filter(|owner| owner.0 == id).count(),
+    };
+}
+
+/// Connects `args.count` bots to the server in `settings` and logs how the match holds up, until
+/// the process is stopped. The bots all run on this thread, so check that the time they take per
+/// frame isn't what holds the numbers back. Their connect tokens are fetched on the task pool like
+/// any client's, so bots waiting for one don't hold up the others.
+pub fn run(settings: &Settings, args: &BotArgs) -> Result<(), String> {
+    let behaviour = match &args.script {
+        Some(path) => BotBehaviour::Script(BotScript::load(path)?),
+        None => BotBehaviour::Random(BotRates {
+            spawn: args.spawn_rate,
+            order: args.order_rate,
+            select: args.select_rate,
+            max_units: args.max_units,
+        }),
+    };
+    info!(count = args.count, server_addr = %settings.server_addr, ?behaviour, "Starting bots");
+
+    let frame = Duration::from_secs_f64(1.0 / 64.0);
+    let started = Instant::now();
+    let mut last_report = started;
+    let mut bots = Vec::<App>::new();
+    let mut frame_times = Vec::new();
+    loop {
+        let frame_start = Instant::now();
+
+        // Bring the bots in evenly over the ramp
+        let due = if args.ramp > 0.0 {
+            (started.elapsed().as_secs_f32() / args.ramp * args.count as f32).ceil() as u32
+        } else {
+            args.count
+        };
+        while (bots.len() as u32) < due.min(args.count) {
+            bots.push(spawn_bot(settings, bots.len() + 1, behaviour.clone()));
+        }
+
+        for bot in &mut bots {
+            bot.update();
+        }
+        frame_times.push(frame_start.elapsed());
+
+        if last_report.elapsed() >= REPORT_INTERVAL {
+            last_report = Instant::now();
+            report(&bots, args.count, &std::mem::take(&mut frame_times));
+        }
+        std::thread::sleep(frame.saturating_sub(frame_start.elapsed()));
+    }
+}
+
+fn spawn_bot(settings: &Settings, number: usize, behaviour: BotBehaviour) -> App {
+    let mut app = crate::create_headless_app();
+    crate::insert_settings(&mut app, settings)
+        .insert_resource(Platform(std::sync::Arc::new(NoPlatform)))
+        .insert_resource(LocalPlayerName(format!("Bot {number}")))
+        .add_plugins(BotPlugin(behaviour))
+        .insert_state(NetworkState::Client {
+            server_addr: settings.server_addr,
+            client_id: rand::random(),
+        });
+    app.finish();
+    app.cleanup();
+    app
+}
+
+fn report(bots: &[App], count: u32, frame_times: &[Duration]) {
+    let statuses = bots
+        .iter()
+        .map(|bot| *bot.world().resource::<BotStatus>())
+        .collect::<Vec<_>>();
+    let stats = bots
+        .iter()
+        .map(|bot| bot.world().resource::<NetStats>())
+        .collect::<Vec<_>>();
+    let connected = statuses.iter().filter(|status| status.connected).count();
+    let rtts = statuses
+        .iter()
+        .filter(|status| status.connected)
+        .map(|status| status.rtt_ms);
+    let avg_rtt = rtts.clone().sum::<u32>() / connected.max(1) as u32;
+    let max_rtt = rtts.max().unwrap_or(0);
+    let upload = stats.iter().map(|stats| stats.bytes_up_per_sec).sum();
+    let download = stats.iter().map(|stats| stats.bytes_down_per_sec).sum();
+    let loss =
+        stats.iter().map(|stats| stats.packet_loss()).sum::<f32>() / stats.len().max(1) as f32;
+    let units = statuses.iter().map(|status| status.units).sum::<usize>();
+    // Every bot hears the same from the server, the freshest will do
+    let load = stats
+        .iter()
+        .find_map(|stats| stats.server_load)
+        .unwrap_or_default();
+    let bot_frame = frame_times.iter().sum::<Duration>() / frame_times.len().max(1) as u32;
+
+    info!(
+        "{connected}/{count} bots connected, {units} units | RTT {avg_rtt} ms (max {max_rtt} ms), \
+         loss {:.1} %, up {}, down {} | server frame {:.1} ms (max {:.1} ms), {} entities | \
+         bot frame {:.1} ms",
+        loss * 100.0,
+        format_rate(upload),
+        format_rate(download),
+        load.frame_us as f32 / 1000.0,
+        load.max_frame_us as f32 / 1000.0,
+        load.entities,
+        bot_frame.as_secs_f32() * 1000.0,
+    );
+}
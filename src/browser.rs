@@ -0,0 +1,252 @@
+use std::net::{Ipv4Addr, UdpSocket};
+use std::time::Instant;
+
+use bevy::prelude::*;
+use bevy_egui::EguiContexts;
+use bevy_egui::egui::{Align2, Checkbox, ComboBox, Grid, TextEdit};
+
+use crate::discovery::{PROBE, REPLY, ServerInfo, decode_datagram, show_server_entry};
+use crate::game::handshake::BuildInfo;
+use crate::master_server::{LISTING, Listing, MasterServer, QUERY};
+use crate::networking::NetworkState;
+use crate::token_service::TokenServicePort;
+
+/// Lists the servers registered with the master server, with the ping to each measured from here.
+pub struct BrowserPlugin;
+
+impl Plugin for BrowserPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<ServerBrowser>()
+            .add_systems(OnEnter(NetworkState::Disconnected), refresh_browser)
+            .add_systems(
+                Update,
+                (receive_listings, show_server_browser)
+                    .chain()
+                    .run_if(in_state(NetworkState::Disconnected))
+                    .run_if(|master: Res<MasterServer>| master.addr.is_some()),
+            );
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+enum SortBy {
+    Name,
+    Players,
+    Region,
+    #[default]
+    Ping,
+}
+
+struct BrowserEntry {
+    listing: Listing,
+    /// When we sent the server a probe, until it answers.
+    probe_sent: Option<Instant>,
+    ping_ms: Option<u32>,
+}
+
+#[derive(Resource, Default)]
+struct ServerBrowser {
+    socket: Option<UdpSocket>,
+    entries: Vec<BrowserEntry>,
+    /// Listings the master server sent that we could not read.
+    unreadable: usize,
+    filter: String,
+    region: Option<String>,
+    hide_full: bool,
+    hide_incompatible: bool,
+    sort_by: SortBy,
+}
+
+impl ServerBrowser {
+    fn visible_entries(&self) -> Vec<&BrowserEntry> {
+        let filter = self.filter.to_lowercase();
+        let mut entries = self
+            .entries
+            .iter()
+            .filter(|entry| {
+                let info = &entry.listing.info;
+                info.name.to_lowercase().contains(&filter)
+                    && self
+                        .region
+                        .as_ref()
+                        .is_none_or(|region| *region == entry.listing.region)
+                    && !(self.hide_full && info.players >= info.max_players)
+                    && (!self.hide_incompatible || BuildInfo::local().is_compatible(&info.build))
+            })
+            .collect::<Vec<_>>();
+
+        entries.sort_by(|a, b| match self.sort_by {
+            SortBy::Name => a.listing.info.name.cmp(&b.listing.info.name),
+            SortBy::Players => b.listing.info.players.cmp(&a.listing.info.players),
+            SortBy::Region => a.listing.region.cmp(&b.listing.region),
+            // Servers we have no ping for yet go last
+            SortBy::Ping => a
+                .ping_ms
+                .unwrap_or(u32::MAX)
+                .cmp(&b.ping_ms.unwrap_or(u32::MAX)),
+        });
+        entries
+    }
+}
+
+fn refresh_browser(mut browser: ResMut<ServerBrowser>, master: Res<MasterServer>) {
+    let Some(master_addr) = master.addr else {
+        return;
+    };
+    if browser.socket.is_none() {
+        browser.socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))
+            .and_then(|socket| socket.set_nonblocking(true).map(|()| socket))
+            .inspect_err(|err| warn!("Could not open a socket for the server browser: {err}"))
+            .ok();
+    }
+    let Some(socket) = &browser.socket else {
+        return;
+    };
+
+    if let Err(err) = socket.send_to(QUERY, master_addr) {
+        warn!(%master_addr, "Could not query the master server: {err}");
+    }
+    browser.entries.clear();
+    browser.unreadable = 0;
+}
+
+fn receive_listings(mut browser: ResMut<ServerBrowser>) {
+    let ServerBrowser {
+        socket: Some(socket),
+        entries,
+        unreadable,
+        ..
+    } = &mut *browser
+    else {
+        return;
+    };
+
+    let mut buf = [0; 2048];
+    while let Ok((len, peer)) = socket.recv_from(&mut buf) {
+        let message = &buf[..len];
+
+        if message.starts_with(LISTING) {
+            let Some(listing) = decode_datagram::<Listing>(LISTING, message) else {
+                *unreadable += 1;
+                continue;
+            };
+            let probe_sent = listing
+                .discovery_port
+                .and_then(|port| socket.send_to(PROBE, (listing.addr.ip(), port)).ok())
+                .map(|_| Instant::now());
+            entries.retain(|entry| entry.listing.addr != listing.addr);
+            entries.push(BrowserEntry {
+                listing,
+                probe_sent,
+                ping_ms: None,
+            });
+        } else if message.starts_with(REPLY) {
+            // A server answering our probe, which is all we need to know its ping
+            let entry = entries.iter_mut().find(|entry| {
+                entry.listing.addr.ip() == peer.ip()
+                    && entry.listing.discovery_port == Some(peer.port())
+            });
+            if let Some(entry) = entry
+                && let Some(sent) = entry.probe_sent.take()
+            {
+                entry.ping_ms = Some(sent.elapsed().as_millis() as u32);
+            }
+        }
+    }
+}
+
+fn show_server_browser(
+    mut contexts: EguiContexts,
+    mut browser: ResMut<ServerBrowser>,
+    master: Res<MasterServer>,
+    mut token_port: ResMut<TokenServicePort>,
+    mut next_network_state: ResMut<NextState<NetworkState>>,
+) {
+    let mut refresh = false;
+    let mut join = None;
+
+    bevy_egui::egui::Window::new("Server browser")
+        .anchor(Align2::RIGHT_TOP, (-10.0, 10.0))
+        .resizable(false)
+        .collapsible(true)
+        .show(contexts.ctx_mut(), |ui| {
+            let browser = &mut *browser;
+            ui.horizontal(|ui| {
+                ui.label("Search");
+                ui.add_sized((120.0, 20.0), TextEdit::singleline(&mut browser.filter));
+
+                let mut regions = browser
+                    .entries
+                    .iter()
+                    .map(|entry| entry.listing.region.clone())
+                    .collect::<Vec<_>>();
+                regions.sort();
+                regions.dedup();
+                ComboBox::from_id_salt("region")
+                    .selected_text(browser.region.as_deref().unwrap_or("All regions"))
+                    .show_ui(ui, |ui| {
+                        ui.selectable_value(&mut browser.region, None, "All regions");
+                        for region in regions {
+                            let label = region.clone();
+                            ui.selectable_value(&mut browser.region, Some(region), label);
+                        }
+                    });
+
+                refresh = ui.button("Refresh").clicked();
+            });
+            ui.horizontal(|ui| {
+                ui.add(Checkbox::new(&mut browser.hide_full, "Hide full"));
+                ui.add(Checkbox::new(
+                    &mut browser.hide_incompatible,
+                    "Hide incompatible",
+                ));
+            });
+            ui.separator();
+
+            Grid::new("server_browser")
+                .num_columns(4)
+                .striped(true)
+                .show(ui, |ui| {
+                    for (label, sort_by) in [
+                        ("Server", SortBy::Name),
+                        ("Players", SortBy::Players),
+                        ("Region", SortBy::Region),
+                        ("Ping", SortBy::Ping),
+                    ] {
+                        ui.selectable_value(&mut browser.sort_by, sort_by, label);
+                    }
+                    ui.end_row();
+
+                    for entry in browser.visible_entries() {
+                        let info: &ServerInfo = &entry.listing.info;
+                        show_server_entry(ui, entry.listing.addr, info, &mut join);
+                        ui.label(format!("{}/{}", info.players, info.max_players));
+                        ui.label(&entry.listing.region);
+                        ui.label(entry.ping_ms.map_or("?".into(), |ms| format!("{ms} ms")));
+                        ui.end_row();
+                    }
+                });
+
+            if browser.unreadable > 0 {
+                ui.label(format!(
+                    "{} servers could not be read, they probably run another version",
+                    browser.unreadable
+                ));
+            } else if browser.entries.is_empty() {
+                ui.label(format!(
+                    "No servers registered with {}",
+                    master.addr.map_or("-".into(), |addr| addr.to_string())
+                ));
+            }
+        });
+
+    if refresh {
+        refresh_browser(browser, master);
+    } else if let Some((server_addr, port)) = join {
+        token_port.0 = port;
+        next_network_state.set(NetworkState::Client {
+            server_addr,
+            client_id: rand::random(),
+        });
+    }
+}
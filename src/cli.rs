@@ -0,0 +1,506 @@
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use bevy::math::UVec2;
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use serde::Deserialize;
+
+use crate::ai::Difficulty;
+use crate::auth::{ServerKey, encode_token};
+use crate::conditioner::{Conditions, LinkConditions};
+use crate::logging::{LogFormat, LogRotation, LogSettings};
+use crate::master_server::DEFAULT_MASTER_PORT;
+use crate::platform::{CONNECT_LOBBY, LobbyId, parse_connect_lobby};
+
+/// The config file read when `--config` isn't given, if it exists.
+const DEFAULT_CONFIG: &str = "aoe.toml";
+const DEFAULT_KEY_FILE: &str = "server.key";
+/// Spacewar, which any Steam user can run as.
+const STEAM_TEST_APP_ID: u32 = 480;
+
+#[derive(Parser, Debug)]
+#[command(version, about = "Bevy AoE")]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    #[command(flatten)]
+    pub options: Options,
+}
+
+#[derive(Subcommand, Debug, Clone, PartialEq)]
+pub enum Command {
+    /// Open the network menu (the default in release builds)
+    Menu,
+    /// Host a game and play in it
+    Host,
+    /// Run a server without a local player
+    Server,
+    /// Connect to a server
+    Client {
+        /// Client id to connect with, random if not given
+        #[arg(long)]
+        client_id: Option<u64>,
+        /// Where to put the window when tiling several local clients
+        #[arg(long, default_value_t = 0)]
+        slot: u32,
+        /// Hex encoded connect token to connect with the first time. Without one, or when
+        /// reconnecting, the client asks the server's token service for a token
+        #[arg(long, hide = true)]
+        connect_token: Option<String>,
+        /// Watch the match instead of playing
+        #[arg(long)]
+        spectate: bool,
+    },
+    /// Play back a recorded match
+    Replay {
+        /// Replay file to play, see `--record`
+        file: PathBuf,
+    },
+    /// Connect headless bots to a server, to see how much load it takes
+    Bot(BotArgs),
+    /// Run the master server that dedicated servers register with
+    MasterServer,
+    /// Only run the service that hands out connect tokens for a server elsewhere. Needs the
+    /// server's private key
+    TokenService,
+    /// Host a game and launch local clients that connect to it (the default in debug builds)
+    LocalTest {
+        /// How many clients to launch next to the host
+        #[arg(long)]
+        clients: Option<u32>,
+    },
+}
+
+#[derive(Args, Debug, Clone, PartialEq)]
+pub struct BotArgs {
+    /// How many bots to connect
+    #[arg(long, default_value_t = 10)]
+    pub count: u32,
+    /// Seconds over which the bots connect one after another, to see where things degrade
+    #[arg(long, default_value_t = 0.0)]
+    pub ramp: f32,
+    /// Units each bot trains per second
+    #[arg(long, default_value_t = 0.5)]
+    pub spawn_rate: f32,
+    /// Move orders each bot gives per second
+    #[arg(long, default_value_t = 1.0)]
+    pub order_rate: f32,
+    /// Times per second each bot selects other units
+    #[arg(long, default_value_t = 0.5)]
+    pub select_rate: f32,
+    /// Units a bot has before it stops training more
+    #[arg(long, default_value_t = 50)]
+    pub max_units: usize,
+    /// TOML script of actions to take instead of acting at random
+    #[arg(long)]
+    pub script: Option<PathBuf>,
+}
+
+#[derive(Args, Debug, Clone, Default)]
+pub struct Options {
+    /// TOML file to read defaults from [default: aoe.toml if it exists]
+    #[arg(long, global = true)]
+    pub config: Option<PathBuf>,
+    /// File holding the server's private key, generated if it doesn't exist [default: server.key]
+    #[arg(long, global = true)]
+    pub key_file: Option<PathBuf>,
+    /// Address to host on or connect to
+    #[arg(long, global = true)]
+    pub address: Option<IpAddr>,
+    #[arg(long, global = true)]
+    pub port: Option<u16>,
+    /// Name the server shows up with in server lists [default: based on the player name]
+    #[arg(long, global = true)]
+    pub server_name: Option<String>,
+    /// Region the server registers with the master server as
+    #[arg(long, global = true)]
+    pub region: Option<String>,
+    /// Master server to register with and browse [default: 127.0.0.1:27900]
+    #[arg(long, global = true)]
+    pub master_server: Option<SocketAddr>,
+    /// Don't register with or browse a master server
+    #[arg(long, global = true)]
+    pub no_master_server: bool,
+    /// Seconds spectators are kept behind the match [default: 0]
+    #[arg(long, global = true)]
+    pub spectator_delay: Option<f32>,
+    /// Record hosted and served matches to the `replays` directory
+    #[arg(long, global = true)]
+    pub record: bool,
+    /// Saved match to continue when hosting or running a server
+    #[arg(long, global = true)]
+    pub load: Option<PathBuf>,
+    /// Seconds between automatic saves of hosted and served matches [default: never]
+    #[arg(long, global = true)]
+    pub autosave: Option<f32>,
+    /// Add an AI player to hosted and served matches, can be given more than once
+    #[arg(long = "ai", global = true, value_name = "DIFFICULTY")]
+    pub ai_opponents: Vec<Difficulty>,
+    /// Steam app id to run as [default: 480, the Steam test app]
+    #[arg(long, global = true)]
+    pub steam_app_id: Option<u32>,
+    /// TCP port of the service handing out connect tokens [default: the game port + 1]
+    #[arg(long, global = true)]
+    pub token_port: Option<u16>,
+    /// Log filter, e.g. `info` or `warn,aoe=debug`. `RUST_LOG` overrides this when set
+    #[arg(long, global = true)]
+    pub log_level: Option<String>,
+    /// Format of the log files [default: json for `server`, text otherwise]
+    #[arg(long, global = true)]
+    pub log_format: Option<LogFormat>,
+    /// How often to start a new log file [default: daily]
+    #[arg(long, global = true)]
+    pub log_rotation: Option<LogRotation>,
+    /// Size of the monitor local test windows are laid out on, e.g. `2560x1440`
+    #[arg(long, global = true, value_parser = parse_size)]
+    pub monitor: Option<UVec2>,
+
+    #[command(flatten)]
+    pub conditioner: ConditionerArgs,
+}
+
+/// Simulated network conditions. The plain flags set both directions, the `--client-*` and
+/// `--server-*` flags only the packets received by that side.
+#[derive(Args, Debug, Clone, Default)]
+pub struct ConditionerArgs {
+    /// Simulated latency in milliseconds
+    #[arg(long, global = true)]
+    pub latency: Option<u64>,
+    /// Simulated jitter in milliseconds
+    #[arg(long, global = true)]
+    pub jitter: Option<u64>,
+    /// Fraction of packets to drop, between 0 and 1
+    #[arg(long, global = true)]
+    pub loss: Option<f32>,
+    #[arg(long, global = true, hide = true)]
+    pub client_latency: Option<u64>,
+    #[arg(long, global = true, hide = true)]
+    pub client_jitter: Option<u64>,
+    #[arg(long, global = true, hide = true)]
+    pub client_loss: Option<f32>,
+    #[arg(long, global = true, hide = true)]
+    pub server_latency: Option<u64>,
+    #[arg(long, global = true, hide = true)]
+    pub server_jitter: Option<u64>,
+    #[arg(long, global = true, hide = true)]
+    pub server_loss: Option<f32>,
+}
+
+impl ConditionerArgs {
+    /// Applies the flags that were given on top of `conditions`.
+    fn apply(&self, conditions: &mut LinkConditions) {
+        let apply = |conditions: &mut Conditions,
+                     latency: Option<u64>,
+                     jitter: Option<u64>,
+                     loss: Option<f32>| {
+            if let Some(latency) = latency.or(self.latency) {
+                conditions.latency_ms = latency;
+            }
+            if let Some(jitter) = jitter.or(self.jitter) {
+                conditions.jitter_ms = jitter;
+            }
+            if let Some(loss) = loss.or(self.loss) {
+                conditions.loss = loss;
+            }
+        };
+        apply(
+            &mut conditions.client,
+            self.client_latency,
+            self.client_jitter,
+            self.client_loss,
+        );
+        apply(
+            &mut conditions.server,
+            self.server_latency,
+            self.server_jitter,
+            self.server_loss,
+        );
+    }
+}
+
+/// The master server in the config file: an address, or `false` for none.
+#[derive(Deserialize, Debug, Clone, Copy)]
+#[serde(untagged)]
+pub enum MasterServerSetting {
+    Addr(SocketAddr),
+    Enabled(bool),
+}
+
+/// The contents of the config file. Everything is optional and overridden by command line flags.
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct ConfigFile {
+    pub address: Option<IpAddr>,
+    pub port: Option<u16>,
+    pub server_name: Option<String>,
+    pub region: Option<String>,
+    /// Set to `false` to not use a master server.
+    pub master_server: Option<MasterServerSetting>,
+    pub steam_app_id: Option<u32>,
+    pub spectator_delay: Option<f32>,
+    pub record_replays: Option<bool>,
+    pub autosave: Option<f32>,
+    /// Difficulties of the AI players added to hosted and served matches.
+    pub ai: Option<Vec<Difficulty>>,
+    pub token_port: Option<u16>,
+    pub key_file: Option<PathBuf>,
+    pub log_level: Option<String>,
+    pub log_format: Option<LogFormat>,
+    pub log_rotation: Option<LogRotation>,
+    pub monitor: Option<[u32; 2]>,
+    pub local_clients: Option<u32>,
+    pub conditioner: LinkConditions,
+}
+
+impl ConfigFile {
+    fn load(path: &Path) -> Result<Self, String> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|err| format!("Could not read {}: {err}", path.display()))?;
+        toml::from_str(&text).map_err(|err| format!("Invalid config {}: {err}", path.display()))
+    }
+}
+
+/// Everything the launcher needs, after merging the config file and the command line.
+#[derive(Debug, Clone)]
+pub struct Settings {
+    pub command: Command,
+    pub server_addr: SocketAddr,
+    pub server_name: Option<String>,
+    pub region: String,
+    pub master_server: Option<SocketAddr>,
+    pub steam_app_id: u32,
+    /// Lobby to join once started, when Steam started us for an invite.
+    pub join_lobby: Option<LobbyId>,
+    pub spectator_delay: Duration,
+    pub record_replays: bool,
+    /// Saved match to continue.
+    pub load_match: Option<PathBuf>,
+    pub autosave: Option<Duration>,
+    pub ai_opponents: Vec<Difficulty>,
+    pub token_port: u16,
+    /// Only loaded for the commands that run a server or the token service.
+    pub key: Option<ServerKey>,
+    pub log_level: String,
+    pub log_format: LogFormat,
+    pub log_rotation: LogRotation,
+    pub monitor: UVec2,
+    pub local_clients: u32,
+    pub link_conditions: LinkConditions,
+    /// The options as given on the command line, passed on to local clients.
+    pub options: Options,
+}
+
+impl Settings {
+    pub fn load() -> Result<Self, String> {
+        // Steam starts the game with `+connect_lobby <id>` when an invite is accepted, which clap
+        // would choke on
+        let mut args = std::env::args().collect::<Vec<_>>();
+        let join_lobby = parse_connect_lobby(&args.join(" "));
+        if let Some(index) = args.iter().position(|arg| arg == CONNECT_LOBBY) {
+            args.drain(index..(index + 2).min(args.len()));
+        }
+        let cli = Cli::parse_from(args);
+
+        let config = match &cli.options.config {
+            Some(path) => ConfigFile::load(path)?,
+            None if Path::new(DEFAULT_CONFIG).exists() => {
+                ConfigFile::load(Path::new(DEFAULT_CONFIG))?
+            }
+            None => ConfigFile::default(),
+        };
+
+        let command = cli.command.clone().unwrap_or(if cfg!(debug_assertions) {
+            Command::LocalTest { clients: None }
+        } else {
+            Command::Menu
+        });
+        let local_clients = match command {
+            Command::LocalTest {
+                clients: Some(clients),
+            } => clients,
+            _ => config.local_clients.unwrap_or(2),
+        };
+
+        let options = &cli.options;
+        let log_format =
+            options
+                .log_format
+                .or(config.log_format)
+                .unwrap_or(if command == Command::Server {
+                    LogFormat::Json
+                } else {
+                    LogFormat::Text
+                });
+        // Only what runs a server or issues tokens needs the key, clients get tokens instead
+        let key = match command {
+            Command::Host | Command::Server | Command::TokenService | Command::LocalTest { .. } => {
+                let key_file = options
+                    .key_file
+                    .clone()
+                    .or(config.key_file)
+                    .unwrap_or_else(|| DEFAULT_KEY_FILE.into());
+                Some(ServerKey::load_or_generate(&key_file)?)
+            }
+            _ => None,
+        };
+        let mut link_conditions = config.conditioner;
+        options.conditioner.apply(&mut link_conditions);
+
+        let port = options.port.or(config.port).unwrap_or(5000);
+        // The token service goes right above the game server unless told otherwise
+        let token_port = match options.token_port.or(config.token_port) {
+            Some(token_port) => token_port,
+            None => port.checked_add(1).ok_or_else(|| {
+                format!("Port {port} leaves no room for the token service, set its port")
+            })?,
+        };
+        Ok(Self {
+            command,
+            server_addr: SocketAddr::new(
+                options
+                    .address
+                    .or(config.address)
+                    .unwrap_or(Ipv4Addr::LOCALHOST.into()),
+                port,
+            ),
+            server_name: options.server_name.clone().or(config.server_name),
+            region: options
+                .region
+                .clone()
+                .or(config.region)
+                .unwrap_or_else(|| "local".into()),
+            master_server: match (options.no_master_server, options.master_server) {
+                (true, _) => None,
+                (false, Some(addr)) => Some(addr),
+                (false, None) => match config.master_server {
+                    Some(MasterServerSetting::Addr(addr)) => Some(addr),
+                    Some(MasterServerSetting::Enabled(false)) => None,
+                    Some(MasterServerSetting::Enabled(true)) | None => Some(SocketAddr::new(
+                        Ipv4Addr::LOCALHOST.into(),
+                        DEFAULT_MASTER_PORT,
+                    )),
+                },
+            },
+            steam_app_id: options
+                .steam_app_id
+                .or(config.steam_app_id)
+                .unwrap_or(STEAM_TEST_APP_ID),
+            join_lobby,
+            spectator_delay: Duration::from_secs_f32(
+                options
+                    .spectator_delay
+                    .or(config.spectator_delay)
+                    .unwrap_or(0.0)
+                    .max(0.0),
+            ),
+            record_replays: options.record || config.record_replays.unwrap_or(false),
+            load_match: options.load.clone(),
+            autosave: options
+                .autosave
+                .or(config.autosave)
+                .filter(|&seconds| seconds > 0.0)
+                .map(Duration::from_secs_f32),
+            ai_opponents: if options.ai_opponents.is_empty() {
+                config.ai.unwrap_or_default()
+            } else {
+                options.ai_opponents.clone()
+            },
+            token_port,
+            key,
+            log_level: options
+                .log_level
+                .clone()
+                .or(config.log_level)
+                .unwrap_or_else(|| "info".into()),
+            log_format,
+            log_rotation: options
+                .log_rotation
+                .or(config.log_rotation)
+                .unwrap_or_default(),
+            monitor: options
+                .monitor
+                .or(config.monitor.map(UVec2::from))
+                .unwrap_or(UVec2::new(2560, 1440)),
+            local_clients,
+            link_conditions,
+            options: cli.options,
+        })
+    }
+
+    /// How a process launched with these settings logs, to a file named `file_name`.
+    pub fn log_settings(&self, file_name: impl Into<String>) -> LogSettings {
+        LogSettings {
+            filter: self.log_level.clone(),
+            format: self.log_format,
+            rotation: self.log_rotation,
+            file_name: file_name.into(),
+        }
+    }
+
+    /// Arguments for a local client process, so it ends up with the same settings as this one.
+    /// The client gets a connect token issued with our key for its first connection, and asks the
+    /// token service for new ones after that.
+    pub fn client_args(&self, client_id: u64, slot: u32) -> Result<Vec<String>, String> {
+        let key = self.key.ok_or("Local clients need the server's key")?;
+        let token = key.issue_token(self.server_addr, client_id)?;
+        let mut args = vec![
+            "client".into(),
+            "--client-id".into(),
+            client_id.to_string(),
+            "--slot".into(),
+            slot.to_string(),
+            "--connect-token".into(),
+            encode_token(token)?,
+            "--address".into(),
+            self.server_addr.ip().to_string(),
+            "--port".into(),
+            self.server_addr.port().to_string(),
+            "--token-port".into(),
+            self.token_port.to_string(),
+            "--region".into(),
+            self.region.clone(),
+            "--steam-app-id".into(),
+            self.steam_app_id.to_string(),
+            "--log-level".into(),
+            self.log_level.clone(),
+            "--log-format".into(),
+            self.log_format
+                .to_possible_value()
+                .unwrap()
+                .get_name()
+                .into(),
+            "--log-rotation".into(),
+            self.log_rotation
+                .to_possible_value()
+                .unwrap()
+                .get_name()
+                .into(),
+            "--monitor".into(),
+            format!("{}x{}", self.monitor.x, self.monitor.y),
+        ];
+        for (flag, path) in [
+            ("--config", &self.options.config),
+            ("--key-file", &self.options.key_file),
+        ] {
+            if let Some(path) = path {
+                args.extend([flag.into(), path.display().to_string()]);
+            }
+        }
+        match self.master_server {
+            Some(addr) => args.extend(["--master-server".into(), addr.to_string()]),
+            None => args.push("--no-master-server".into()),
+        }
+        args.extend(self.link_conditions.to_args());
+        Ok(args)
+    }
+}
+
+fn parse_size(size: &str) -> Result<UVec2, String> {
+    let (width, height) = size
+        .split_once('x')
+        .ok_or_else(|| format!("Expected WIDTHxHEIGHT, got {size}"))?;
+    let parse = |n: &str| n.parse::<u32>().map_err(|err| format!("{n}: {err}"));
+    Ok(UVec2::new(parse(width)?, parse(height)?))
+}
@@ -1,31 +1,42 @@
 use std::net::SocketAddr;
 use std::str::FromStr;
-use std::time::Duration;
 
+use bevy::ecs::system::SystemParam;
 use bevy::prelude::*;
+use bevy::tasks::futures_lite::future;
+use bevy::tasks::{AsyncComputeTaskPool, Task, block_on};
 use bevy::window::PrimaryWindow;
-use lightyear::client::input::native::InputSystemSet;
+use bevy_egui::EguiContexts;
+use leafwing_input_manager::prelude::*;
+use lightyear::client::input::leafwing::InputSystemSet;
+use lightyear::connection::netcode::ConnectToken;
 use lightyear::prelude::client::NetClient;
 use lightyear::prelude::*;
 
-use crate::SteamClient;
+use crate::auth::IssuedConnectToken;
+use crate::conditioner::LinkConditions;
 use crate::game::InputHandling;
+use crate::game::handshake::HandshakeReply;
 use crate::game::minion::Selected;
-use crate::game::player::Direction;
-use crate::game::player::Inputs;
-use crate::game::player::PlayerColor;
-use crate::game::player::PlayerPosition;
+use crate::game::player::PlayerActions;
+use crate::game::player::PlayerId;
 use crate::game::{
-    Channel1, ClientMessage, KEY, OwnedBy, PROTOCOL_ID,
-    minion::{MinionPosition, MinionTarget},
+    Channel1, ClaimSecret, ClientMessage, OwnedBy, ServerMessage,
+    minion::{MinionPosition, MinionTarget, UnitKind},
     shared_config,
 };
+use crate::minimap::{MinimapCamera, minimap_to_world};
+use crate::networking::DisconnectReason;
 use crate::networking::IsClient;
+use crate::networking::LocalPlayerName;
 use crate::networking::NetworkState;
+use crate::platform::Platform;
+use crate::spectator::JoinAs;
+use crate::token_service::{FetchError, TokenServicePort, fetch_token};
 
 use self::client::{
-    Authentication, ClientCommands, ClientConfig, ClientConnection, ClientTransport, InputManager,
-    IoConfig, NetConfig, Predicted,
+    Authentication, ClientCommands, ClientConfig, ClientConnection, ClientTransport, IoConfig,
+    NetConfig, Predicted,
 };
 
 #[derive(Debug, Resource)]
@@ -34,6 +45,29 @@ pub struct StartDrag(Vec2);
 #[derive(Debug, Resource)]
 pub struct SelectedMinions(Vec<Entity>);
 
+/// An order for the currently selected units, issued from the HUD or a hotkey.
+#[derive(Event, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UnitCommand {
+    Stop,
+    Train(UnitKind),
+}
+
+impl UnitCommand {
+    pub fn label(self) -> String {
+        match self {
+            UnitCommand::Stop => "Stop".into(),
+            UnitCommand::Train(kind) => format!("Train {} ({} apples)", kind.name(), kind.cost()),
+        }
+    }
+
+    pub fn hotkey(self) -> Option<KeyCode> {
+        match self {
+            UnitCommand::Stop => Some(KeyCode::KeyX),
+            UnitCommand::Train(_) => None,
+        }
+    }
+}
+
 pub struct ClientPlugin;
 
 impl Plugin for ClientPlugin {
@@ -48,108 +82,341 @@ impl Plugin for ClientPlugin {
         app.add_plugins(client::ClientPlugins::new(client_config));
 
         app.insert_resource(SelectedMinions(vec![]))
+            .init_resource::<IssuedConnectToken>()
+            .init_resource::<ClaimSecret>()
             .add_computed_state::<IsClient>()
+            .add_event::<UnitCommand>()
+            .add_event::<Reconnect>()
+            .add_systems(
+                Update,
+                (
+                    add_input_map,
+                    issue_unit_commands,
+                    send_join_request,
+                    receive_refusal.run_if(in_state(IsClient)),
+                    reconnect.run_if(in_state(IsClient)),
+                    receive_token.run_if(resource_exists::<TokenRequest>),
+                ),
+            )
             .add_systems(
                 FixedPreUpdate,
                 buffer_input
-                    .in_set(InputSystemSet::BufferInputs)
+                    .before(InputSystemSet::BufferClientInputs)
                     .in_set(InputHandling),
             )
-            .add_systems(OnEnter(IsClient), start_client);
+            .add_systems(OnEnter(IsClient), start_client)
+            .add_systems(OnExit(IsClient), stop_client);
     }
 }
 
-fn start_client(
-    mut commands: Commands,
-    network_state: Res<State<NetworkState>>,
-    mut client_config: ResMut<ClientConfig>,
-    steam_client: Res<SteamClient>,
+/// Sent to drop the current connection and connect again with freshly built settings, e.g. after
+/// changing the link conditioner.
+#[derive(Event, Clone, Copy, Debug)]
+pub struct Reconnect {
+    /// Asked for by the player rather than retrying a failed connection, so the retries start
+    /// over.
+    pub manual: bool,
+}
+
+fn start_client(mut connection_settings: ConnectionSettings, mut connector: Connector) {
+    connector.begin(&mut connection_settings);
+}
+
+fn stop_client(mut commands: Commands, mut selected_minions: ResMut<SelectedMinions>) {
+    commands.disconnect_client();
+    commands.remove_resource::<TokenRequest>();
+    selected_minions.0.clear();
+}
+
+fn reconnect(
+    mut reconnect_events: EventReader<Reconnect>,
+    mut connection_settings: ConnectionSettings,
+    mut connector: Connector,
+) {
+    if reconnect_events.read().count() == 0 {
+        return;
+    }
+    connector.commands.disconnect_client();
+    connector.begin(&mut connection_settings);
+}
+
+/// A connect token being fetched from the server's token service, off the main thread as that
+/// can take a few seconds. We connect once it arrives.
+#[derive(Resource)]
+struct TokenRequest {
+    token_addr: SocketAddr,
+    task: Task<Result<ConnectToken, FetchError>>,
+}
+
+fn receive_token(
+    mut request: ResMut<TokenRequest>,
+    mut connection_settings: ConnectionSettings,
+    mut connector: Connector,
 ) {
-    *client_config = match network_state.get() {
-        NetworkState::Host { .. } => {
-            let net_config = NetConfig::Local { id: 0 };
-
-            ClientConfig {
-                shared: shared_config(Mode::HostServer),
-                net: net_config,
-                ..default()
+    let Some(result) = block_on(future::poll_once(&mut request.task)) else {
+        return;
+    };
+    let token_addr = request.token_addr;
+    connector.commands.remove_resource::<TokenRequest>();
+
+    let config = result
+        .map_err(|err| match err {
+            FetchError::Incompatible(_) => err.to_string(),
+            FetchError::Io(err) => {
+                format!("Could not get a connect token from {token_addr}: {err}")
             }
+        })
+        .and_then(|token| connection_settings.client_config(Some(token)));
+    connector.connect(config);
+}
+
+/// Starts connections, or goes back to the menu with the reason they can't be made.
+#[derive(SystemParam)]
+struct Connector<'w, 's> {
+    commands: Commands<'w, 's>,
+    client_config: ResMut<'w, ClientConfig>,
+    disconnect_reason: ResMut<'w, DisconnectReason>,
+    next_network_state: ResMut<'w, NextState<NetworkState>>,
+}
+
+impl Connector<'_, '_> {
+    /// Connects right away if we can, otherwise asks the token service for a token first.
+    fn begin(&mut self, connection_settings: &mut ConnectionSettings) {
+        match connection_settings.token_service() {
+            Some(token_addr) => {
+                debug!(%token_addr, "Asking the token service for a connect token");
+                let task =
+                    AsyncComputeTaskPool::get().spawn(async move { fetch_token(token_addr) });
+                self.commands
+                    .insert_resource(TokenRequest { token_addr, task });
+            }
+            None => self.connect(connection_settings.client_config(None)),
         }
-        &NetworkState::Client {
-            server_addr,
-            client_id,
-        } => {
-            let link_conditioner = LinkConditionerConfig {
-                incoming_latency: Duration::from_millis(200),
-                incoming_jitter: Duration::from_millis(0),
-                incoming_loss: 0.0,
-            };
-            let io_config = IoConfig::from_transport(ClientTransport::UdpSocket(
-                SocketAddr::from_str("0.0.0.0:0").unwrap(),
-            ))
-            .with_conditioner(link_conditioner);
-
-            let auth = Authentication::Manual {
-                server_addr,
-                client_id,
-                private_key: KEY,
-                protocol_id: PROTOCOL_ID,
-            };
-
-            let net_config = NetConfig::Netcode {
-                auth,
-                io: io_config,
-                config: default(),
-            };
-
-            ClientConfig {
-                shared: shared_config(Mode::Separate),
-                net: net_config,
-                ..default()
+    }
+
+    fn connect(&mut self, config: Result<ClientConfig, String>) {
+        match config {
+            Ok(config) => {
+                *self.client_config = config;
+                self.commands.connect_client();
+            }
+            Err(reason) => {
+                warn!("Could not connect: {reason}");
+                self.disconnect_reason.0 = Some(reason);
+                self.next_network_state.set(NetworkState::Disconnected);
             }
         }
-        &NetworkState::ClientSteam { friend } => {
-            let link_conditioner = LinkConditionerConfig {
-                incoming_latency: Duration::from_millis(200),
-                incoming_jitter: Duration::from_millis(0),
-                incoming_loss: 0.0,
-            };
-            let net_config = NetConfig::Steam {
-                steamworks_client: Some(steam_client.clone()),
-                config: client::SteamConfig {
-                    socket_config: client::SocketConfig::P2P {
-                        virtual_port: 5001,
-                        steam_id: friend.raw(),
+    }
+}
+
+/// Replaces the UDP socket clients connect to servers with, e.g. with in-memory channels in tests.
+#[derive(Resource, Clone)]
+pub struct ClientIo(pub IoConfig);
+
+/// Everything that goes into the config of a new connection.
+#[derive(SystemParam)]
+struct ConnectionSettings<'w> {
+    network_state: Res<'w, State<NetworkState>>,
+    platform: Res<'w, Platform>,
+    link_conditions: Res<'w, LinkConditions>,
+    connect_token: ResMut<'w, IssuedConnectToken>,
+    token_port: Res<'w, TokenServicePort>,
+    client_io: Option<Res<'w, ClientIo>>,
+}
+
+impl ConnectionSettings<'_> {
+    /// The config for a new connection, with `fetched` as the connect token if we got one from
+    /// the token service.
+    fn client_config(&mut self, fetched: Option<ConnectToken>) -> Result<ClientConfig, String> {
+        let link_conditions = &self.link_conditions;
+        let config = match self.network_state.get() {
+            NetworkState::Host { .. } => {
+                let net_config = NetConfig::Local { id: 0 };
+
+                ClientConfig {
+                    shared: shared_config(Mode::HostServer),
+                    net: net_config,
+                    ..default()
+                }
+            }
+            NetworkState::Client { .. } => {
+                let io_config = match &self.client_io {
+                    Some(client_io) => client_io.0.clone(),
+                    None => IoConfig {
+                        conditioner: link_conditions.client.to_config(),
+                        ..IoConfig::from_transport(ClientTransport::UdpSocket(
+                            SocketAddr::from_str("0.0.0.0:0").unwrap(),
+                        ))
                     },
-                    app_id: 480,
-                },
-                conditioner: Some(link_conditioner),
-            };
-            ClientConfig {
-                shared: shared_config(Mode::Separate),
-                net: net_config,
-                ..default()
+                };
+
+                let auth = self.authentication(fetched)?;
+
+                let net_config = NetConfig::Netcode {
+                    auth,
+                    io: io_config,
+                    config: default(),
+                };
+
+                ClientConfig {
+                    shared: shared_config(Mode::Separate),
+                    net: net_config,
+                    ..default()
+                }
             }
+            &NetworkState::ClientP2p {
+                friend,
+                virtual_port,
+            } => {
+                let net_config = self
+                    .platform
+                    .client_net_config(friend, virtual_port, link_conditions.client.to_config())
+                    .ok_or_else(|| format!("{} can't connect to friends", self.platform.name()))?;
+                ClientConfig {
+                    shared: shared_config(Mode::Separate),
+                    net: net_config,
+                    ..default()
+                }
+            }
+            _ => return Err("Not connecting to a server".into()),
+        };
+        Ok(config)
+    }
+
+    /// Where to ask for a connect token before connecting, if we need one. The token we were
+    /// launched with is only used for the first connection, as it expires soon after.
+    fn token_service(&self) -> Option<SocketAddr> {
+        match self.network_state.get() {
+            NetworkState::Client { server_addr, .. } if self.connect_token.0.is_none() => {
+                Some(SocketAddr::new(server_addr.ip(), self.token_port.0))
+            }
+            _ => None,
         }
-        _ => return,
-    };
+    }
 
-    commands.connect_client();
+    /// Uses the token fetched from the token service, or else the one we were launched with.
+    fn authentication(&mut self, fetched: Option<ConnectToken>) -> Result<Authentication, String> {
+        if let Some(token) = fetched {
+            return Ok(Authentication::Token(token));
+        }
+        let bytes = self
+            .connect_token
+            .0
+            .take()
+            .ok_or("No connect token to connect with")?;
+        ConnectToken::try_from_bytes(&bytes)
+            .map(Authentication::Token)
+            .map_err(|err| format!("Invalid connect token: {err:?}"))
+    }
+}
+
+/// Joins once the server has accepted our build, so it never has to deal with an incompatible one.
+fn send_join_request(
+    mut message_reader: EventReader<ClientMessageEvent<HandshakeReply>>,
+    join_as: Res<JoinAs>,
+    claim: Res<ClaimSecret>,
+    local_name: Res<LocalPlayerName>,
+    mut message_manager: ResMut<ClientConnectionManager>,
+) {
+    for event in message_reader.read() {
+        if !event.message.accepted {
+            continue;
+        }
+        info!(role = ?join_as.0, "Joining the match");
+        message_manager
+            .send_message::<Channel1, _>(&ClientMessage::Join(join_as.0, *claim))
+            .unwrap();
+        message_manager
+            .send_message::<Channel1, _>(&ClientMessage::SetName(local_name.0.clone()))
+            .unwrap();
+    }
+}
+
+/// Goes back to the menu when the server won't let us join, showing why.
+fn receive_refusal(
+    mut commands: Commands,
+    mut message_reader: EventReader<ClientMessageEvent<ServerMessage>>,
+    mut disconnect_reason: ResMut<DisconnectReason>,
+    mut next_network_state: ResMut<NextState<NetworkState>>,
+) {
+    for event in message_reader.read() {
+        let ServerMessage::Refused(reason) = &event.message else {
+            continue;
+        };
+        warn!("The server refused to let us join: {reason}");
+        disconnect_reason.0 = Some(reason.clone());
+        commands.disconnect_client();
+        next_network_state.set(NetworkState::Disconnected);
+    }
+}
+
+/// Gives the local player's entity an `InputMap`, so its `ActionState` gets filled in and sent to
+/// the server. In host mode the player is the server entity itself rather than a predicted copy.
+fn add_input_map(
+    mut commands: Commands,
+    players: Query<
+        (Entity, &PlayerId),
+        (Added<PlayerId>, Or<(With<Predicted>, With<Replicating>)>),
+    >,
+    connection: Res<ClientConnection>,
+) {
+    for (entity, player_id) in &players {
+        if player_id.0 == connection.id() {
+            commands.entity(entity).insert((
+                PlayerActions::input_map(),
+                ActionState::<PlayerActions>::default(),
+            ));
+        }
+    }
+}
+
+fn issue_unit_commands(
+    mut unit_commands: EventReader<UnitCommand>,
+    keypress: Res<ButtonInput<KeyCode>>,
+    mut selected: Query<(Entity, &MinionPosition, &mut MinionTarget), With<Selected>>,
+    predicted: Query<&Predicted>,
+    mut message_manager: ResMut<ClientConnectionManager>,
+) {
+    let hotkey_commands = [UnitCommand::Stop].into_iter().filter(|command| {
+        command
+            .hotkey()
+            .is_some_and(|key| keypress.just_pressed(key))
+    });
+
+    for command in unit_commands.read().copied().chain(hotkey_commands) {
+        let confirmed = selected
+            .iter()
+            .filter_map(|(entity, ..)| predicted.get(entity).ok()?.confirmed_entity)
+            .collect();
+
+        let message = match command {
+            UnitCommand::Stop => {
+                for (_, pos, mut target) in &mut selected {
+                    target.0 = pos.0;
+                }
+                ClientMessage::Stop(confirmed)
+            }
+            UnitCommand::Train(kind) => ClientMessage::Train(confirmed, kind),
+        };
+        message_manager
+            .send_message::<Channel1, _>(&message)
+            .unwrap();
+    }
 }
 
 #[allow(clippy::too_many_arguments)]
 fn buffer_input(
     mut commands: Commands,
-    tick_manager: Res<TickManager>,
-    mut input_manager: ResMut<InputManager<Inputs>>,
-    keypress: Res<ButtonInput<KeyCode>>,
+    mut contexts: EguiContexts,
+    mut action_states: Query<&mut ActionState<PlayerActions>, With<InputMap<PlayerActions>>>,
     mouse: Res<ButtonInput<MouseButton>>,
     windows: Query<&Window, With<PrimaryWindow>>,
-    camera: Query<(&Camera, &GlobalTransform)>,
+    keypress: Res<ButtonInput<KeyCode>>,
+    camera: Query<(&Camera, &GlobalTransform), Without<MinimapCamera>>,
+    minimap: Query<(&Camera, &GlobalTransform), With<MinimapCamera>>,
     selected_minions: Res<SelectedMinions>,
     start_drag: Option<Res<StartDrag>>,
     mut gizmos: Gizmos,
-    players: Query<&PlayerColor, (With<PlayerPosition>, With<Predicted>, Without<OwnedBy>)>,
     mut my_minions: Query<
         (Entity, &MinionPosition, &mut MinionTarget, &OwnedBy),
         Or<(With<Predicted>, With<PreSpawnedPlayerObject>)>,
@@ -159,33 +426,28 @@ fn buffer_input(
     connection: Res<ClientConnection>,
     mut message_manager: ResMut<ClientConnectionManager>,
 ) {
-    let tick = tick_manager.tick();
-    let player_color = players.get_single().copied().map(|c| c.0).ok();
-
-    let mut input = Inputs::None;
-    let direction = Direction {
-        up: keypress.pressed(KeyCode::KeyW),
-        down: keypress.pressed(KeyCode::KeyS),
-        left: keypress.pressed(KeyCode::KeyA),
-        right: keypress.pressed(KeyCode::KeyD),
-    };
-
-    if direction.up || direction.down || direction.left || direction.right {
-        input = Inputs::Direction(direction);
-    }
-
-    input_manager.add_input(input, tick);
-
     let window = windows.single();
     let (camera, camera_transform) = camera.single();
     if let Some(mouse_pos) = window
         .cursor_position()
         .and_then(|cursor| camera.viewport_to_world_2d(camera_transform, cursor).ok())
     {
-        if let Some(player_color) = player_color {
-            if keypress.just_pressed(KeyCode::Space) {
-                input_manager.add_input(Inputs::Spawn(mouse_pos, player_color), tick);
-            }
+        for mut action_state in &mut action_states {
+            action_state.set_axis_pair(&PlayerActions::Cursor, mouse_pos);
+        }
+
+        // Clicks on the HUD, other egui windows or the minimap are not meant for the world, and
+        // alt-clicks place map pings instead
+        let over_minimap = window.cursor_position().is_some_and(|cursor| {
+            minimap
+                .iter()
+                .any(|(camera, tf)| minimap_to_world(camera, tf, cursor).is_some())
+        });
+        if contexts.ctx_mut().is_pointer_over_area()
+            || over_minimap
+            || keypress.any_pressed([KeyCode::AltLeft, KeyCode::AltRight])
+        {
+            return;
         }
 
         if mouse.just_pressed(MouseButton::Right) {
@@ -232,7 +494,7 @@ fn buffer_input(
                 for &minion in &selected_minions {
                     commands.entity(minion).insert(Selected);
                 }
-                println!("Selected {} minions", selected_minions.len());
+                debug!(count = selected_minions.len(), "Selected minions");
                 commands.insert_resource(SelectedMinions(selected_minions));
             }
         }
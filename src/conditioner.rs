@@ -0,0 +1,119 @@
+use std::time::Duration;
+
+use bevy::input::common_conditions::input_toggle_active;
+use bevy::prelude::*;
+use bevy_egui::EguiContexts;
+use bevy_egui::egui::{Align2, DragValue, Grid, Slider};
+use lightyear::prelude::LinkConditionerConfig;
+use serde::{Deserialize, Serialize};
+
+use crate::client::Reconnect;
+use crate::networking::IsClient;
+
+pub struct ConditionerPlugin;
+
+impl Plugin for ConditionerPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<LinkConditions>().add_systems(
+            Update,
+            show_conditioner_panel.run_if(input_toggle_active(false, KeyCode::F8)),
+        );
+    }
+}
+
+/// Simulated network conditions for packets arriving on one side of the connection.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Conditions {
+    pub latency_ms: u64,
+    pub jitter_ms: u64,
+    /// Fraction of packets dropped, between 0 and 1.
+    pub loss: f32,
+}
+
+impl Conditions {
+    pub fn is_disabled(&self) -> bool {
+        self.latency_ms == 0 && self.jitter_ms == 0 && self.loss == 0.0
+    }
+
+    /// The lightyear conditioner for these conditions, or `None` if they don't change anything.
+    pub fn to_config(self) -> Option<LinkConditionerConfig> {
+        (!self.is_disabled()).then(|| LinkConditionerConfig {
+            incoming_latency: Duration::from_millis(self.latency_ms),
+            incoming_jitter: Duration::from_millis(self.jitter_ms),
+            incoming_loss: self.loss,
+        })
+    }
+}
+
+/// Network conditions to simulate, used whenever a client or server is started. Everything is off
+/// by default.
+#[derive(Resource, Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LinkConditions {
+    /// Applied to what clients receive from the server.
+    pub client: Conditions,
+    /// Applied to what the server receives from clients.
+    pub server: Conditions,
+}
+
+impl LinkConditions {
+    /// Command line flags that reproduce these conditions, used to pass them on to child
+    /// processes.
+    pub fn to_args(self) -> Vec<String> {
+        let mut args = vec![];
+        for (side, conditions) in [("client", self.client), ("server", self.server)] {
+            args.extend([
+                format!("--{side}-latency"),
+                conditions.latency_ms.to_string(),
+                format!("--{side}-jitter"),
+                conditions.jitter_ms.to_string(),
+                format!("--{side}-loss"),
+                conditions.loss.to_string(),
+            ]);
+        }
+        args
+    }
+}
+
+fn show_conditioner_panel(
+    mut contexts: EguiContexts,
+    mut conditions: ResMut<LinkConditions>,
+    is_client: Option<Res<State<IsClient>>>,
+    mut reconnect: EventWriter<Reconnect>,
+) {
+    bevy_egui::egui::Window::new("Link conditioner")
+        .anchor(Align2::LEFT_CENTER, (0.0, 0.0))
+        .resizable([false, false])
+        .collapsible(false)
+        .show(contexts.ctx_mut(), |ui| {
+            Grid::new("conditioner").num_columns(3).show(ui, |ui| {
+                ui.label("");
+                ui.strong("Client receives");
+                ui.strong("Server receives");
+                ui.end_row();
+
+                let LinkConditions { client, server } = &mut *conditions;
+                ui.label("Latency (ms)");
+                ui.add(DragValue::new(&mut client.latency_ms).range(0..=2000));
+                ui.add(DragValue::new(&mut server.latency_ms).range(0..=2000));
+                ui.end_row();
+
+                ui.label("Jitter (ms)");
+                ui.add(DragValue::new(&mut client.jitter_ms).range(0..=1000));
+                ui.add(DragValue::new(&mut server.jitter_ms).range(0..=1000));
+                ui.end_row();
+
+                ui.label("Loss");
+                ui.add(Slider::new(&mut client.loss, 0.0..=1.0));
+                ui.add(Slider::new(&mut server.loss, 0.0..=1.0));
+                ui.end_row();
+            });
+
+            ui.label("Client changes apply when reconnecting.");
+            ui.label("Server changes apply when the server is started again.");
+            if is_client.is_some() && ui.button("Reconnect now").clicked() {
+                reconnect.send(Reconnect { manual: true });
+            }
+        });
+}
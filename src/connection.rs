@@ -0,0 +1,335 @@
+use bevy::prelude::*;
+use bevy_egui::EguiContexts;
+use bevy_egui::egui::{Align2, Color32};
+use lightyear::prelude::client::{
+    ClientCommands, Confirmed, Interpolated, NetworkingState as ClientNetworkingState, Predicted,
+};
+use lightyear::prelude::*;
+
+use crate::ai::{AddAi, Difficulty};
+use crate::client::Reconnect;
+use crate::game::Replayed;
+use crate::networking::{DisconnectReason, IsClient, IsServer, NetworkState};
+use crate::platform::{Friends, Platform};
+use crate::save::SaveMatch;
+
+/// Shows what the connection to the server is doing, retries dropped connections and lets the
+/// player leave the match.
+pub struct ConnectionPlugin;
+
+impl Plugin for ConnectionPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_computed_state::<IsRemoteClient>()
+            .init_resource::<Retry>()
+            .add_event::<LeaveMatch>()
+            .add_systems(
+                Update,
+                (
+                    (track_connection, retry_connection, show_connection_status)
+                        .chain()
+                        .run_if(in_state(IsRemoteClient)),
+                    show_game_menu.run_if(in_state(IsClient)),
+                    leave_match,
+                ),
+            )
+            .add_systems(OnExit(IsRemoteClient), reset_retry)
+            .add_systems(OnEnter(NetworkState::Disconnected), despawn_match);
+    }
+}
+
+/// Give up on a connection that hasn't been established after this many seconds.
+const CONNECT_TIMEOUT: f64 = 10.0;
+const MAX_ATTEMPTS: u32 = 5;
+/// Seconds to wait before the first retry, doubled for every retry after that.
+const RETRY_DELAY: f64 = 1.0;
+const MAX_RETRY_DELAY: f64 = 16.0;
+
+/// Connected, or trying to connect, to a server running somewhere else. Only these connections
+/// can fail; in host mode the client lives in the same app as the server.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+pub struct IsRemoteClient;
+
+impl ComputedStates for IsRemoteClient {
+    type SourceStates = NetworkState;
+
+    fn compute(network_state: NetworkState) -> Option<Self> {
+        match network_state {
+            NetworkState::Client { .. } | NetworkState::ClientP2p { .. } => Some(Self),
+            _ => None,
+        }
+    }
+}
+
+/// Sent to disconnect, or stop hosting, and go back to the menu.
+#[derive(Event, Clone, Copy, Debug)]
+pub struct LeaveMatch;
+
+#[derive(Resource, Default)]
+struct Retry {
+    /// Failed attempts since we were last connected.
+    attempt: u32,
+    /// When the current attempt started, while it's in progress.
+    connecting_since: Option<f64>,
+    /// When to try again, after an attempt failed.
+    retry_at: Option<f64>,
+    /// Whether the current attempt got anywhere, so the disconnected state lightyear starts in
+    /// isn't taken for a failure.
+    in_progress: bool,
+    was_connected: bool,
+}
+
+#[allow(clippy::too_many_arguments)]
+fn track_connection(
+    mut commands: Commands,
+    mut retry: ResMut<Retry>,
+    mut reconnects: EventReader<Reconnect>,
+    state: Res<State<ClientNetworkingState>>,
+    network_state: Res<State<NetworkState>>,
+    mut disconnect_reason: ResMut<DisconnectReason>,
+    mut next_network_state: ResMut<NextState<NetworkState>>,
+    time: Res<Time>,
+) {
+    // Dropping the connection to reconnect on purpose isn't a failure
+    if reconnects.read().any(|reconnect| reconnect.manual) {
+        *retry = Retry {
+            was_connected: retry.was_connected,
+            ..default()
+        };
+    }
+
+    let now = time.elapsed_secs_f64();
+    match state.get() {
+        ClientNetworkingState::Connecting => {
+            retry.in_progress = true;
+            let since = *retry.connecting_since.get_or_insert(now);
+            if now - since > CONNECT_TIMEOUT {
+                warn!("Timed out connecting to the server");
+                commands.disconnect_client();
+            }
+        }
+        ClientNetworkingState::Connected => {
+            if !retry.was_connected || retry.attempt > 0 {
+                info!("Connected");
+            }
+            *retry = Retry {
+                in_progress: true,
+                was_connected: true,
+                ..default()
+            };
+        }
+        ClientNetworkingState::Disconnected => {
+            if !retry.in_progress {
+                return;
+            }
+            retry.in_progress = false;
+            retry.connecting_since = None;
+
+            if retry.attempt + 1 >= MAX_ATTEMPTS {
+                let reason = if retry.was_connected {
+                    "Lost the connection to the server".into()
+                } else {
+                    format!(
+                        "Could not connect to {}",
+                        describe_target(network_state.get())
+                    )
+                };
+                warn!("{reason}, giving up");
+                disconnect_reason.0 = Some(reason);
+                next_network_state.set(NetworkState::Disconnected);
+                return;
+            }
+
+            let delay = (RETRY_DELAY * 2f64.powi(retry.attempt as i32)).min(MAX_RETRY_DELAY);
+            retry.attempt += 1;
+            retry.retry_at = Some(now + delay);
+            info!(
+                attempt = retry.attempt,
+                delay, "Connection failed, retrying"
+            );
+        }
+    }
+}
+
+fn retry_connection(
+    mut retry: ResMut<Retry>,
+    mut reconnect: EventWriter<Reconnect>,
+    time: Res<Time>,
+) {
+    if retry
+        .retry_at
+        .is_some_and(|at| time.elapsed_secs_f64() >= at)
+    {
+        retry.retry_at = None;
+        reconnect.send(Reconnect { manual: false });
+    }
+}
+
+fn reset_retry(mut retry: ResMut<Retry>) {
+    *retry = Retry::default();
+}
+
+fn show_connection_status(
+    mut contexts: EguiContexts,
+    mut retry: ResMut<Retry>,
+    state: Res<State<ClientNetworkingState>>,
+    network_state: Res<State<NetworkState>>,
+    mut leave: EventWriter<LeaveMatch>,
+    time: Res<Time>,
+) {
+    let now = time.elapsed_secs_f64();
+    let target = describe_target(network_state.get());
+    let (title, status) = match (state.get(), retry.retry_at) {
+        (ClientNetworkingState::Connected, _) => return,
+        (_, Some(at)) => {
+            let title = if retry.was_connected {
+                "Connection lost"
+            } else {
+                "Could not connect"
+            };
+            let status = format!(
+                "Retrying in {:.0} s (attempt {} of {MAX_ATTEMPTS})",
+                (at - now).max(0.0).ceil(),
+                retry.attempt + 1,
+            );
+            (title, status)
+        }
+        (_, None) => {
+            let elapsed = retry.connecting_since.map_or(0.0, |since| now - since);
+            (
+                "Connecting",
+                format!("Connecting to {target}... {elapsed:.0} s"),
+            )
+        }
+    };
+
+    bevy_egui::egui::Window::new(title)
+        .anchor(Align2::CENTER_CENTER, (0.0, 0.0))
+        .resizable(false)
+        .collapsible(false)
+        .show(contexts.ctx_mut(), |ui| {
+            if retry.retry_at.is_some() {
+                ui.colored_label(Color32::from_rgb(255, 80, 80), status);
+            } else {
+                ui.label(status);
+            }
+            ui.horizontal(|ui| {
+                if retry.retry_at.is_some() && ui.button("Retry now").clicked() {
+                    retry.retry_at = Some(now);
+                }
+                if ui.button("Back to menu").clicked() {
+                    leave.send(LeaveMatch);
+                }
+            });
+        });
+}
+
+/// The in-game menu, opened with Escape. Hosts can save the match, add AI players and invite their
+/// friends from here.
+#[allow(clippy::too_many_arguments)]
+fn show_game_menu(
+    mut open: Local<bool>,
+    mut contexts: EguiContexts,
+    keypress: Res<ButtonInput<KeyCode>>,
+    platform: Res<Platform>,
+    friends: Res<Friends>,
+    is_server: Option<Res<State<IsServer>>>,
+    mut save: EventWriter<SaveMatch>,
+    mut add_ai: EventWriter<AddAi>,
+    mut leave: EventWriter<LeaveMatch>,
+) {
+    if keypress.just_pressed(KeyCode::Escape) && !contexts.ctx_mut().wants_keyboard_input() {
+        *open = !*open;
+    }
+    if !*open {
+        return;
+    }
+
+    bevy_egui::egui::Window::new("Menu")
+        .anchor(Align2::CENTER_CENTER, (0.0, 0.0))
+        .resizable(false)
+        .collapsible(false)
+        .show(contexts.ctx_mut(), |ui| {
+            if ui.button("Leave match").clicked() {
+                leave.send(LeaveMatch);
+                *open = false;
+            }
+            if ui.button("Resume").clicked() {
+                *open = false;
+            }
+            if is_server.is_some() && ui.button("Save match").clicked() {
+                save.send(SaveMatch::timestamped());
+            }
+
+            if is_server.is_some() {
+                ui.separator();
+                ui.label("Add an AI player");
+                ui.horizontal(|ui| {
+                    for difficulty in Difficulty::ALL {
+                        if ui.button(difficulty.name()).clicked() {
+                            add_ai.send(AddAi(difficulty));
+                        }
+                    }
+                });
+            }
+
+            if is_server.is_some() && !friends.is_empty() {
+                ui.separator();
+                ui.label(format!("Invite {} friends", platform.name()));
+                for friend in friends.iter().filter(|friend| friend.lobby.is_none()) {
+                    if ui.button(&friend.name).clicked() {
+                        match platform.invite(friend.id) {
+                            Ok(()) => info!(name = %friend.name, "Invited friend"),
+                            Err(err) => {
+                                warn!(name = %friend.name, "Could not invite friend: {err}")
+                            }
+                        }
+                    }
+                }
+            }
+        });
+}
+
+/// Disconnecting and stopping the server happen when leaving [`IsClient`] and [`IsServer`].
+///
+/// [`IsServer`]: crate::networking::IsServer
+fn leave_match(
+    mut events: EventReader<LeaveMatch>,
+    mut next_network_state: ResMut<NextState<NetworkState>>,
+) {
+    if events.read().count() > 0 {
+        info!("Leaving the match");
+        next_network_state.set(NetworkState::Disconnected);
+    }
+}
+
+/// Removes everything the match left behind, so the next one starts from a clean world.
+fn despawn_match(
+    mut commands: Commands,
+    entities: Query<
+        Entity,
+        (
+            Or<(
+                With<Replicated>,
+                With<Replicating>,
+                With<Predicted>,
+                With<Interpolated>,
+                With<Confirmed>,
+                With<PreSpawnedPlayerObject>,
+                With<Replayed>,
+            )>,
+            Without<Parent>,
+        ),
+    >,
+) {
+    for entity in &entities {
+        commands.entity(entity).despawn_recursive();
+    }
+}
+
+fn describe_target(network_state: &NetworkState) -> String {
+    match network_state {
+        NetworkState::Client { server_addr, .. } => server_addr.to_string(),
+        NetworkState::ClientP2p { friend, .. } => format!("friend {}", friend.0),
+        _ => "the server".into(),
+    }
+}
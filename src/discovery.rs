@@ -0,0 +1,288 @@
+use std::io::ErrorKind;
+use std::net::{Ipv4Addr, SocketAddr, UdpSocket};
+use std::time::Duration;
+
+use bevy::prelude::*;
+use bevy::time::common_conditions::on_timer;
+use bevy::utils::HashMap;
+use bevy_egui::egui::{Color32, Ui};
+use lightyear::prelude::*;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+use crate::game::handshake::BuildInfo;
+use crate::game::player::PlayerId;
+use crate::networking::{IsServer, LocalPlayerName, NetworkState};
+use crate::token_service::TokenServicePort;
+
+/// Lets players find games on their network. Servers answer UDP probes with a [`ServerInfo`],
+/// and the network menu probes every few seconds and lists whoever answered.
+///
+/// Servers listen on the first free port of [`DISCOVERY_PORTS`] and clients probe all of them,
+/// so several servers on one machine can be found without needing `SO_REUSEADDR`.
+pub struct DiscoveryPlugin;
+
+impl Plugin for DiscoveryPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<ServerName>()
+            .init_resource::<LanServers>()
+            .add_systems(Startup, open_probe_socket)
+            .add_systems(OnEnter(IsServer), start_responder)
+            .add_systems(OnExit(IsServer), stop_responder)
+            .add_systems(
+                Update,
+                (
+                    answer_probes.run_if(resource_exists::<DiscoveryResponder>),
+                    (
+                        send_probes.run_if(on_timer(PROBE_INTERVAL)),
+                        receive_probe_replies,
+                    )
+                        .run_if(in_state(NetworkState::Disconnected)),
+                ),
+            );
+    }
+}
+
+const DISCOVERY_PORTS: std::ops::Range<u16> = 5990..5998;
+pub const PROBE: &[u8] = b"AOE?";
+pub const REPLY: &[u8] = b"AOE!";
+const PROBE_INTERVAL: Duration = Duration::from_secs(2);
+/// Servers that haven't answered for this long are dropped from the list.
+const SERVER_TIMEOUT: f64 = 6.0;
+/// The only map there is, for now.
+pub const MAP_NAME: &str = "Meadow";
+
+/// Name servers advertise, defaulting to one based on the host's player name.
+#[derive(Resource, Clone, Debug, Default)]
+pub struct ServerName(pub Option<String>);
+
+/// What a server tells players looking for a game.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct ServerInfo {
+    pub name: String,
+    pub map: String,
+    pub players: u32,
+    pub max_players: u32,
+    pub build: BuildInfo,
+    pub game_port: u16,
+    pub token_port: u16,
+}
+
+impl ServerInfo {
+    /// Up to this many players can join a server.
+    pub const MAX_PLAYERS: u32 = 8;
+}
+
+/// Encodes `value` as a datagram starting with `prefix`, as server info travels between servers,
+/// clients and the master server.
+pub fn encode_datagram(prefix: &[u8], value: &impl Serialize) -> Option<Vec<u8>> {
+    match toml::to_string(value) {
+        Ok(text) => Some([prefix, text.as_bytes()].concat()),
+        Err(err) => {
+            error!(
+                "Could not encode {}: {err}",
+                std::any::type_name_of_val(value)
+            );
+            None
+        }
+    }
+}
+
+/// Decodes a datagram made by [`encode_datagram`], if it starts with `prefix`.
+pub fn decode_datagram<T: DeserializeOwned>(prefix: &[u8], datagram: &[u8]) -> Option<T> {
+    let text = datagram.strip_prefix(prefix)?;
+    let decoded = std::str::from_utf8(text)
+        .map_err(|err| err.to_string())
+        .and_then(|text| toml::from_str(text).map_err(|err| err.to_string()));
+    decoded
+        .inspect_err(|err| debug!("Ignoring bad {}: {err}", std::any::type_name::<T>()))
+        .ok()
+}
+
+/// Builds the info for the server this app runs, if it runs one.
+pub fn local_server_info(
+    network_state: &NetworkState,
+    server_name: &ServerName,
+    local_name: &LocalPlayerName,
+    token_port: u16,
+    players: usize,
+) -> Option<ServerInfo> {
+    let (&NetworkState::Host(addr) | &NetworkState::Server(addr)) = network_state else {
+        return None;
+    };
+    let name = server_name.0.clone().unwrap_or_else(|| {
+        if local_name.is_empty() {
+            "Dedicated server".into()
+        } else {
+            format!("{}'s game", local_name.0)
+        }
+    });
+    Some(ServerInfo {
+        name,
+        map: MAP_NAME.into(),
+        players: players as u32,
+        max_players: ServerInfo::MAX_PLAYERS,
+        build: BuildInfo::local(),
+        game_port: addr.port(),
+        token_port,
+    })
+}
+
+/// The socket this server answers discovery probes on, while it runs.
+#[derive(Resource)]
+pub struct DiscoveryResponder(UdpSocket);
+
+impl DiscoveryResponder {
+    pub fn port(&self) -> Option<u16> {
+        self.0.local_addr().ok().map(|addr| addr.port())
+    }
+}
+
+fn start_responder(mut commands: Commands) {
+    let socket = DISCOVERY_PORTS.clone().find_map(|port| {
+        let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, port)).ok()?;
+        socket.set_nonblocking(true).ok()?;
+        Some(socket)
+    });
+    match socket {
+        Some(socket) => {
+            info!(addr = ?socket.local_addr().ok(), "Answering LAN discovery probes");
+            commands.insert_resource(DiscoveryResponder(socket));
+        }
+        None => warn!("No free port for LAN discovery, the game won't show up on the LAN"),
+    }
+}
+
+fn stop_responder(mut commands: Commands) {
+    commands.remove_resource::<DiscoveryResponder>();
+}
+
+fn answer_probes(
+    responder: Res<DiscoveryResponder>,
+    network_state: Res<State<NetworkState>>,
+    server_name: Res<ServerName>,
+    local_name: Res<LocalPlayerName>,
+    token_port: Res<TokenServicePort>,
+    players: Query<(), (With<PlayerId>, With<Replicating>)>,
+) {
+    let mut buf = [0; 16];
+    loop {
+        let peer = match responder.0.recv_from(&mut buf) {
+            Ok((len, peer)) if &buf[..len] == PROBE => peer,
+            Ok(_) => continue,
+            Err(err) if err.kind() == ErrorKind::WouldBlock => return,
+            Err(err) => {
+                warn!("LAN discovery failed: {err}");
+                return;
+            }
+        };
+
+        let Some(info) = local_server_info(
+            network_state.get(),
+            &server_name,
+            &local_name,
+            token_port.0,
+            players.iter().count(),
+        ) else {
+            return;
+        };
+        let Some(reply) = encode_datagram(REPLY, &info) else {
+            return;
+        };
+        let _ = responder.0.send_to(&reply, peer);
+    }
+}
+
+/// Servers that answered our probes, by the address to connect to.
+#[derive(Resource, Default)]
+pub struct LanServers(HashMap<SocketAddr, (ServerInfo, f64)>);
+
+#[derive(Resource)]
+struct ProbeSocket(UdpSocket);
+
+fn open_probe_socket(mut commands: Commands) {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).and_then(|socket| {
+        socket.set_broadcast(true)?;
+        socket.set_nonblocking(true)?;
+        Ok(socket)
+    });
+    match socket {
+        Ok(socket) => commands.insert_resource(ProbeSocket(socket)),
+        Err(err) => warn!("Could not open a socket for LAN discovery: {err}"),
+    }
+}
+
+fn send_probes(socket: Option<Res<ProbeSocket>>) {
+    let Some(socket) = socket else {
+        return;
+    };
+    for port in DISCOVERY_PORTS {
+        // Broadcasts don't always loop back, so ask this machine directly too
+        for ip in [Ipv4Addr::BROADCAST, Ipv4Addr::LOCALHOST] {
+            let _ = socket.0.send_to(PROBE, (ip, port));
+        }
+    }
+}
+
+fn receive_probe_replies(
+    socket: Option<Res<ProbeSocket>>,
+    mut servers: ResMut<LanServers>,
+    time: Res<Time>,
+) {
+    let now = time.elapsed_secs_f64();
+    servers
+        .0
+        .retain(|_, (_, seen)| now - *seen < SERVER_TIMEOUT);
+
+    let Some(socket) = socket else {
+        return;
+    };
+    let mut buf = [0; 1024];
+    while let Ok((len, peer)) = socket.0.recv_from(&mut buf) {
+        let Some(info) = decode_datagram::<ServerInfo>(REPLY, &buf[..len]) else {
+            continue;
+        };
+        let addr = SocketAddr::new(peer.ip(), info.game_port);
+        servers.0.insert(addr, (info, now));
+    }
+}
+
+/// Lists the servers found on the LAN, returning the one the player wants to join.
+pub fn show_lan_servers(ui: &mut Ui, servers: &LanServers) -> Option<(SocketAddr, u16)> {
+    if servers.0.is_empty() {
+        ui.label("Looking for games...");
+        return None;
+    }
+
+    let mut servers = servers.0.iter().collect::<Vec<_>>();
+    servers.sort_by_key(|&(addr, _)| addr);
+
+    let mut join = None;
+    for (&addr, (info, _)) in servers {
+        show_server_entry(ui, addr, info, &mut join);
+    }
+    join
+}
+
+/// One line of a server list: a join button, or why it can't be joined.
+pub fn show_server_entry(
+    ui: &mut Ui,
+    addr: SocketAddr,
+    info: &ServerInfo,
+    join: &mut Option<(SocketAddr, u16)>,
+) {
+    ui.horizontal(|ui| {
+        let label = format!(
+            "{} - {} ({}/{})",
+            info.name, info.map, info.players, info.max_players
+        );
+        if !BuildInfo::local().is_compatible(&info.build) {
+            ui.colored_label(Color32::GRAY, label)
+                .on_hover_text(BuildInfo::mismatch_reason(&info.build));
+        } else if info.players >= info.max_players {
+            ui.colored_label(Color32::GRAY, label).on_hover_text("Full");
+        } else if ui.button(label).on_hover_text(addr.to_string()).clicked() {
+            *join = Some((addr, info.token_port));
+        }
+    });
+}
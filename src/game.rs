@@ -1,38 +1,72 @@
+use std::any::type_name;
+use std::sync::LazyLock;
 use std::time::Duration;
 
 use bevy::ecs::entity::MapEntities;
 use bevy::prelude::*;
+use bevy::reflect::GetTypeRegistration;
 use bevy::render::camera::ScalingMode;
-use lightyear::connection::netcode::PRIVATE_KEY_BYTES;
-use lightyear::prelude::client::{ComponentSyncMode, Interpolated, Predicted};
+use bevy::utils::HashMap;
+use lightyear::prelude::client::{ComponentSyncMode, Interpolated, Predicted, SyncComponent};
 use lightyear::prelude::*;
+use serde::de::DeserializeOwned;
 
+use self::chat::{ChatLine, ChatPlugin, ChatScope};
+use self::handshake::{BuildInfo, HandshakePlugin, HandshakeReply};
 use self::minion::MinionPlugin;
 use self::minion::MinionPosition;
 use self::minion::MinionTarget;
-use self::player::{Inputs, PlayerColor, PlayerId, PlayerPlugin, PlayerPosition};
+use self::minion::UnitKind;
+use self::ping::{MapPing, PingPlugin};
+use self::player::{
+    Ping, PlayerActions, PlayerColor, PlayerId, PlayerName, PlayerPlugin, PlayerPosition, Team,
+};
 use self::resource::Item;
 use self::resource::ItemPos;
 use self::resource::ResourcePlugin;
-use self::resource::Scoreboard;
+use self::resource::{Apples, Scoreboard};
+use self::snapshot::{Snapshot, SnapshotPlugin};
+use self::status::Health;
+use self::status::StatusPlugin;
 
+pub mod chat;
+pub mod handshake;
 pub mod minion;
+pub mod ping;
 pub mod player;
+pub mod rate_limit;
 pub mod resource;
+pub mod snapshot;
+pub mod status;
 
 pub type Relevant = Or<(
     With<Predicted>,
     With<Interpolated>,
     With<Replicating>,
     With<PreSpawnedPlayerObject>,
+    With<Replayed>,
 )>;
 
+/// Shown from snapshots rather than replicated, see [`snapshot`].
+#[derive(Component, Clone, Copy, Debug)]
+pub struct Replayed;
+
 pub struct GamePlugin;
 
 impl Plugin for GamePlugin {
     fn build(&self, app: &mut App) {
-        app.add_plugins((ProtocolPlugin, PlayerPlugin, MinionPlugin, ResourcePlugin))
-            .add_systems(Startup, spawn_camera);
+        app.add_plugins((
+            ProtocolPlugin,
+            PlayerPlugin,
+            MinionPlugin,
+            ResourcePlugin,
+            StatusPlugin,
+            ChatPlugin,
+            PingPlugin,
+            HandshakePlugin,
+            SnapshotPlugin,
+        ))
+        .add_systems(Startup, spawn_camera);
     }
 }
 
@@ -58,82 +92,275 @@ fn spawn_camera(mut commands: Commands) {
     ));
 }
 
+/// How a client takes part in a match.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum Role {
+    #[default]
+    Player,
+    /// Watches everyone without playing, and doesn't show up on the scoreboard.
+    Spectator,
+}
+
+/// The clients watching the match, by what they're called. Kept on the server.
+#[derive(Resource, Default, Debug)]
+pub struct Spectators(pub HashMap<ClientId, PlayerName>);
+
+/// Clients the server told why it won't have them, with when it did. They're disconnected a little
+/// later, which gives the message time to get through, though they normally leave on their own
+/// first. Kept on the server.
+#[derive(Resource, Default, Debug)]
+pub struct Dismissed(pub HashMap<ClientId, f64>);
+
+/// A number only one player knows, that they take their slot in a saved match back with when they
+/// come back under another client id. Clients keep theirs between runs, see
+/// [`load_claim_secret`](crate::save::load_claim_secret), and the server keeps it on their player.
+#[derive(Resource, Component, Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct ClaimSecret(pub u64);
+
+impl Default for ClaimSecret {
+    /// A new secret, for players that don't keep one.
+    fn default() -> Self {
+        Self(rand::random())
+    }
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
 pub enum ClientMessage {
+    /// Sent once after connecting, before anything else.
+    Join(Role, ClaimSecret),
     Target(Vec<Entity>, Vec2),
+    Stop(Vec<Entity>),
+    /// Has each of the units train one of the kind, as long as the apples for it last.
+    Train(Vec<Entity>, UnitKind),
+    SetName(String),
+    Chat(String, ChatScope),
+    /// Sent on [`PingChannel`].
+    Ping(Vec2),
 }
 
 impl MapEntities for ClientMessage {
     fn map_entities<M: EntityMapper>(&mut self, entity_mapper: &mut M) {
         match self {
-            ClientMessage::Target(entities, _) => {
+            ClientMessage::Target(entities, _)
+            | ClientMessage::Stop(entities)
+            | ClientMessage::Train(entities, _) => {
                 for entity in entities {
                     *entity = entity_mapper.map_entity(*entity);
                 }
             }
+            ClientMessage::Join(..)
+            | ClientMessage::SetName(_)
+            | ClientMessage::Chat(..)
+            | ClientMessage::Ping(_) => {}
         }
     }
 }
 
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum ServerMessage {
+    Chat(ChatLine),
+    /// Sent on [`PingChannel`].
+    Ping(MapPing),
+    /// Sent on [`StatsChannel`].
+    Heartbeat(u32),
+    /// Sent on [`StatsChannel`] every second.
+    Load(ServerLoad),
+    /// Confirms a [`Role::Spectator`] join, with how far behind the match spectators are kept.
+    Spectating {
+        delay_ms: u32,
+    },
+    /// What spectators see of the match instead of replication, sent every
+    /// [`SNAPSHOT_INTERVAL`](snapshot::SNAPSHOT_INTERVAL) ticks once the spectator delay has
+    /// passed.
+    Snapshot(Snapshot),
+    /// Sent instead of a player when the server won't take the join, e.g. because the match is
+    /// full. The client disconnects with the reason.
+    Refused(String),
+}
+
+/// How hard the server is working, averaged over the last second.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+pub struct ServerLoad {
+    pub frame_us: u32,
+    pub max_frame_us: u32,
+    /// Everything replicated, players and units alike.
+    pub entities: u32,
+}
+
+/// Only carries the handshake, see [`handshake`].
+#[derive(Channel)]
+pub struct HandshakeChannel;
+
 #[derive(Channel)]
 pub struct Channel1;
 
-pub const PROTOCOL_ID: u64 = 0;
-pub const KEY: [u8; PRIVATE_KEY_BYTES] = [0; PRIVATE_KEY_BYTES];
+/// Map pings and alerts, kept apart so they don't queue up behind commands and chat.
+#[derive(Channel)]
+pub struct PingChannel;
+
+/// Unreliable heartbeats used to measure packet loss.
+#[derive(Channel)]
+pub struct StatsChannel;
+
+/// Identifies which builds can talk to each other, so a server rejects clients with an
+/// incompatible protocol instead of failing in odd ways later. It's derived from the crate version
+/// and from everything [`ProtocolPlugin`] registers, in order, so it changes by itself whenever
+/// what's sent between them does.
+pub static PROTOCOL_ID: LazyLock<u64> = LazyLock::new(|| protocol_id(&protocol_registrations()));
+
+/// What [`ProtocolPlugin`] registers, in order, as text: the type names and how each is sent.
+pub fn protocol_registrations() -> Vec<String> {
+    let mut registrar = Registrar {
+        app: None,
+        registrations: Vec::new(),
+    };
+    register_protocol(&mut registrar);
+    registrar.registrations
+}
+
+/// Hashes the crate version and `registrations` into a protocol id.
+pub fn protocol_id(registrations: &[String]) -> u64 {
+    // FNV-1a, with a separator after each part so moving text between them changes the hash
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for part in
+        std::iter::once(env!("CARGO_PKG_VERSION")).chain(registrations.iter().map(String::as_str))
+    {
+        for byte in part.bytes().chain([0]) {
+            hash ^= byte as u64;
+            hash = hash.wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+    hash
+}
 
 pub struct ProtocolPlugin;
 
 impl Plugin for ProtocolPlugin {
     fn build(&self, app: &mut App) {
-        app.add_plugins(InputPlugin::<Inputs>::default());
-
-        app.register_message::<ClientMessage>(ChannelDirection::ClientToServer)
-            .add_map_entities();
-
-        app.register_type::<PlayerId>()
-            .register_component::<PlayerId>(ChannelDirection::ServerToClient)
-            .add_prediction(ComponentSyncMode::Once)
-            .add_interpolation(ComponentSyncMode::Once);
-        app.register_type::<PlayerPosition>()
-            .register_component::<PlayerPosition>(ChannelDirection::ServerToClient)
-            .add_prediction(ComponentSyncMode::Full)
-            .add_interpolation(ComponentSyncMode::Full)
-            .add_linear_interpolation_fn();
-        app.register_type::<PlayerColor>()
-            .register_component::<PlayerColor>(ChannelDirection::ServerToClient)
-            .add_prediction(ComponentSyncMode::Once)
-            .add_interpolation(ComponentSyncMode::Once);
-        app.register_type::<MinionPosition>()
-            .register_component::<MinionPosition>(ChannelDirection::ServerToClient)
-            .add_prediction(ComponentSyncMode::Full)
-            .add_interpolation(ComponentSyncMode::Full)
-            .add_linear_interpolation_fn();
-        app.register_type::<MinionTarget>()
-            .register_component::<MinionTarget>(ChannelDirection::ServerToClient)
-            .add_prediction(ComponentSyncMode::Simple)
-            .add_interpolation(ComponentSyncMode::Simple);
-        app.register_type::<OwnedBy>()
-            .register_component::<OwnedBy>(ChannelDirection::ServerToClient)
-            .add_prediction(ComponentSyncMode::Once)
-            .add_interpolation(ComponentSyncMode::Once);
-        app.register_type::<Item>()
-            .register_component::<Item>(ChannelDirection::ServerToClient)
-            .add_prediction(ComponentSyncMode::Once)
-            .add_interpolation(ComponentSyncMode::Once);
-        app.register_type::<ItemPos>()
-            .register_component::<ItemPos>(ChannelDirection::ServerToClient)
-            .add_prediction(ComponentSyncMode::Once)
-            .add_interpolation(ComponentSyncMode::Once);
-        app.register_type::<Scoreboard>()
-            .register_component::<Scoreboard>(ChannelDirection::ServerToClient);
-
-        app.add_channel::<Channel1>(ChannelSettings {
-            mode: ChannelMode::OrderedReliable(default()),
-            ..default()
+        register_protocol(&mut Registrar {
+            app: Some(app),
+            registrations: Vec::new(),
         });
     }
 }
 
+/// Registers the protocol with an app, or, without one, only lists what would be registered, so
+/// [`PROTOCOL_ID`] comes from the same code that builds the protocol.
+struct Registrar<'a> {
+    app: Option<&'a mut App>,
+    registrations: Vec<String>,
+}
+
+impl Registrar<'_> {
+    fn channel<C: Channel>(&mut self, mode: ChannelMode) {
+        self.registrations
+            .push(format!("channel {} {mode:?}", type_name::<C>()));
+        if let Some(app) = self.app.as_deref_mut() {
+            app.add_channel::<C>(ChannelSettings { mode, ..default() });
+        }
+    }
+
+    fn plugin<P: Plugin>(&mut self, plugin: P) {
+        self.registrations
+            .push(format!("plugin {}", type_name::<P>()));
+        if let Some(app) = self.app.as_deref_mut() {
+            app.add_plugins(plugin);
+        }
+    }
+
+    fn message<M: Message + Serialize + DeserializeOwned>(&mut self, direction: ChannelDirection) {
+        self.registrations
+            .push(format!("message {} {direction:?}", type_name::<M>()));
+        if let Some(app) = self.app.as_deref_mut() {
+            app.register_message::<M>(direction);
+        }
+    }
+
+    /// Like [`Self::message`], for messages holding entities that have to be mapped on arrival.
+    fn message_with_entities<M>(&mut self, direction: ChannelDirection)
+    where
+        M: Message + Serialize + DeserializeOwned + Clone + MapEntities,
+    {
+        self.registrations
+            .push(format!("message {} {direction:?}", type_name::<M>()));
+        if let Some(app) = self.app.as_deref_mut() {
+            app.register_message::<M>(direction).add_map_entities();
+        }
+    }
+
+    /// Replicates `C` from the server, and copies it to predicted and interpolated entities with
+    /// `mode`.
+    fn component<C>(&mut self, mode: ComponentSyncMode)
+    where
+        C: SyncComponent + Serialize + DeserializeOwned + GetTypeRegistration,
+    {
+        self.registrations
+            .push(format!("component {} {mode:?}", type_name::<C>()));
+        if let Some(app) = self.app.as_deref_mut() {
+            app.register_type::<C>()
+                .register_component::<C>(ChannelDirection::ServerToClient)
+                .add_prediction(mode)
+                .add_interpolation(mode);
+        }
+    }
+
+    /// Like [`Self::component`], interpolating linearly between updates.
+    fn linear_component<C>(&mut self, mode: ComponentSyncMode)
+    where
+        C: SyncComponent + Serialize + DeserializeOwned + GetTypeRegistration + Linear,
+    {
+        self.component::<C>(mode);
+        if let Some(app) = self.app.as_deref_mut() {
+            app.add_linear_interpolation_fn::<C>();
+        }
+    }
+
+    /// Replicates `C` from the server without copying it to predicted or interpolated entities.
+    fn unsynced_component<C>(&mut self)
+    where
+        C: SyncComponent + Serialize + DeserializeOwned + GetTypeRegistration,
+    {
+        self.registrations
+            .push(format!("component {}", type_name::<C>()));
+        if let Some(app) = self.app.as_deref_mut() {
+            app.register_type::<C>()
+                .register_component::<C>(ChannelDirection::ServerToClient);
+        }
+    }
+}
+
+fn register_protocol(registrar: &mut Registrar) {
+    // Registered before anything else so its ids are the same in every build
+    registrar.channel::<HandshakeChannel>(ChannelMode::OrderedReliable(default()));
+    registrar.message::<BuildInfo>(ChannelDirection::ClientToServer);
+    registrar.message::<HandshakeReply>(ChannelDirection::ServerToClient);
+
+    registrar.plugin(LeafwingInputPlugin::<PlayerActions>::default());
+
+    registrar.message_with_entities::<ClientMessage>(ChannelDirection::ClientToServer);
+    registrar.message::<ServerMessage>(ChannelDirection::ServerToClient);
+
+    registrar.component::<PlayerId>(ComponentSyncMode::Once);
+    registrar.linear_component::<PlayerPosition>(ComponentSyncMode::Full);
+    registrar.component::<PlayerColor>(ComponentSyncMode::Simple);
+    registrar.component::<PlayerName>(ComponentSyncMode::Simple);
+    registrar.component::<Team>(ComponentSyncMode::Simple);
+    registrar.component::<Ping>(ComponentSyncMode::Simple);
+    registrar.linear_component::<MinionPosition>(ComponentSyncMode::Full);
+    registrar.component::<MinionTarget>(ComponentSyncMode::Simple);
+    registrar.component::<UnitKind>(ComponentSyncMode::Once);
+    registrar.component::<Health>(ComponentSyncMode::Simple);
+    registrar.component::<OwnedBy>(ComponentSyncMode::Once);
+    registrar.component::<Item>(ComponentSyncMode::Once);
+    registrar.component::<ItemPos>(ComponentSyncMode::Once);
+    registrar.unsynced_component::<Scoreboard>();
+    registrar.unsynced_component::<Apples>();
+
+    registrar.channel::<Channel1>(ChannelMode::OrderedReliable(default()));
+    registrar.channel::<PingChannel>(ChannelMode::UnorderedReliable(default()));
+    registrar.channel::<StatsChannel>(ChannelMode::UnorderedUnreliable);
+}
+
 pub fn shared_config(mode: Mode) -> SharedConfig {
     SharedConfig {
         server_replication_send_interval: Duration::from_millis(40),
@@ -0,0 +1,267 @@
+use std::collections::VecDeque;
+
+use bevy::prelude::*;
+use bevy_egui::EguiContexts;
+use bevy_egui::egui::{Align2, Color32, ScrollArea, TextEdit};
+use leafwing_input_manager::prelude::*;
+use lightyear::prelude::*;
+
+use super::player::{PlayerActions, PlayerId, PlayerName, Team};
+use super::rate_limit::RateLimiter;
+use super::{Channel1, ClientMessage, ServerMessage, Spectators};
+use crate::networking::IsClient;
+use crate::spectator::SpectatorFeed;
+
+pub struct ChatPlugin;
+
+impl Plugin for ChatPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_event::<SystemChat>()
+            .init_resource::<ChatFilters>()
+            .init_resource::<ChatRateLimiter>()
+            .init_resource::<ChatLog>()
+            .add_systems(
+                Update,
+                (
+                    (relay_chat, send_system_chat).run_if(is_server),
+                    (receive_chat, show_chat).chain().run_if(in_state(IsClient)),
+                ),
+            )
+            .add_systems(OnExit(IsClient), clear_chat_log);
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ChatScope {
+    All,
+    Team,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct ChatLine {
+    /// `None` for messages from the server itself.
+    pub sender: Option<String>,
+    /// Whether the sender is spectating rather than playing.
+    pub spectator: bool,
+    pub scope: ChatScope,
+    pub text: String,
+}
+
+/// Send on the server to announce something to every player through the chat.
+#[derive(Event, Clone, Debug)]
+pub struct SystemChat(pub String);
+
+/// Hook run on the text of every chat message before it is relayed. Returning `None` drops the
+/// message.
+pub type ChatFilter = fn(String) -> Option<String>;
+
+/// Filters applied in order to every chat message, e.g. a profanity filter.
+#[derive(Resource, Default)]
+pub struct ChatFilters(pub Vec<ChatFilter>);
+
+pub const MAX_CHAT_LEN: usize = 200;
+
+#[derive(Resource, Deref, DerefMut)]
+struct ChatRateLimiter(RateLimiter);
+
+impl Default for ChatRateLimiter {
+    fn default() -> Self {
+        Self(RateLimiter::new(5, 10.0))
+    }
+}
+
+const CHAT_HISTORY: usize = 100;
+
+#[derive(Resource, Default)]
+struct ChatLog(VecDeque<ChatLine>);
+
+#[allow(clippy::too_many_arguments)]
+fn relay_chat(
+    mut message_reader: EventReader<ServerMessageEvent<ClientMessage>>,
+    mut connection_manager: ResMut<ServerConnectionManager>,
+    mut rate_limiter: ResMut<ChatRateLimiter>,
+    filters: Res<ChatFilters>,
+    players: Query<(&PlayerId, &PlayerName, &Team), With<Replicating>>,
+    spectators: Res<Spectators>,
+    mut spectator_feed: SpectatorFeed,
+    time: Res<Time>,
+) {
+    for event in message_reader.read() {
+        let ClientMessage::Chat(text, scope) = &event.message else {
+            continue;
+        };
+        let client_id = event.from();
+        // Spectators have no team, their team chat goes to the other spectators
+        let (name, team) = match players.iter().find(|(id, ..)| id.0 == client_id) {
+            Some((_, name, &team)) => (name, Some(team)),
+            None => match spectators.0.get(&client_id) {
+                Some(name) => (name, None),
+                None => continue,
+            },
+        };
+
+        if !rate_limiter.allow(client_id, time.elapsed_secs_f64()) {
+            let line = ChatLine {
+                sender: None,
+                spectator: false,
+                scope: ChatScope::All,
+                text: "You are sending messages too quickly".into(),
+            };
+            connection_manager
+                .send_message_to_target::<Channel1, _>(
+                    &ServerMessage::Chat(line),
+                    NetworkTarget::Single(client_id),
+                )
+                .unwrap();
+            continue;
+        }
+
+        let text = text.trim().chars().take(MAX_CHAT_LEN).collect::<String>();
+        let Some(text) = filters
+            .0
+            .iter()
+            .try_fold(text, |text, filter| filter(text))
+            .filter(|text| !text.is_empty())
+        else {
+            continue;
+        };
+
+        // What players say reaches spectators late, what spectators say doesn't need to
+        let target = match (scope, team) {
+            (ChatScope::All, Some(_)) => spectator_feed.players(),
+            (ChatScope::All, None) => NetworkTarget::All,
+            (ChatScope::Team, Some(team)) => NetworkTarget::Only(
+                players
+                    .iter()
+                    .filter(|&(.., &other)| other == team)
+                    .map(|(id, ..)| id.0)
+                    .collect(),
+            ),
+            (ChatScope::Team, None) => NetworkTarget::Only(spectators.0.keys().copied().collect()),
+        };
+        let line = ChatLine {
+            sender: Some(name.0.clone()),
+            spectator: team.is_none(),
+            scope: *scope,
+            text,
+        };
+        if *scope == ChatScope::All && team.is_some() {
+            spectator_feed.hold_back(ServerMessage::Chat(line.clone()));
+        }
+        connection_manager
+            .send_message_to_target::<Channel1, _>(&ServerMessage::Chat(line), target)
+            .unwrap();
+    }
+}
+
+fn send_system_chat(
+    mut system_chat: EventReader<SystemChat>,
+    mut connection_manager: ResMut<ServerConnectionManager>,
+    mut spectator_feed: SpectatorFeed,
+) {
+    for SystemChat(text) in system_chat.read() {
+        let line = ChatLine {
+            sender: None,
+            spectator: false,
+            scope: ChatScope::All,
+            text: text.clone(),
+        };
+        spectator_feed.hold_back(ServerMessage::Chat(line.clone()));
+        connection_manager
+            .send_message_to_target::<Channel1, _>(
+                &ServerMessage::Chat(line),
+                spectator_feed.players(),
+            )
+            .unwrap();
+    }
+}
+
+fn receive_chat(
+    mut message_reader: EventReader<ClientMessageEvent<ServerMessage>>,
+    mut log: ResMut<ChatLog>,
+) {
+    for event in message_reader.read() {
+        let ServerMessage::Chat(line) = &event.message else {
+            continue;
+        };
+        log.0.push_back(line.clone());
+        if log.0.len() > CHAT_HISTORY {
+            log.0.pop_front();
+        }
+    }
+}
+
+fn clear_chat_log(mut log: ResMut<ChatLog>) {
+    log.0.clear();
+}
+
+fn show_chat(
+    mut draft: Local<String>,
+    mut scope: Local<Option<ChatScope>>,
+    mut contexts: EguiContexts,
+    log: Res<ChatLog>,
+    keypress: Res<ButtonInput<KeyCode>>,
+    mut action_states: Query<&mut ActionState<PlayerActions>, With<InputMap<PlayerActions>>>,
+    mut connection_manager: ResMut<ClientConnectionManager>,
+) {
+    let scope = scope.get_or_insert(ChatScope::All);
+    let ctx = contexts.ctx_mut();
+
+    bevy_egui::egui::Window::new("Chat")
+        .anchor(Align2::LEFT_BOTTOM, (0.0, -120.0))
+        .default_size((320.0, 160.0))
+        .resizable(false)
+        .collapsible(true)
+        .show(ctx, |ui| {
+            ScrollArea::vertical()
+                .max_height(120.0)
+                .stick_to_bottom(true)
+                .show(ui, |ui| {
+                    for line in &log.0 {
+                        match &line.sender {
+                            Some(sender) => {
+                                let prefix = match (line.scope, line.spectator) {
+                                    (ChatScope::All, false) => "",
+                                    (ChatScope::Team, false) => "[Team] ",
+                                    (ChatScope::All, true) => "[Spectator] ",
+                                    (ChatScope::Team, true) => "[Spectators] ",
+                                };
+                                ui.label(format!("{prefix}{sender}: {}", line.text));
+                            }
+                            None => {
+                                ui.colored_label(Color32::YELLOW, &line.text);
+                            }
+                        }
+                    }
+                });
+
+            ui.horizontal(|ui| {
+                ui.selectable_value(scope, ChatScope::All, "All");
+                ui.selectable_value(scope, ChatScope::Team, "Team");
+                let input = ui.add(TextEdit::singleline(&mut *draft).char_limit(MAX_CHAT_LEN));
+
+                if input.lost_focus() && keypress.just_pressed(KeyCode::Enter) {
+                    if !draft.trim().is_empty() {
+                        connection_manager
+                            .send_message::<Channel1, _>(&ClientMessage::Chat(
+                                std::mem::take(&mut *draft),
+                                *scope,
+                            ))
+                            .unwrap();
+                    }
+                } else if !input.has_focus() && keypress.just_pressed(KeyCode::Enter) {
+                    input.request_focus();
+                }
+            });
+        });
+
+    // Don't move the player around while typing
+    let typing = ctx.wants_keyboard_input();
+    for mut action_state in &mut action_states {
+        if typing {
+            action_state.disable();
+        } else {
+            action_state.enable();
+        }
+    }
+}
@@ -0,0 +1,163 @@
+use std::fmt;
+
+use bevy::prelude::*;
+use bevy::utils::HashSet;
+use lightyear::prelude::client::ClientCommands;
+use lightyear::prelude::*;
+
+use super::{Dismissed, HandshakeChannel, PROTOCOL_ID};
+use crate::networking::{DisconnectReason, IsClient, NetworkState};
+
+/// Checks that the client and server were built from compatible versions before anything else
+/// happens, and sends the client back to the menu with a reason if they weren't.
+///
+/// Netcode already drops connections whose protocol id doesn't match, but silently, and Steam
+/// connections don't check it at all. The messages here are registered before everything else so
+/// they keep the same ids, and therefore still get through, between incompatible builds.
+pub struct HandshakePlugin;
+
+impl Plugin for HandshakePlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<Handshaken>().add_systems(
+            Update,
+            (
+                answer_handshakes.run_if(is_server),
+                (send_handshake, receive_handshake_reply).run_if(in_state(IsClient)),
+            ),
+        );
+    }
+}
+
+/// Clients whose build the server accepted, the only ones it lets join. Kept on the server.
+#[derive(Resource, Default, Debug)]
+pub struct Handshaken(pub HashSet<ClientId>);
+
+/// Which build a peer runs. The protocol id covers everything that has to match, the version is
+/// what gets shown to players.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct BuildInfo {
+    pub version: String,
+    #[serde(with = "protocol_id")]
+    pub protocol_id: u64,
+}
+
+/// TOML integers are signed, so text formats get the protocol id as hex instead, which also keeps
+/// ids above `i64::MAX` in server listings. Binary formats, like the handshake itself, still use
+/// the plain number.
+mod protocol_id {
+    use serde::de::Error;
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    pub fn serialize<S: Serializer>(id: &u64, serializer: S) -> Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() {
+            format!("{id:016x}").serialize(serializer)
+        } else {
+            id.serialize(serializer)
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u64, D::Error> {
+        if deserializer.is_human_readable() {
+            let hex = String::deserialize(deserializer)?;
+            u64::from_str_radix(&hex, 16).map_err(D::Error::custom)
+        } else {
+            u64::deserialize(deserializer)
+        }
+    }
+}
+
+impl BuildInfo {
+    pub fn local() -> Self {
+        Self {
+            version: env!("CARGO_PKG_VERSION").into(),
+            protocol_id: *PROTOCOL_ID,
+        }
+    }
+
+    pub fn is_compatible(&self, other: &BuildInfo) -> bool {
+        self.protocol_id == other.protocol_id
+    }
+
+    /// Explains to a player why they can't connect to a server running `server`.
+    pub fn mismatch_reason(server: &BuildInfo) -> String {
+        format!(
+            "Server runs version {server}, you run {}",
+            BuildInfo::local()
+        )
+    }
+}
+
+impl fmt::Display for BuildInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} (protocol {})", self.version, self.protocol_id)
+    }
+}
+
+/// Sent by the server in reply to a client's [`BuildInfo`].
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct HandshakeReply {
+    pub accepted: bool,
+    pub server: BuildInfo,
+}
+
+fn answer_handshakes(
+    mut message_reader: EventReader<ServerMessageEvent<BuildInfo>>,
+    mut connection_manager: ResMut<ServerConnectionManager>,
+    mut handshaken: ResMut<Handshaken>,
+    mut dismissed: ResMut<Dismissed>,
+    time: Res<Time>,
+) {
+    let local = BuildInfo::local();
+    for event in message_reader.read() {
+        let client_id = event.from();
+        let accepted = local.is_compatible(&event.message);
+        if accepted {
+            debug!(%client_id, version = %event.message, "Handshake accepted");
+            handshaken.0.insert(client_id);
+        } else {
+            warn!(%client_id, version = %event.message, "Rejected client running an incompatible build");
+            dismissed.0.insert(client_id, time.elapsed_secs_f64());
+        }
+
+        connection_manager
+            .send_message_to_target::<HandshakeChannel, _>(
+                &HandshakeReply {
+                    accepted,
+                    server: local.clone(),
+                },
+                NetworkTarget::Single(client_id),
+            )
+            .unwrap();
+    }
+}
+
+fn send_handshake(
+    mut connect_events: EventReader<ClientConnectEvent>,
+    mut connection_manager: ResMut<ClientConnectionManager>,
+) {
+    for event in connect_events.read() {
+        info!(client_id = %event.client_id(), "Connected to server");
+        connection_manager
+            .send_message::<HandshakeChannel, _>(&BuildInfo::local())
+            .unwrap();
+    }
+}
+
+fn receive_handshake_reply(
+    mut commands: Commands,
+    mut message_reader: EventReader<ClientMessageEvent<HandshakeReply>>,
+    mut disconnect_reason: ResMut<DisconnectReason>,
+    mut next_network_state: ResMut<NextState<NetworkState>>,
+) {
+    for event in message_reader.read() {
+        if event.message.accepted {
+            continue;
+        }
+
+        let reason = BuildInfo::mismatch_reason(&event.message.server);
+        warn!("{reason}");
+        disconnect_reason.0 = Some(reason);
+        commands.disconnect_client();
+        next_network_state.set(NetworkState::Disconnected);
+    }
+}
@@ -2,9 +2,12 @@ use std::ops::Add;
 use std::ops::Mul;
 
 use bevy::prelude::*;
+use bevy::utils::HashMap;
 use lightyear::prelude::*;
 
-use super::{InputHandling, PlayerColor, Relevant};
+use super::player::{PlayerId, Team};
+use super::status::Health;
+use super::{InputHandling, OwnedBy, PlayerColor, Relevant};
 
 pub struct MinionPlugin;
 
@@ -12,18 +15,97 @@ impl Plugin for MinionPlugin {
     fn build(&self, app: &mut App) {
         app.add_systems(
             FixedUpdate,
-            (show_minions, (minion_movement, move_minions).chain())
+            (
+                show_minions,
+                (minion_movement, move_minions).chain(),
+                fight.run_if(is_server),
+            )
                 .chain()
                 .after(InputHandling),
         );
-        app.add_observer(unselect_minions);
-        app.add_observer(show_selected_minions);
     }
 }
 
+/// How close an enemy has to be to get hit.
+const ATTACK_RANGE: f32 = 1.0;
+
 #[derive(Debug, Component)]
 pub struct Selected;
 
+#[derive(
+    Component,
+    Reflect,
+    Serialize,
+    Deserialize,
+    Clone,
+    Copy,
+    Debug,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Hash,
+)]
+pub enum UnitKind {
+    Minion,
+    Barracks,
+}
+
+impl UnitKind {
+    pub fn name(self) -> &'static str {
+        match self {
+            UnitKind::Minion => "Minion",
+            UnitKind::Barracks => "Barracks",
+        }
+    }
+
+    pub fn max_health(self) -> f32 {
+        match self {
+            UnitKind::Minion => 10.0,
+            UnitKind::Barracks => 50.0,
+        }
+    }
+
+    /// The unit kinds a unit of this kind can train. Minions build barracks, which train minions.
+    pub fn produces(self) -> &'static [UnitKind] {
+        match self {
+            UnitKind::Minion => &[UnitKind::Barracks],
+            UnitKind::Barracks => &[UnitKind::Minion],
+        }
+    }
+
+    /// Apples it takes to train one.
+    pub fn cost(self) -> u64 {
+        match self {
+            UnitKind::Minion => 2,
+            UnitKind::Barracks => 5,
+        }
+    }
+
+    /// Buildings stay where they were put, and don't gather.
+    pub fn is_building(self) -> bool {
+        match self {
+            UnitKind::Minion => false,
+            UnitKind::Barracks => true,
+        }
+    }
+
+    /// Damage a second it deals to an enemy in range.
+    pub fn attack(self) -> f32 {
+        match self {
+            UnitKind::Minion => 2.0,
+            UnitKind::Barracks => 0.0,
+        }
+    }
+
+    fn size(self) -> f32 {
+        match self {
+            UnitKind::Minion => 0.5,
+            UnitKind::Barracks => 1.2,
+        }
+    }
+}
+
 #[derive(
     Component, Reflect, Deref, DerefMut, Serialize, Deserialize, Clone, Copy, Debug, PartialEq,
 )]
@@ -66,6 +148,52 @@ pub fn minion_movement(
     }
 }
 
+/// Units hit the closest enemy unit in range and die when they run out of health. Runs on the
+/// server only, clients see the health it leaves.
+fn fight(
+    mut commands: Commands,
+    mut units: Query<
+        (Entity, &MinionPosition, &UnitKind, &OwnedBy, &mut Health),
+        With<Replicating>,
+    >,
+    players: Query<(&PlayerId, &Team), With<Replicating>>,
+    time: Res<Time<Fixed>>,
+) {
+    let teams = players
+        .iter()
+        .map(|(id, &team)| (id.0, team))
+        .collect::<HashMap<_, _>>();
+    let mut hits = Vec::new();
+    for (_, pos, kind, owner, _) in &units {
+        let Some(team) = teams.get(&owner.0) else {
+            continue;
+        };
+        if kind.attack() <= 0.0 {
+            continue;
+        }
+        let target = units
+            .iter()
+            .filter(|(.., other, _)| teams.get(&other.0).is_some_and(|other| other != team))
+            .map(|(entity, other_pos, ..)| (entity, pos.distance(other_pos.0)))
+            .filter(|&(_, distance)| distance <= ATTACK_RANGE)
+            .min_by(|(_, a), (_, b)| a.total_cmp(b));
+        if let Some((target, _)) = target {
+            hits.push((target, kind.attack() * time.delta_secs()));
+        }
+    }
+
+    for (target, damage) in hits {
+        if let Ok((.., mut health)) = units.get_mut(target) {
+            health.current -= damage;
+        }
+    }
+    for (entity, .., health) in &units {
+        if health.current <= 0.0 {
+            commands.entity(entity).despawn_recursive();
+        }
+    }
+}
+
 fn move_minions(mut minions: Query<(&MinionPosition, &mut Transform), Relevant>) {
     for (pos, mut tf) in &mut minions {
         tf.translation = pos.extend(0.0);
@@ -74,34 +202,16 @@ fn move_minions(mut minions: Query<(&MinionPosition, &mut Transform), Relevant>)
 
 fn show_minions(
     mut commands: Commands,
-    players: Query<(Entity, &MinionPosition, &PlayerColor), (Without<Sprite>, Relevant)>,
+    players: Query<(Entity, &MinionPosition, &UnitKind, &PlayerColor), (Without<Sprite>, Relevant)>,
 ) {
-    for (player, pos, &PlayerColor(color)) in &players {
+    for (player, pos, kind, &PlayerColor(color)) in &players {
         commands.entity(player).insert((
             Sprite::from_color(color, Vec2::splat(1.0)),
             Transform {
                 translation: pos.extend(0.0),
-                scale: Vec3::splat(0.5),
+                scale: Vec3::splat(kind.size()),
                 ..default()
             },
         ));
     }
 }
-
-fn show_selected_minions(trigger: Trigger<OnAdd, Selected>, mut commands: Commands) {
-    commands.entity(trigger.entity()).with_children(|commands| {
-        commands.spawn((
-            Sprite::from_color(Color::srgb(1.0, 0.0, 1.0), Vec2::splat(1.1)),
-            Transform::from_xyz(0.0, 0.0, -0.1),
-        ));
-    });
-}
-
-fn unselect_minions(trigger: Trigger<OnRemove, Selected>, mut commands: Commands) {
-    let entity = trigger.entity();
-    commands.queue(move |world: &mut World| {
-        if let Ok(mut entity) = world.get_entity_mut(entity) {
-            entity.despawn_descendants();
-        }
-    });
-}
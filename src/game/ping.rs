@@ -0,0 +1,333 @@
+use bevy::prelude::*;
+use bevy::render::view::RenderLayers;
+use bevy::utils::HashMap;
+use bevy::window::PrimaryWindow;
+use bevy_egui::EguiContexts;
+use bevy_egui::egui::{Align2, Area, Color32, Id, RichText};
+use lightyear::prelude::*;
+
+use super::minion::MinionPosition;
+use super::player::{PlayerId, Team};
+use super::rate_limit::RateLimiter;
+use super::resource::{Item, ItemPos};
+use super::status::Health;
+use super::{ClientMessage, OwnedBy, PingChannel, ServerMessage};
+use crate::minimap::{MINIMAP_LAYER, MinimapCamera, minimap_to_world};
+use crate::networking::IsClient;
+use crate::spectator::SpectatorFeed;
+
+pub struct PingPlugin;
+
+impl Plugin for PingPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<PingRateLimiter>()
+            .init_resource::<ActiveAlert>()
+            .add_systems(Startup, load_ping_assets)
+            .add_systems(
+                Update,
+                (
+                    (relay_pings, detect_attacks, detect_depleted_items).run_if(is_server),
+                    (
+                        send_map_pings,
+                        receive_pings,
+                        animate_ping_markers,
+                        show_alert,
+                    )
+                        .run_if(in_state(IsClient)),
+                ),
+            );
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Alert {
+    UnderAttack,
+    ResourceDepleted,
+}
+
+impl Alert {
+    fn text(self) -> &'static str {
+        match self {
+            Alert::UnderAttack => "Your units are under attack",
+            Alert::ResourceDepleted => "A resource has been depleted",
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum PingKind {
+    /// Placed by a teammate.
+    Player(ClientId),
+    Alert(Alert),
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct MapPing {
+    pub pos: Vec2,
+    pub kind: PingKind,
+}
+
+#[derive(Resource, Deref, DerefMut)]
+struct PingRateLimiter(RateLimiter);
+
+impl Default for PingRateLimiter {
+    fn default() -> Self {
+        Self(RateLimiter::new(3, 5.0))
+    }
+}
+
+/// Minimum time between two "under attack" alerts for the same player, in seconds.
+const ALERT_COOLDOWN: f64 = 10.0;
+const PING_DURATION: f32 = 3.0;
+
+#[derive(Resource)]
+struct PingAssets {
+    ring: Handle<Mesh>,
+    player: Handle<ColorMaterial>,
+    danger: Handle<ColorMaterial>,
+    info: Handle<ColorMaterial>,
+}
+
+impl PingAssets {
+    fn material(&self, kind: PingKind) -> Handle<ColorMaterial> {
+        match kind {
+            PingKind::Player(_) => self.player.clone(),
+            PingKind::Alert(Alert::UnderAttack) => self.danger.clone(),
+            PingKind::Alert(Alert::ResourceDepleted) => self.info.clone(),
+        }
+    }
+}
+
+#[derive(Component)]
+struct PingMarker(Timer);
+
+/// The latest alert, shown at the top of the screen until its timer runs out.
+#[derive(Resource, Default)]
+struct ActiveAlert(Option<(Alert, Timer)>);
+
+fn load_ping_assets(
+    mut commands: Commands,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<ColorMaterial>>,
+) {
+    commands.insert_resource(PingAssets {
+        ring: meshes.add(Annulus::new(0.4, 0.5)),
+        player: materials.add(Color::srgb(0.2, 0.9, 1.0)),
+        danger: materials.add(Color::srgb(1.0, 0.1, 0.1)),
+        info: materials.add(Color::srgb(1.0, 0.6, 0.1)),
+    });
+}
+
+fn relay_pings(
+    mut message_reader: EventReader<ServerMessageEvent<ClientMessage>>,
+    mut connection_manager: ResMut<ServerConnectionManager>,
+    mut rate_limiter: ResMut<PingRateLimiter>,
+    players: Query<(&PlayerId, &Team), With<Replicating>>,
+    mut spectator_feed: SpectatorFeed,
+    time: Res<Time>,
+) {
+    for event in message_reader.read() {
+        let &ClientMessage::Ping(pos) = &event.message else {
+            continue;
+        };
+        let client_id = event.from();
+        let Some((_, &team)) = players.iter().find(|(id, _)| id.0 == client_id) else {
+            continue;
+        };
+        if !rate_limiter.allow(client_id, time.elapsed_secs_f64()) {
+            continue;
+        }
+
+        let teammates = players
+            .iter()
+            .filter(|&(_, &other)| other == team)
+            .map(|(id, _)| id.0)
+            .collect();
+        let ping = MapPing {
+            pos,
+            kind: PingKind::Player(client_id),
+        };
+        // Spectators see both teams' pings
+        spectator_feed.hold_back(ServerMessage::Ping(ping));
+        connection_manager
+            .send_message_to_target::<PingChannel, _>(
+                &ServerMessage::Ping(ping),
+                NetworkTarget::Only(teammates),
+            )
+            .unwrap();
+    }
+}
+
+fn detect_attacks(
+    mut last_health: Local<HashMap<Entity, f32>>,
+    mut last_alert: Local<HashMap<ClientId, f64>>,
+    units: Query<(Entity, &Health, &OwnedBy, &MinionPosition), Changed<Health>>,
+    mut removed: RemovedComponents<Health>,
+    mut connection_manager: ResMut<ServerConnectionManager>,
+    time: Res<Time>,
+) {
+    for entity in removed.read() {
+        last_health.remove(&entity);
+    }
+
+    let now = time.elapsed_secs_f64();
+    for (entity, health, owner, pos) in &units {
+        let previous = last_health.insert(entity, health.current);
+        if previous.is_none_or(|previous| health.current >= previous) {
+            continue;
+        }
+        if last_alert
+            .get(&owner.0)
+            .is_some_and(|&at| now - at < ALERT_COOLDOWN)
+        {
+            continue;
+        }
+        last_alert.insert(owner.0, now);
+
+        let ping = MapPing {
+            pos: pos.0,
+            kind: PingKind::Alert(Alert::UnderAttack),
+        };
+        connection_manager
+            .send_message_to_target::<PingChannel, _>(
+                &ServerMessage::Ping(ping),
+                NetworkTarget::Single(owner.0),
+            )
+            .unwrap();
+    }
+}
+
+fn detect_depleted_items(
+    mut positions: Local<HashMap<Entity, Vec2>>,
+    items: Query<(Entity, &ItemPos), Added<ItemPos>>,
+    mut removed: RemovedComponents<Item>,
+    mut connection_manager: ResMut<ServerConnectionManager>,
+    mut spectator_feed: SpectatorFeed,
+) {
+    for (entity, pos) in &items {
+        positions.insert(entity, pos.0);
+    }
+
+    for entity in removed.read() {
+        let Some(pos) = positions.remove(&entity) else {
+            continue;
+        };
+        let ping = MapPing {
+            pos,
+            kind: PingKind::Alert(Alert::ResourceDepleted),
+        };
+        spectator_feed.hold_back(ServerMessage::Ping(ping));
+        connection_manager
+            .send_message_to_target::<PingChannel, _>(
+                &ServerMessage::Ping(ping),
+                spectator_feed.players(),
+            )
+            .unwrap();
+    }
+}
+
+fn send_map_pings(
+    keypress: Res<ButtonInput<KeyCode>>,
+    mouse: Res<ButtonInput<MouseButton>>,
+    windows: Query<&Window, With<PrimaryWindow>>,
+    camera: Query<(&Camera, &GlobalTransform), Without<MinimapCamera>>,
+    minimap: Query<(&Camera, &GlobalTransform), With<MinimapCamera>>,
+    mut contexts: EguiContexts,
+    mut connection_manager: ResMut<ClientConnectionManager>,
+) {
+    if !keypress.any_pressed([KeyCode::AltLeft, KeyCode::AltRight])
+        || !mouse.just_pressed(MouseButton::Left)
+        || contexts.ctx_mut().is_pointer_over_area()
+    {
+        return;
+    }
+    let Some(cursor) = windows.get_single().ok().and_then(|w| w.cursor_position()) else {
+        return;
+    };
+
+    let on_minimap = minimap
+        .iter()
+        .find_map(|(camera, tf)| minimap_to_world(camera, tf, cursor));
+    let Some(pos) = on_minimap.or_else(|| {
+        let (camera, tf) = camera.get_single().ok()?;
+        camera.viewport_to_world_2d(tf, cursor).ok()
+    }) else {
+        return;
+    };
+
+    connection_manager
+        .send_message::<PingChannel, _>(&ClientMessage::Ping(pos))
+        .unwrap();
+}
+
+fn receive_pings(
+    mut commands: Commands,
+    mut message_reader: EventReader<ClientMessageEvent<ServerMessage>>,
+    mut active_alert: ResMut<ActiveAlert>,
+    assets: Res<PingAssets>,
+) {
+    for event in message_reader.read() {
+        let ServerMessage::Ping(ping) = event.message else {
+            continue;
+        };
+        if let PingKind::Alert(alert) = ping.kind {
+            active_alert.0 = Some((alert, Timer::from_seconds(PING_DURATION, TimerMode::Once)));
+        }
+
+        let material = assets.material(ping.kind);
+        commands
+            .spawn((
+                Name::new("Ping"),
+                PingMarker(Timer::from_seconds(PING_DURATION, TimerMode::Once)),
+                Mesh2d(assets.ring.clone()),
+                MeshMaterial2d(material.clone()),
+                Transform::from_translation(ping.pos.extend(5.0)),
+            ))
+            .with_children(|parent| {
+                // A much larger ring that only shows up on the minimap, so pings are easy to spot
+                parent.spawn((
+                    Mesh2d(assets.ring.clone()),
+                    MeshMaterial2d(material),
+                    Transform::from_scale(Vec3::splat(6.0)),
+                    RenderLayers::layer(MINIMAP_LAYER),
+                ));
+            });
+    }
+}
+
+fn animate_ping_markers(
+    mut commands: Commands,
+    mut markers: Query<(Entity, &mut PingMarker, &mut Transform)>,
+    time: Res<Time>,
+) {
+    for (entity, mut marker, mut transform) in &mut markers {
+        marker.0.tick(time.delta());
+        if marker.0.finished() {
+            commands.entity(entity).despawn_recursive();
+            continue;
+        }
+
+        let pulse = 1.0 + 0.3 * (marker.0.elapsed_secs() * 10.0).sin();
+        transform.scale = Vec3::splat(pulse);
+    }
+}
+
+fn show_alert(mut contexts: EguiContexts, mut active_alert: ResMut<ActiveAlert>, time: Res<Time>) {
+    let Some((alert, timer)) = &mut active_alert.0 else {
+        return;
+    };
+    timer.tick(time.delta());
+    if timer.finished() {
+        active_alert.0 = None;
+        return;
+    }
+
+    let text = RichText::new(alert.text())
+        .heading()
+        .color(Color32::from_rgb(255, 80, 80));
+    Area::new(Id::new("alert"))
+        .anchor(Align2::CENTER_TOP, (0.0, 40.0))
+        .show(contexts.ctx_mut(), |ui| {
+            ui.label(text);
+        });
+}
@@ -2,12 +2,15 @@ use std::ops::Add;
 use std::ops::Mul;
 
 use bevy::prelude::*;
+use leafwing_input_manager::prelude::*;
 use lightyear::prelude::*;
-use lightyear::shared::events::components::InputEvent;
 
 use crate::game::OwnedBy;
 use crate::game::minion::MinionPosition;
 use crate::game::minion::MinionTarget;
+use crate::game::minion::UnitKind;
+use crate::game::resource::Apples;
+use crate::game::status::Health;
 
 use self::client::ClientConnection;
 use self::client::NetClient;
@@ -48,82 +51,97 @@ fn show_players(
     }
 }
 
+#[allow(clippy::too_many_arguments)]
 pub fn shared_movement_behaviour(
-    input: &Inputs,
+    action_state: &ActionState<PlayerActions>,
     commands: &mut Commands,
     position: &mut PlayerPosition,
+    color: PlayerColor,
+    apples: &mut u64,
     time: &Time<Fixed>,
+    tick: Tick,
     client_id: ClientId,
     spawn_bundle: impl Bundle,
 ) {
-    match input {
-        Inputs::Direction(dir) => {
-            const MOVE_SPEED: f32 = 10.0;
-            let move_speed = MOVE_SPEED * time.delta_secs();
-            if dir.up {
-                position.y += move_speed;
-            }
-            if dir.down {
-                position.y -= move_speed;
-            }
-            if dir.left {
-                position.x -= move_speed;
-            }
-            if dir.right {
-                position.x += move_speed;
-            }
+    const MOVE_SPEED: f32 = 10.0;
+    position.0 += action_state.axis_pair(&PlayerActions::Move) * MOVE_SPEED * time.delta_secs();
+
+    if action_state.just_pressed(&PlayerActions::Spawn) {
+        let cost = UnitKind::Minion.cost();
+        if *apples < cost {
+            debug!(%client_id, tick = tick.0, apples = *apples, "Not enough apples to spawn");
+            return;
         }
-        &Inputs::Spawn(pos, color) => {
-            println!("Spawn minion");
-            commands.spawn((
-                Name::new(format!("Minion - {client_id}")),
-                MinionPosition(pos),
-                MinionTarget(Vec2::new(4.0, 4.0)),
-                PlayerColor(color),
-                OwnedBy(client_id),
-                spawn_bundle,
-            ));
-        }
-        Inputs::None => (),
+        *apples -= cost;
+        debug!(%client_id, tick = tick.0, "Spawning minion");
+        commands.spawn((
+            Name::new(format!("Minion - {client_id}")),
+            MinionPosition(action_state.axis_pair(&PlayerActions::Cursor)),
+            MinionTarget(Vec2::new(4.0, 4.0)),
+            UnitKind::Minion,
+            Health::new(UnitKind::Minion.max_health()),
+            color,
+            OwnedBy(client_id),
+            spawn_bundle,
+        ));
     }
 }
 
 fn player_movement(
     mut commands: Commands,
-    mut position_query: Query<&mut PlayerPosition, With<Predicted>>,
-    mut input_reader: EventReader<InputEvent<Inputs>>,
+    mut players: Query<
+        (
+            &mut PlayerPosition,
+            &PlayerColor,
+            &ActionState<PlayerActions>,
+        ),
+        With<Predicted>,
+    >,
+    apples: Query<&Apples>,
     time: Res<Time<Fixed>>,
+    tick_manager: Res<TickManager>,
     connection: Res<ClientConnection>,
 ) {
-    for input in input_reader.read() {
-        if let Some(input) = input.input() {
-            if let Ok(mut position) = position_query.get_single_mut() {
-                shared_movement_behaviour(
-                    input,
-                    &mut commands,
-                    &mut position,
-                    &time,
-                    connection.id(),
-                    PreSpawnedPlayerObject::default(),
-                );
-            }
-        }
+    let client_id = connection.id();
+    for (mut position, &color, action_state) in &mut players {
+        // Only the server spends apples, the prediction just checks there are enough
+        let mut apples = apples
+            .get_single()
+            .ok()
+            .and_then(|apples| apples.get(&client_id).copied())
+            .unwrap_or(0);
+        shared_movement_behaviour(
+            action_state,
+            &mut commands,
+            &mut position,
+            color,
+            &mut apples,
+            &time,
+            tick_manager.tick(),
+            client_id,
+            PreSpawnedPlayerObject::default(),
+        );
     }
 }
 
-#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
-pub struct Direction {
-    pub(crate) up: bool,
-    pub(crate) down: bool,
-    pub(crate) left: bool,
-    pub(crate) right: bool,
+/// Everything a player can do in a single tick. Several actions can be active at once, and the
+/// whole `ActionState` is sent to the server every tick so rollback replays the same inputs.
+#[derive(Actionlike, Reflect, Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum PlayerActions {
+    #[actionlike(DualAxis)]
+    Move,
+    /// World position of the cursor, written by the client every tick.
+    #[actionlike(DualAxis)]
+    Cursor,
+    Spawn,
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
-pub enum Inputs {
-    Direction(Direction),
-    Spawn(Vec2, Color),
-    None,
+impl PlayerActions {
+    pub fn input_map() -> InputMap<Self> {
+        InputMap::default()
+            .with_dual_axis(PlayerActions::Move, VirtualDPad::wasd())
+            .with(PlayerActions::Spawn, KeyCode::Space)
+    }
 }
 
 #[derive(
@@ -158,3 +176,40 @@ impl Mul<f32> for &PlayerPosition {
     Component, Reflect, Deref, DerefMut, Serialize, Deserialize, Clone, Copy, Debug, PartialEq,
 )]
 pub struct PlayerColor(pub Color);
+
+#[derive(Component, Reflect, Deref, DerefMut, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct PlayerName(pub String);
+
+impl PlayerName {
+    pub const MAX_LEN: usize = 24;
+
+    /// Trims the name and cuts it down to [`Self::MAX_LEN`] characters.
+    pub fn sanitized(name: &str) -> Self {
+        Self(name.trim().chars().take(Self::MAX_LEN).collect())
+    }
+}
+
+#[derive(
+    Component,
+    Reflect,
+    Deref,
+    DerefMut,
+    Serialize,
+    Deserialize,
+    Clone,
+    Copy,
+    Debug,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Hash,
+)]
+pub struct Team(pub u8);
+
+/// Connection quality to the server in milliseconds, as measured by the server.
+#[derive(Component, Reflect, Serialize, Deserialize, Default, Clone, Copy, Debug, PartialEq)]
+pub struct Ping {
+    pub rtt: u32,
+    pub jitter: u32,
+}
@@ -0,0 +1,35 @@
+use std::collections::VecDeque;
+
+use bevy::utils::HashMap;
+use lightyear::prelude::ClientId;
+
+/// Lets each client do something at most `limit` times within a sliding window of `window`
+/// seconds.
+pub struct RateLimiter {
+    limit: usize,
+    window: f64,
+    history: HashMap<ClientId, VecDeque<f64>>,
+}
+
+impl RateLimiter {
+    pub fn new(limit: usize, window: f64) -> Self {
+        Self {
+            limit,
+            window,
+            history: HashMap::new(),
+        }
+    }
+
+    /// Records an attempt at time `now` and returns whether it is allowed.
+    pub fn allow(&mut self, client_id: ClientId, now: f64) -> bool {
+        let history = self.history.entry(client_id).or_default();
+        while history.front().is_some_and(|&at| now - at > self.window) {
+            history.pop_front();
+        }
+        if history.len() >= self.limit {
+            return false;
+        }
+        history.push_back(now);
+        true
+    }
+}
@@ -5,12 +5,13 @@ use bevy::prelude::*;
 use bevy::time::common_conditions::on_timer;
 use bevy::utils::HashMap;
 use bevy_egui::EguiContexts;
-use bevy_egui::egui::Align2;
+use bevy_egui::egui::{Align2, Color32, Grid, Sense, vec2};
 use lightyear::prelude::{ClientId, is_server};
 use serde::{Deserialize, Serialize};
 
-use super::OwnedBy;
-use super::minion::MinionPosition;
+use super::minion::{MinionPosition, UnitKind};
+use super::player::{Ping, PlayerColor, PlayerId, PlayerName, Team};
+use super::{OwnedBy, Relevant};
 
 #[expect(non_snake_case)]
 pub fn ResourcePlugin(app: &mut App) {
@@ -58,44 +59,74 @@ fn show_items(
 #[derive(Component, Reflect, Deref, DerefMut, Serialize, Deserialize, Clone, Debug, PartialEq)]
 pub struct Scoreboard(pub HashMap<ClientId, u64>);
 
-fn show_scoreboard(mut contexts: EguiContexts, scoreboard: Query<&Scoreboard>) {
+/// Apples each player has left to spend. Gathering adds to these and to the scoreboard alike,
+/// but only these go down when units are bought.
+#[derive(Component, Reflect, Deref, DerefMut, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Apples(pub HashMap<ClientId, u64>);
+
+/// What players join with, enough for a first minion to go gathering.
+pub const STARTING_APPLES: u64 = 4;
+
+fn show_scoreboard(
+    mut contexts: EguiContexts,
+    scoreboard: Query<&Scoreboard>,
+    players: Query<(&PlayerId, &PlayerName, &PlayerColor, &Team, &Ping), Relevant>,
+) {
     let Ok(scoreboard) = scoreboard.get_single() else {
         return;
     };
 
+    let mut rows = players
+        .iter()
+        .map(|(id, name, color, team, ping)| {
+            let score = scoreboard.get(&id.0).copied().unwrap_or(0);
+            (name, color, team, score, ping)
+        })
+        .collect::<Vec<_>>();
+    rows.sort_by(|a, b| b.3.cmp(&a.3).then_with(|| a.0.0.cmp(&b.0.0)));
+
     bevy_egui::egui::Window::new("Scoreboard")
         .anchor(Align2::RIGHT_TOP, (0.0, 0.0))
         .resizable([false, false])
         .collapsible(false)
         .show(contexts.ctx_mut(), |ui| {
-            ui.horizontal(|ui| {
-                ui.vertical(|ui| {
-                    ui.label("Player");
-                    for player in scoreboard.keys() {
-                        ui.label(format!("{player}"));
+            Grid::new("scoreboard")
+                .striped(true)
+                .spacing((16.0, 4.0))
+                .show(ui, |ui| {
+                    for header in ["Player", "", "Team", "Points", "Ping"] {
+                        ui.strong(header);
                     }
-                });
-                ui.separator();
-                ui.vertical(|ui| {
-                    ui.label("Points");
-                    for points in scoreboard.values() {
-                        ui.label(format!("{points}"));
+                    ui.end_row();
+
+                    for (name, color, team, score, ping) in rows {
+                        ui.label(&name.0);
+                        let [r, g, b, _] = color.to_srgba().to_u8_array();
+                        let (swatch, _) = ui.allocate_exact_size(vec2(12.0, 12.0), Sense::hover());
+                        ui.painter()
+                            .rect_filled(swatch, 2.0, Color32::from_rgb(r, g, b));
+                        ui.label(format!("{}", team.0 + 1));
+                        ui.label(format!("{score}"));
+                        ui.label(format!("{} ± {} ms", ping.rtt, ping.jitter));
+                        ui.end_row();
                     }
                 });
-            });
         });
 }
 
 fn update_scoreboard(
     mut scoreboard: Query<&mut Scoreboard>,
-    minions: Query<(&MinionPosition, &OwnedBy)>,
+    mut apples: Query<&mut Apples>,
+    minions: Query<(&MinionPosition, &UnitKind, &OwnedBy)>,
     items: Query<&ItemPos>,
 ) {
     let mut scoreboard = scoreboard.single_mut();
-    for (&minion_pos, owner) in &minions {
+    let mut apples = apples.single_mut();
+    for (&minion_pos, _, owner) in minions.iter().filter(|(_, kind, _)| !kind.is_building()) {
         for &item_pos in &items {
             if (minion_pos.0 - item_pos.0).length() < 1.0 {
                 *scoreboard.0.entry(owner.0).or_default() += 1;
+                *apples.0.entry(owner.0).or_default() += 1;
             }
         }
     }
@@ -0,0 +1,407 @@
+use bevy::ecs::system::SystemParam;
+use bevy::prelude::*;
+use bevy::utils::{HashMap, HashSet};
+use lightyear::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use super::minion::{MinionPosition, UnitKind};
+use super::player::{Ping, PlayerColor, PlayerId, PlayerName, PlayerPosition, Team};
+use super::resource::{Apples, Item, ItemPos, Scoreboard};
+use super::status::Health;
+use super::{OwnedBy, Replayed, shared_config};
+
+/// Shows a match from snapshots of it instead of replication, for spectators who watch it late
+/// and for replays.
+///
+/// The server takes a snapshot of everything replicated every [`SNAPSHOT_INTERVAL`] ticks, and
+/// [`Playback`] interpolates between them.
+pub struct SnapshotPlugin;
+
+impl Plugin for SnapshotPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_systems(Update, apply_snapshot.run_if(resource_exists::<Playback>));
+    }
+}
+
+/// Ticks between snapshots, about eight a second.
+pub const SNAPSHOT_INTERVAL: u16 = 8;
+/// How far live playback stays behind the newest snapshot, so there's one to move towards even
+/// when the next is a little late.
+const LIVE_BUFFER: f64 = 0.25;
+
+/// Everything replicated at one tick. Entities are identified by their entity on the server.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct Snapshot {
+    tick: u16,
+    players: Vec<PlayerSnapshot>,
+    units: Vec<UnitSnapshot>,
+    items: Vec<(u64, Item, ItemPos)>,
+    scoreboard: Option<Scoreboard>,
+    apples: Option<Apples>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+struct PlayerSnapshot {
+    id: u64,
+    player: PlayerId,
+    name: PlayerName,
+    color: PlayerColor,
+    team: Team,
+    position: PlayerPosition,
+    ping: Ping,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+struct UnitSnapshot {
+    id: u64,
+    kind: UnitKind,
+    position: MinionPosition,
+    health: Health,
+    owner: OwnedBy,
+    color: PlayerColor,
+}
+
+/// Everything on the server that goes into a [`Snapshot`].
+#[derive(SystemParam)]
+pub struct SnapshotSource<'w, 's> {
+    players: Query<
+        'w,
+        's,
+        (
+            Entity,
+            &'static PlayerId,
+            &'static PlayerName,
+            &'static PlayerColor,
+            &'static Team,
+            &'static PlayerPosition,
+            &'static Ping,
+        ),
+        With<Replicating>,
+    >,
+    units: Query<
+        'w,
+        's,
+        (
+            Entity,
+            &'static UnitKind,
+            &'static MinionPosition,
+            &'static Health,
+            &'static OwnedBy,
+            &'static PlayerColor,
+        ),
+        With<Replicating>,
+    >,
+    items: Query<'w, 's, (Entity, &'static Item, &'static ItemPos), With<Replicating>>,
+    scoreboard: Query<'w, 's, &'static Scoreboard, With<Replicating>>,
+    apples: Query<'w, 's, &'static Apples, With<Replicating>>,
+}
+
+impl SnapshotSource<'_, '_> {
+    pub fn take(&self, tick: u16) -> Snapshot {
+        Snapshot {
+            tick,
+            players: self
+                .players
+                .iter()
+                .map(
+                    |(entity, &player, name, &color, &team, &position, &ping)| PlayerSnapshot {
+                        id: entity.to_bits(),
+                        player,
+                        name: name.clone(),
+                        color,
+                        team,
+                        position,
+                        ping,
+                    },
+                )
+                .collect(),
+            units: self
+                .units
+                .iter()
+                .map(
+                    |(entity, &kind, &position, &health, &owner, &color)| UnitSnapshot {
+                        id: entity.to_bits(),
+                        kind,
+                        position,
+                        health,
+                        owner,
+                        color,
+                    },
+                )
+                .collect(),
+            items: self
+                .items
+                .iter()
+                .map(|(entity, &item, &pos)| (entity.to_bits(), item, pos))
+                .collect(),
+            scoreboard: self.scoreboard.get_single().ok().cloned(),
+            apples: self.apples.get_single().ok().cloned(),
+        }
+    }
+}
+
+/// Snapshots placed in time, oldest first.
+#[derive(Debug)]
+pub struct Timeline {
+    tick_duration: f64,
+    snapshots: Vec<Snapshot>,
+    /// Seconds from the start to each snapshot.
+    times: Vec<f64>,
+}
+
+impl Timeline {
+    /// Starts at `first`, the rest is added with [`Self::push`].
+    pub fn new(first: Snapshot, tick_duration: f64) -> Self {
+        Self {
+            tick_duration,
+            snapshots: vec![first],
+            times: vec![0.0],
+        }
+    }
+
+    pub fn push(&mut self, snapshot: Snapshot) {
+        self.times.push(self.time_at(snapshot.tick));
+        self.snapshots.push(snapshot);
+    }
+
+    /// Seconds from the start to `tick`, which is no earlier than the newest snapshot. Ticks wrap
+    /// around, so it's placed by the ticks since that snapshot.
+    pub fn time_at(&self, tick: u16) -> f64 {
+        let last = self.snapshots.len() - 1;
+        let ticks = tick.wrapping_sub(self.snapshots[last].tick);
+        self.times[last] + ticks as f64 * self.tick_duration
+    }
+
+    /// Drops the snapshots that are no longer needed to show `time` or anything after it.
+    fn forget_before(&mut self, time: f64) {
+        let needed = self
+            .times
+            .partition_point(|&start| start <= time)
+            .saturating_sub(1);
+        self.snapshots.drain(..needed);
+        self.times.drain(..needed);
+    }
+
+    /// Seconds from the start to snapshot `index`.
+    fn time_of(&self, index: usize) -> f64 {
+        self.times[index]
+    }
+
+    pub fn duration(&self) -> f64 {
+        self.time_of(self.snapshots.len() - 1)
+    }
+}
+
+/// The match being watched from snapshots.
+#[derive(Resource)]
+pub struct Playback {
+    timeline: Timeline,
+    /// Seconds into the timeline.
+    pub time: f64,
+    pub speed: f32,
+    pub paused: bool,
+    /// The entity spawned for each entity in the snapshots.
+    entities: HashMap<u64, Entity>,
+}
+
+impl Playback {
+    /// Plays a recorded timeline from the start.
+    pub fn new(timeline: Timeline) -> Self {
+        Self {
+            timeline,
+            time: 0.0,
+            speed: 1.0,
+            paused: false,
+            entities: HashMap::new(),
+        }
+    }
+
+    /// Plays snapshots as they come in, a little behind the newest. See [`Self::follow`].
+    pub fn live(first: Snapshot) -> Self {
+        let tick_duration = shared_config(Mode::HostServer)
+            .tick
+            .tick_duration
+            .as_secs_f64();
+        Self {
+            time: -LIVE_BUFFER,
+            ..Self::new(Timeline::new(first, tick_duration))
+        }
+    }
+
+    pub fn timeline(&self) -> &Timeline {
+        &self.timeline
+    }
+
+    pub fn push(&mut self, snapshot: Snapshot) {
+        self.timeline.push(snapshot);
+    }
+
+    /// Moves on by `delta` seconds. It catches up when it falls too far behind the newest
+    /// snapshot, and stops at it when the next doesn't come in.
+    pub fn follow(&mut self, delta: f64) {
+        let newest = self.timeline.duration();
+        self.time = (self.time + delta).clamp(newest - 2.0 * LIVE_BUFFER, newest);
+        self.timeline.forget_before(self.time);
+    }
+
+    /// Playing again after the end starts over.
+    pub fn toggle_pause(&mut self) {
+        self.paused = !self.paused;
+        if !self.paused && self.time >= self.timeline.duration() {
+            self.time = 0.0;
+        }
+    }
+}
+
+/// Puts the world in the state it was in at the current time, interpolating positions between
+/// the snapshots around it.
+pub fn apply_snapshot(
+    mut commands: Commands,
+    mut playback: ResMut<Playback>,
+    mut players: Query<
+        (&mut PlayerPosition, &mut PlayerName, &mut Ping),
+        (With<Replayed>, Without<MinionPosition>),
+    >,
+    mut units: Query<(&mut MinionPosition, &mut Health), With<Replayed>>,
+    mut scoreboard: Query<&mut Scoreboard, With<Replayed>>,
+    mut apples: Query<&mut Apples, With<Replayed>>,
+) {
+    let Playback {
+        timeline,
+        time,
+        entities,
+        ..
+    } = &mut *playback;
+    let index = timeline
+        .times
+        .partition_point(|&start| start <= *time)
+        .saturating_sub(1);
+    let from = &timeline.snapshots[index];
+    let (to, t) = match timeline.snapshots.get(index + 1) {
+        Some(to) => {
+            let start = timeline.time_of(index);
+            let t = (*time - start) / (timeline.time_of(index + 1) - start);
+            (to, t.clamp(0.0, 1.0) as f32)
+        }
+        None => (from, 0.0),
+    };
+
+    let next_players = to
+        .players
+        .iter()
+        .map(|next| (next.id, next))
+        .collect::<HashMap<_, _>>();
+    let next_units = to
+        .units
+        .iter()
+        .map(|next| (next.id, next))
+        .collect::<HashMap<_, _>>();
+
+    let mut seen = HashSet::new();
+    for player in &from.players {
+        seen.insert(player.id);
+        let next = next_players.get(&player.id);
+        let position = PlayerPosition(
+            player
+                .position
+                .lerp(next.map_or(player.position.0, |next| next.position.0), t),
+        );
+        let entity = entities.get(&player.id).copied();
+        match entity.and_then(|entity| players.get_mut(entity).ok()) {
+            Some((mut current, mut name, mut ping)) => {
+                *current = position;
+                if *name != player.name {
+                    *name = player.name.clone();
+                }
+                *ping = player.ping;
+            }
+            None => {
+                let entity = commands
+                    .spawn((
+                        Replayed,
+                        Name::new(format!("Replayed player - {}", player.player.0)),
+                        player.player,
+                        player.name.clone(),
+                        player.color,
+                        player.team,
+                        position,
+                        player.ping,
+                    ))
+                    .id();
+                entities.insert(player.id, entity);
+            }
+        }
+    }
+
+    for unit in &from.units {
+        seen.insert(unit.id);
+        let next = next_units.get(&unit.id);
+        let position = MinionPosition(
+            unit.position
+                .lerp(next.map_or(unit.position.0, |next| next.position.0), t),
+        );
+        let entity = entities.get(&unit.id).copied();
+        match entity.and_then(|entity| units.get_mut(entity).ok()) {
+            Some((mut current, mut health)) => {
+                *current = position;
+                *health = unit.health;
+            }
+            None => {
+                // No `MinionTarget`, so the movement systems leave replayed units alone
+                let entity = commands
+                    .spawn((
+                        Replayed,
+                        Name::new(format!("Replayed {}", unit.kind.name())),
+                        unit.kind,
+                        position,
+                        unit.health,
+                        unit.owner,
+                        unit.color,
+                    ))
+                    .id();
+                entities.insert(unit.id, entity);
+            }
+        }
+    }
+
+    for &(id, item, pos) in &from.items {
+        seen.insert(id);
+        entities
+            .entry(id)
+            .or_insert_with(|| commands.spawn((Replayed, item, pos)).id());
+    }
+
+    if let Some(recorded) = &from.scoreboard {
+        match scoreboard.get_single_mut() {
+            Ok(mut scoreboard) => {
+                if *scoreboard != *recorded {
+                    *scoreboard = recorded.clone();
+                }
+            }
+            Err(_) => {
+                commands.spawn((Replayed, recorded.clone()));
+            }
+        }
+    }
+    if let Some(recorded) = &from.apples {
+        match apples.get_single_mut() {
+            Ok(mut apples) => {
+                if *apples != *recorded {
+                    *apples = recorded.clone();
+                }
+            }
+            Err(_) => {
+                commands.spawn((Replayed, recorded.clone()));
+            }
+        }
+    }
+
+    // Whatever isn't in this snapshot hadn't been spawned yet or was gone by then
+    entities.retain(|id, entity| {
+        let keep = seen.contains(id);
+        if !keep {
+            commands.entity(*entity).despawn_recursive();
+        }
+        keep
+    });
+}
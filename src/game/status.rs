@@ -0,0 +1,319 @@
+use bevy::input::common_conditions::input_toggle_active;
+use bevy::prelude::*;
+use bevy::sprite::Anchor;
+use bevy::utils::HashMap;
+use bevy::window::PrimaryWindow;
+use bevy_egui::EguiContexts;
+use bevy_egui::egui::{Align2, ComboBox};
+use lightyear::prelude::client::{ClientConnection, NetClient};
+use lightyear::prelude::*;
+
+use super::minion::{MinionPosition, Selected};
+use super::player::{PlayerId, Team};
+use super::{OwnedBy, Relevant};
+use crate::minimap::MinimapCamera;
+
+pub struct StatusPlugin;
+
+impl Plugin for StatusPlugin {
+    fn build(&self, app: &mut App) {
+        app.register_type::<HealthBarMode>()
+            .init_resource::<HealthBarMode>()
+            .add_systems(Startup, load_status_assets)
+            .add_systems(
+                Update,
+                (
+                    add_status_visuals,
+                    update_hovered,
+                    (update_selection_rings, update_health_bars),
+                )
+                    .chain(),
+            )
+            .add_systems(
+                Update,
+                show_status_settings.run_if(input_toggle_active(false, KeyCode::F10)),
+            );
+    }
+}
+
+#[derive(Component, Reflect, Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct Health {
+    pub current: f32,
+    pub max: f32,
+}
+
+impl Health {
+    pub fn new(max: f32) -> Self {
+        Self { current: max, max }
+    }
+
+    pub fn fraction(&self) -> f32 {
+        (self.current / self.max).clamp(0.0, 1.0)
+    }
+
+    pub fn is_damaged(&self) -> bool {
+        self.current < self.max
+    }
+}
+
+/// Set on the unit currently under the cursor.
+#[derive(Debug, Component)]
+pub struct Hovered;
+
+/// When health bars are drawn. Selected units always show theirs.
+#[derive(Resource, Reflect, Default, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HealthBarMode {
+    Always,
+    #[default]
+    OnDamage,
+    SelectedOnly,
+}
+
+impl HealthBarMode {
+    const ALL: [HealthBarMode; 3] = [Self::Always, Self::OnDamage, Self::SelectedOnly];
+
+    fn label(self) -> &'static str {
+        match self {
+            HealthBarMode::Always => "Always",
+            HealthBarMode::OnDamage => "On damage",
+            HealthBarMode::SelectedOnly => "Selected only",
+        }
+    }
+}
+
+/// How a unit relates to the local player, used to color its selection ring.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Relation {
+    Own,
+    Ally,
+    Enemy,
+}
+
+impl Relation {
+    pub fn of(owner: Option<&OwnedBy>, local: ClientId, teams: &HashMap<ClientId, Team>) -> Self {
+        let Some(owner) = owner else {
+            return Relation::Enemy;
+        };
+        let team = teams.get(&owner.0);
+        if owner.0 == local {
+            Relation::Own
+        } else if team.is_some() && team == teams.get(&local) {
+            Relation::Ally
+        } else {
+            Relation::Enemy
+        }
+    }
+}
+
+#[derive(Resource)]
+struct StatusAssets {
+    ring: Handle<Mesh>,
+    own: Handle<ColorMaterial>,
+    ally: Handle<ColorMaterial>,
+    enemy: Handle<ColorMaterial>,
+    hover: Handle<ColorMaterial>,
+}
+
+impl StatusAssets {
+    fn material(&self, relation: Relation) -> Handle<ColorMaterial> {
+        match relation {
+            Relation::Own => self.own.clone(),
+            Relation::Ally => self.ally.clone(),
+            Relation::Enemy => self.enemy.clone(),
+        }
+    }
+}
+
+/// The child entities drawing a unit's selection ring and health bar.
+#[derive(Component)]
+struct StatusVisuals {
+    ring: Entity,
+    bar: Entity,
+    fill: Entity,
+}
+
+#[derive(Component)]
+struct SelectionRing;
+
+#[derive(Component)]
+struct HealthBar;
+
+#[derive(Component)]
+struct HealthBarFill;
+
+const HEALTH_BAR_WIDTH: f32 = 1.2;
+
+fn load_status_assets(
+    mut commands: Commands,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<ColorMaterial>>,
+) {
+    commands.insert_resource(StatusAssets {
+        ring: meshes.add(Annulus::new(0.8, 0.95)),
+        own: materials.add(Color::srgb(0.2, 1.0, 0.2)),
+        ally: materials.add(Color::srgb(1.0, 0.9, 0.2)),
+        enemy: materials.add(Color::srgb(1.0, 0.2, 0.2)),
+        hover: materials.add(Color::srgba(1.0, 1.0, 1.0, 0.5)),
+    });
+}
+
+fn add_status_visuals(
+    mut commands: Commands,
+    units: Query<Entity, (With<Health>, Without<StatusVisuals>, Relevant)>,
+    assets: Res<StatusAssets>,
+) {
+    for unit in &units {
+        let ring = commands
+            .spawn((
+                SelectionRing,
+                Mesh2d(assets.ring.clone()),
+                MeshMaterial2d(assets.hover.clone()),
+                Transform::from_xyz(0.0, 0.0, -0.1),
+                Visibility::Hidden,
+            ))
+            .id();
+        let fill = commands
+            .spawn((
+                HealthBarFill,
+                Sprite {
+                    color: Color::srgb(0.2, 0.9, 0.2),
+                    custom_size: Some(Vec2::new(HEALTH_BAR_WIDTH, 0.15)),
+                    anchor: Anchor::CenterLeft,
+                    ..default()
+                },
+                Transform::from_xyz(-HEALTH_BAR_WIDTH / 2.0, 0.0, 0.01),
+            ))
+            .id();
+        let bar = commands
+            .spawn((
+                HealthBar,
+                Sprite::from_color(Color::srgb(0.1, 0.1, 0.1), Vec2::new(HEALTH_BAR_WIDTH, 0.2)),
+                Transform::from_xyz(0.0, 0.85, 0.2),
+                Visibility::Hidden,
+            ))
+            .add_child(fill)
+            .id();
+
+        commands
+            .entity(unit)
+            .add_children(&[ring, bar])
+            .insert(StatusVisuals { ring, bar, fill });
+    }
+}
+
+fn update_hovered(
+    mut commands: Commands,
+    windows: Query<&Window, With<PrimaryWindow>>,
+    camera: Query<(&Camera, &GlobalTransform), Without<MinimapCamera>>,
+    units: Query<(Entity, &MinionPosition), (With<StatusVisuals>, Relevant)>,
+    hovered: Query<Entity, With<Hovered>>,
+) {
+    let Ok((camera, camera_transform)) = camera.get_single() else {
+        return;
+    };
+    let cursor = windows
+        .get_single()
+        .ok()
+        .and_then(|window| window.cursor_position())
+        .and_then(|cursor| camera.viewport_to_world_2d(camera_transform, cursor).ok());
+
+    let new_hovered = cursor.and_then(|cursor| {
+        units
+            .iter()
+            .map(|(entity, pos)| (entity, pos.0.distance_squared(cursor)))
+            .filter(|&(_, distance)| distance < 0.4 * 0.4)
+            .min_by(|(_, a), (_, b)| a.total_cmp(b))
+            .map(|(entity, _)| entity)
+    });
+
+    for entity in &hovered {
+        if Some(entity) != new_hovered {
+            commands.entity(entity).remove::<Hovered>();
+        }
+    }
+    if let Some(entity) = new_hovered
+        && !hovered.contains(entity)
+    {
+        commands.entity(entity).insert(Hovered);
+    }
+}
+
+fn update_selection_rings(
+    units: Query<(
+        &StatusVisuals,
+        Option<&OwnedBy>,
+        Has<Selected>,
+        Has<Hovered>,
+    )>,
+    mut rings: Query<(&mut Visibility, &mut MeshMaterial2d<ColorMaterial>), With<SelectionRing>>,
+    players: Query<(&PlayerId, &Team), Relevant>,
+    assets: Res<StatusAssets>,
+    connection: Res<ClientConnection>,
+) {
+    let teams = players
+        .iter()
+        .map(|(id, &team)| (id.0, team))
+        .collect::<HashMap<_, _>>();
+
+    for (visuals, owner, selected, hovered) in &units {
+        let Ok((mut visibility, mut material)) = rings.get_mut(visuals.ring) else {
+            continue;
+        };
+
+        *visibility = if selected || hovered {
+            Visibility::Inherited
+        } else {
+            Visibility::Hidden
+        };
+        material.0 = if selected {
+            assets.material(Relation::of(owner, connection.id(), &teams))
+        } else {
+            assets.hover.clone()
+        };
+    }
+}
+
+fn update_health_bars(
+    units: Query<(&StatusVisuals, &Health, Has<Selected>, Has<Hovered>)>,
+    mut bars: Query<&mut Visibility, With<HealthBar>>,
+    mut fills: Query<(&mut Sprite, &mut Transform), With<HealthBarFill>>,
+    mode: Res<HealthBarMode>,
+) {
+    for (visuals, health, selected, hovered) in &units {
+        let shown = selected
+            || match *mode {
+                HealthBarMode::Always => true,
+                HealthBarMode::OnDamage => health.is_damaged() || hovered,
+                HealthBarMode::SelectedOnly => false,
+            };
+        if let Ok(mut visibility) = bars.get_mut(visuals.bar) {
+            *visibility = if shown {
+                Visibility::Inherited
+            } else {
+                Visibility::Hidden
+            };
+        }
+
+        if let Ok((mut sprite, mut transform)) = fills.get_mut(visuals.fill) {
+            let fraction = health.fraction();
+            sprite.color = Color::srgb(1.0 - fraction, fraction, 0.2);
+            transform.scale.x = fraction;
+        }
+    }
+}
+
+fn show_status_settings(mut contexts: EguiContexts, mut mode: ResMut<HealthBarMode>) {
+    bevy_egui::egui::Window::new("Settings")
+        .anchor(Align2::LEFT_TOP, (0.0, 0.0))
+        .resizable([false, false])
+        .collapsible(false)
+        .show(contexts.ctx_mut(), |ui| {
+            ComboBox::from_label("Health bars")
+                .selected_text(mode.label())
+                .show_ui(ui, |ui| {
+                    for option in HealthBarMode::ALL {
+                        ui.selectable_value(&mut *mode, option, option.label());
+                    }
+                });
+        });
+}
@@ -0,0 +1,134 @@
+use std::collections::BTreeMap;
+
+use bevy::prelude::*;
+use bevy_egui::EguiContexts;
+use bevy_egui::egui::{Button, Grid, ProgressBar, TopBottomPanel};
+use lightyear::prelude::client::{ClientConnection, NetClient};
+
+use crate::client::UnitCommand;
+use crate::game::minion::{Selected, UnitKind};
+use crate::game::resource::Apples;
+use crate::game::status::Health;
+use crate::game::{OwnedBy, Relevant};
+use crate::networking::IsClient;
+use crate::spectator::Spectating;
+
+pub struct HudPlugin;
+
+impl Plugin for HudPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_systems(
+            Update,
+            show_hud
+                .run_if(in_state(IsClient))
+                .run_if(not(resource_exists::<Spectating>)),
+        );
+    }
+}
+
+/// Selected units of one kind, shown as a single portrait.
+#[derive(Default)]
+struct SelectionGroup {
+    count: usize,
+    health: f32,
+    max_health: f32,
+}
+
+/// Height of the bottom panel in logical pixels.
+pub const HUD_HEIGHT: f32 = 110.0;
+
+const PORTRAITS_PER_ROW: usize = 4;
+
+fn show_hud(
+    mut contexts: EguiContexts,
+    selected: Query<(&UnitKind, &Health), With<Selected>>,
+    units: Query<&OwnedBy, (With<UnitKind>, Relevant)>,
+    apples: Query<&Apples>,
+    connection: Res<ClientConnection>,
+    mut unit_commands: EventWriter<UnitCommand>,
+) {
+    let client_id = connection.id();
+    let apples = apples
+        .get_single()
+        .ok()
+        .and_then(|apples| apples.get(&client_id).copied())
+        .unwrap_or(0);
+    let population = units.iter().filter(|owner| owner.0 == client_id).count();
+
+    let mut groups = BTreeMap::<UnitKind, SelectionGroup>::new();
+    for (&kind, health) in &selected {
+        let group = groups.entry(kind).or_default();
+        group.count += 1;
+        group.health += health.current;
+        group.max_health += health.max;
+    }
+
+    let mut available = vec![];
+    if !groups.is_empty() {
+        available.push(UnitCommand::Stop);
+    }
+    for &kind in groups.keys() {
+        available.extend(kind.produces().iter().map(|&kind| UnitCommand::Train(kind)));
+    }
+
+    TopBottomPanel::bottom("hud")
+        .exact_height(HUD_HEIGHT)
+        .show(contexts.ctx_mut(), |ui| {
+            ui.horizontal_top(|ui| {
+                ui.vertical(|ui| {
+                    ui.heading("Resources");
+                    ui.label(format!("Apples: {apples}"));
+                    ui.label(format!("Population: {population}"));
+                });
+                ui.separator();
+
+                ui.vertical(|ui| {
+                    ui.heading("Selection");
+                    if groups.is_empty() {
+                        ui.label("Nothing selected");
+                    }
+                    Grid::new("selection").show(ui, |ui| {
+                        for (i, (kind, group)) in groups.iter().enumerate() {
+                            ui.vertical(|ui| {
+                                ui.label(format!("{} x{}", kind.name(), group.count));
+                                ui.add(
+                                    ProgressBar::new(group.health / group.max_health)
+                                        .desired_width(90.0)
+                                        .text(format!(
+                                            "{:.0}/{:.0}",
+                                            group.health, group.max_health
+                                        )),
+                                );
+                            });
+                            if (i + 1) % PORTRAITS_PER_ROW == 0 {
+                                ui.end_row();
+                            }
+                        }
+                    });
+                });
+                ui.separator();
+
+                ui.vertical(|ui| {
+                    ui.heading("Commands");
+                    Grid::new("commands").show(ui, |ui| {
+                        for (i, &command) in available.iter().enumerate() {
+                            let text = match command.hotkey() {
+                                Some(key) => format!("{} ({key:?})", command.label()),
+                                None => command.label(),
+                            };
+                            let affordable = match command {
+                                UnitCommand::Train(kind) => apples >= kind.cost(),
+                                UnitCommand::Stop => true,
+                            };
+                            if ui.add_enabled(affordable, Button::new(text)).clicked() {
+                                unit_commands.send(command);
+                            }
+                            if (i + 1) % PORTRAITS_PER_ROW == 0 {
+                                ui.end_row();
+                            }
+                        }
+                    });
+                });
+            });
+        });
+}
@@ -0,0 +1,191 @@
+use std::fs::File;
+use std::io::{BufRead, BufReader, LineWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Child, ExitStatus, Stdio};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use owo_colors::{AnsiColors, OwoColorize};
+use parking_lot::Mutex;
+
+use crate::cli::Settings;
+use crate::logging::LOG_DIR;
+
+const PREFIX_COLORS: [AnsiColors; 5] = [
+    AnsiColors::Green,
+    AnsiColors::Yellow,
+    AnsiColors::Cyan,
+    AnsiColors::Magenta,
+    AnsiColors::Blue,
+];
+/// How often child processes are checked for having exited.
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Clients launched next to the host by the `local-test` command. Their output is printed to this
+/// terminal with a colored prefix and written to a console log per client, next to the log files
+/// the clients write themselves. Dropping this kills them.
+#[derive(Default)]
+pub struct LocalClients {
+    clients: Vec<LocalClient>,
+}
+
+struct LocalClient {
+    child: Arc<Mutex<Child>>,
+    /// Set once we are killing the client ourselves, so that isn't reported as a crash.
+    stopping: Arc<AtomicBool>,
+    threads: Vec<JoinHandle<()>>,
+}
+
+impl LocalClients {
+    pub fn spawn(&mut self, settings: &Settings, index: u32) -> Result<(), String> {
+        let args = settings.client_args(index as u64, index)?;
+        self.spawn_with_args(args, index)
+            .map_err(|err| format!("Could not start client {index}: {err}"))
+    }
+
+    fn spawn_with_args(&mut self, args: Vec<String>, index: u32) -> std::io::Result<()> {
+        let color = PREFIX_COLORS[(index as usize - 1) % PREFIX_COLORS.len()];
+        let prefix = format!("[C{index}]").color(color).to_string();
+
+        std::fs::create_dir_all(LOG_DIR)?;
+        let log_path = Path::new(LOG_DIR).join(format!("client-{index}-console.log"));
+        let log_file = Arc::new(Mutex::new(LineWriter::new(File::create(&log_path)?)));
+
+        let mut child = std::process::Command::new(std::env::current_exe()?)
+            .args(args)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+
+        let stdout = child.stdout.take().unwrap();
+        let stderr = child.stderr.take().unwrap();
+        let child = Arc::new(Mutex::new(child));
+        let stopping = Arc::new(AtomicBool::new(false));
+
+        let threads = vec![
+            forward_output(stdout, prefix.clone(), log_file.clone(), false),
+            forward_output(stderr, prefix.clone(), log_file, true),
+            watch_exit(child.clone(), stopping.clone(), prefix, log_path),
+        ];
+        self.clients.push(LocalClient {
+            child,
+            stopping,
+            threads,
+        });
+        Ok(())
+    }
+}
+
+impl Drop for LocalClients {
+    fn drop(&mut self) {
+        for client in &self.clients {
+            client.stopping.store(true, Ordering::Relaxed);
+            // Fails if the client already exited, which is fine
+            let _ = client.child.lock().kill();
+        }
+        for client in self.clients.drain(..) {
+            for thread in client.threads {
+                let _ = thread.join();
+            }
+        }
+    }
+}
+
+/// Copies every line from a child's output stream to ours with `prefix` in front, and to its log
+/// file without colors.
+fn forward_output(
+    stream: impl Read + Send + 'static,
+    prefix: String,
+    log_file: Arc<Mutex<LineWriter<File>>>,
+    is_stderr: bool,
+) -> JoinHandle<()> {
+    std::thread::spawn(move || {
+        for line in BufReader::new(stream).lines() {
+            let Ok(line) = line else {
+                break;
+            };
+            if is_stderr {
+                eprintln!("{prefix} {line}");
+            } else {
+                println!("{prefix} {line}");
+            }
+            let _ = writeln!(log_file.lock(), "{}", strip_ansi(&line));
+        }
+    })
+}
+
+/// Reports when a child exits on its own, and whether it crashed.
+fn watch_exit(
+    child: Arc<Mutex<Child>>,
+    stopping: Arc<AtomicBool>,
+    prefix: String,
+    log_path: PathBuf,
+) -> JoinHandle<()> {
+    std::thread::spawn(move || {
+        let status = loop {
+            match child.lock().try_wait() {
+                Ok(Some(status)) => break status,
+                Ok(None) => {}
+                Err(err) => {
+                    eprintln!(
+                        "{prefix} {}",
+                        format!("Could not wait for client: {err}").red()
+                    );
+                    return;
+                }
+            }
+            std::thread::sleep(POLL_INTERVAL);
+        };
+
+        if stopping.load(Ordering::Relaxed) {
+            return;
+        }
+        if status.success() {
+            println!("{prefix} Exited");
+        } else {
+            eprintln!(
+                "{prefix} {} (log: {})",
+                format!("Crashed: {}", describe_exit(status)).red().bold(),
+                log_path.display()
+            );
+        }
+    })
+}
+
+fn describe_exit(status: ExitStatus) -> String {
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::ExitStatusExt;
+        if let Some(signal) = status.signal() {
+            return format!("killed by signal {signal}");
+        }
+    }
+    match status.code() {
+        Some(code) => format!("exit code {code}"),
+        None => status.to_string(),
+    }
+}
+
+/// Removes the color escape codes from a line of log output.
+fn strip_ansi(line: &str) -> String {
+    let mut stripped = String::with_capacity(line.len());
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        if c == '\x1b' {
+            // Skip `ESC [ parameters final-byte`
+            if chars.next() == Some('[') {
+                for c in chars.by_ref() {
+                    if ('@'..='~').contains(&c) {
+                        break;
+                    }
+                }
+            }
+            continue;
+        }
+        stripped.push(c);
+    }
+    stripped
+}
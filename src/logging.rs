@@ -0,0 +1,93 @@
+use bevy::log::{BoxedLayer, LogPlugin};
+use bevy::prelude::*;
+use clap::ValueEnum;
+use serde::Deserialize;
+use tracing_appender::non_blocking::WorkerGuard;
+use tracing_appender::rolling::{RollingFileAppender, Rotation};
+use tracing_subscriber::Layer;
+
+/// Directory all log files are written to.
+pub const LOG_DIR: &str = "logs";
+
+#[derive(ValueEnum, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    #[default]
+    Text,
+    /// One JSON object per line, for feeding dedicated server logs to other tools
+    Json,
+}
+
+#[derive(ValueEnum, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogRotation {
+    Hourly,
+    #[default]
+    Daily,
+    Never,
+}
+
+impl From<LogRotation> for Rotation {
+    fn from(rotation: LogRotation) -> Self {
+        match rotation {
+            LogRotation::Hourly => Rotation::HOURLY,
+            LogRotation::Daily => Rotation::DAILY,
+            LogRotation::Never => Rotation::NEVER,
+        }
+    }
+}
+
+/// Where and how a process logs. Everything goes to the terminal as usual, and also to a
+/// rotating file in [`LOG_DIR`] named after the process so desyncs can be looked into later.
+#[derive(Resource, Clone, Debug)]
+pub struct LogSettings {
+    /// `EnvFilter` directives. `RUST_LOG` takes precedence when it is set.
+    pub filter: String,
+    pub format: LogFormat,
+    pub rotation: LogRotation,
+    /// Name of the log file, e.g. `host` or `client-2`.
+    pub file_name: String,
+}
+
+impl LogSettings {
+    /// Bevy's log plugin, with our filter and the file output added. The settings have to be
+    /// inserted as a resource before the plugin is added.
+    pub fn plugin(&self) -> LogPlugin {
+        LogPlugin {
+            filter: format!("wgpu=error,naga=warn,{}", self.filter),
+            custom_layer: file_layer,
+            ..default()
+        }
+    }
+}
+
+/// Keeps the background thread writing the log file alive, flushing it when the app exits.
+#[derive(Resource)]
+struct LogFileGuard(#[expect(unused)] WorkerGuard);
+
+fn file_layer(app: &mut App) -> Option<BoxedLayer> {
+    let settings = app.world().get_resource::<LogSettings>()?.clone();
+
+    let appender = RollingFileAppender::builder()
+        .rotation(settings.rotation.into())
+        .filename_prefix(&settings.file_name)
+        .filename_suffix("log")
+        .build(LOG_DIR);
+    let appender = match appender {
+        Ok(appender) => appender,
+        Err(err) => {
+            eprintln!("Could not create log file in {LOG_DIR}: {err}");
+            return None;
+        }
+    };
+    let (writer, guard) = tracing_appender::non_blocking(appender);
+    app.insert_resource(LogFileGuard(guard));
+
+    let layer = tracing_subscriber::fmt::layer()
+        .with_writer(writer)
+        .with_ansi(false);
+    Some(match settings.format {
+        LogFormat::Text => layer.boxed(),
+        LogFormat::Json => layer.json().with_current_span(true).boxed(),
+    })
+}
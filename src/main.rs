@@ -1,98 +1,163 @@
 #![allow(clippy::type_complexity)]
 
-use std::fmt::Display;
-use std::net::{Ipv4Addr, SocketAddr};
-use std::process::Stdio;
-use std::sync::Arc;
+use std::net::SocketAddr;
+use std::path::Path;
 
 use bevy::input::common_conditions::input_toggle_active;
+use bevy::log::LogPlugin;
 use bevy::prelude::*;
-use bevy::window::WindowResolution;
+use bevy::render::RenderPlugin;
+use bevy::render::settings::WgpuSettings;
+use bevy::window::{ExitCondition, WindowResolution};
+use bevy::winit::WinitPlugin;
+use bevy_egui::EguiPlugin;
 use bevy_inspector_egui::quick::WorldInspectorPlugin;
-use lightyear::prelude::SteamworksClient;
-use owo_colors::OwoColorize;
-use parking_lot::RwLock;
+use tracing_subscriber::EnvFilter;
 
+use ai::{AiOpponents, AiPlugin};
+use auth::IssuedConnectToken;
+use browser::BrowserPlugin;
+use cli::{BotArgs, Command, Settings};
 use client::ClientPlugin;
-use game::GamePlugin;
-use networking::{NetworkState, SteamClient};
-use server::ServerPlugin;
+use conditioner::ConditionerPlugin;
+use connection::ConnectionPlugin;
+use discovery::{DiscoveryPlugin, ServerName};
+use game::snapshot::Playback;
+use game::{GamePlugin, Role};
+use hud::HudPlugin;
+use launcher::LocalClients;
+use logging::LogSettings;
+use master_server::{MasterServer, MasterServerPlugin, Region};
+use minimap::MinimapPlugin;
+use netstats::NetStatsPlugin;
+use networking::NetworkState;
+use platform::{Platform, PlatformPlugin};
+use replay::{RecordReplays, Replay, ReplayPlugin};
+use save::{Autosave, LoadedMatch, SaveFile, SavePlugin, claim_secret_path, load_claim_secret};
+use server::{ServerPlugin, SpectatorDelay};
+use spectator::{JoinAs, SpectatorPlugin};
+use token_service::{TokenService, TokenServicePort};
 
 use self::networking::NetworkingPlugin;
 
+mod ai;
+mod auth;
+mod bot;
+mod browser;
+mod cli;
 mod client;
+mod conditioner;
+mod connection;
+mod discovery;
 mod game;
+mod hud;
+mod launcher;
+mod logging;
+mod master_server;
+mod minimap;
+mod netstats;
 mod networking;
+mod platform;
+mod replay;
+mod save;
 mod server;
+mod spectator;
+mod token_service;
+
+#[cfg(test)]
+mod tests;
 
 fn main() {
-    match std::env::args().nth(1).as_deref() {
-        _ if cfg!(not(debug_assertions)) => start_normal(),
-        Some("normal") => start_normal(),
-        Some("client") => client(
-            std::env::args()
-                .nth(2)
-                .expect("Client needs a second argument")
-                .parse::<i32>()
-                .expect("Second argument must be a number"),
-        ),
-        Some("server") => server(),
-        Some("host") | None => {
-            start_client(1, "[C1]".green());
-            start_client(2, "[C2]".yellow());
-
-            server();
+    let settings = Settings::load().unwrap_or_else(|err| {
+        eprintln!("{err}");
+        std::process::exit(2);
+    });
+
+    if let Err(err) = run(&settings) {
+        eprintln!("{err}");
+        std::process::exit(1);
+    }
+}
+
+/// Runs the command in `settings`. Errors are returned rather than exiting on the spot, so
+/// everything started on the way gets dropped, and local clients killed, first.
+fn run(settings: &Settings) -> Result<(), String> {
+    match settings.command {
+        Command::Menu => start_menu(settings),
+        Command::Host => host(settings)?,
+        Command::Server => server(settings)?,
+        Command::Client {
+            client_id,
+            slot,
+            ref connect_token,
+            spectate,
+        } => {
+            let connect_token = connect_token
+                .as_deref()
+                .map(auth::decode_hex)
+                .transpose()
+                .map_err(|err| format!("Invalid connect token: {err}"))?;
+            client(
+                settings,
+                client_id.unwrap_or_else(rand::random),
+                slot,
+                IssuedConnectToken(connect_token),
+                if spectate {
+                    Role::Spectator
+                } else {
+                    Role::Player
+                },
+            )
+        }
+        Command::Replay { ref file } => replay(settings, file)?,
+        Command::Bot(ref args) => bots(settings, args)?,
+        Command::MasterServer => master_server(settings)?,
+        Command::TokenService => token_service(settings)?,
+        Command::LocalTest { .. } => {
+            let mut clients = LocalClients::default();
+            for index in 1..=settings.local_clients {
+                if let Err(err) = clients.spawn(settings, index) {
+                    eprintln!("{err}");
+                }
+            }
+
+            // Dropping the clients on the way out takes them down with the host
+            host(settings)?;
         }
-        _ => panic!("The first argument must be in {{server,client,host}}"),
     }
+    Ok(())
 }
 
-fn start_normal() {
+fn start_menu(settings: &Settings) {
     create_app(
+        settings,
+        settings.log_settings("menu"),
         "Bevy AoE".into(),
         WindowPosition::Centered(MonitorSelection::Primary),
         default(),
         true,
     )
+    .insert_resource(load_claim_secret(&claim_secret_path(0)))
     .run();
 }
 
-fn start_client(index: usize, prefix: impl Display) {
-    let mut child = std::process::Command::new(std::env::args().next().unwrap())
-        .args(["client", &format!("{index}")])
-        .stdin(Stdio::piped())
-        .stdout(Stdio::piped())
-        .stderr(Stdio::piped())
-        .spawn()
-        .unwrap();
-
-    let prefix = format!("{{ print \"{} \" $0}}", prefix);
-
-    #[expect(clippy::zombie_processes)]
-    std::process::Command::new("awk")
-        .arg(prefix.clone())
-        .stdin(child.stdout.take().unwrap())
-        .spawn()
-        .unwrap();
-    #[expect(clippy::zombie_processes)]
-    std::process::Command::new("awk")
-        .arg(prefix)
-        .stdin(child.stderr.take().unwrap())
-        .spawn()
-        .unwrap();
-
-    let _ = child;
-}
-
 pub fn create_app(
+    settings: &Settings,
+    log_settings: LogSettings,
     title: String,
     position: WindowPosition,
     resolution: WindowResolution,
     focused: bool,
 ) -> App {
-    let steam_client = Arc::new(RwLock::new(SteamworksClient::new_with_app_id(480)));
+    // Dedicated servers have no player to bring friends, and shouldn't need Steam running
+    let platform = Platform::detect(settings.command == Command::Server, settings.steam_app_id);
+    if let Some(lobby) = settings.join_lobby {
+        platform.join_lobby(lobby);
+    }
     let mut app = App::new();
-    app.insert_resource(SteamClient(steam_client))
+    insert_settings(&mut app, settings)
+        .insert_resource(platform)
+        .insert_resource(log_settings.clone())
         .add_plugins((
             DefaultPlugins
                 .set(WindowPlugin {
@@ -107,81 +172,243 @@ pub fn create_app(
                     }),
                     ..default()
                 })
-                .set(ImagePlugin::default_nearest()),
+                .set(ImagePlugin::default_nearest())
+                .set(log_settings.plugin()),
             WorldInspectorPlugin::new().run_if(input_toggle_active(false, KeyCode::F3)),
-            NetworkingPlugin,
-            ServerPlugin,
-            ClientPlugin,
-            GamePlugin,
-        ))
-        .add_systems(
-            Update,
-            (move |mut windows: Query<&mut Window>, time: Res<Time>| {
-                if time.elapsed_secs_f64() < 1.0 {
-                    for mut window in &mut windows {
-                        window.position = position;
-                        window.resolution = resolution.clone();
-                        window.focused = focused;
-                    }
+        ));
+    add_game_plugins(&mut app);
+    app.add_systems(
+        Update,
+        (move |mut windows: Query<&mut Window>, time: Res<Time>| {
+            if time.elapsed_secs_f64() < 1.0 {
+                for mut window in &mut windows {
+                    window.position = position;
+                    window.resolution = resolution.clone();
+                    window.focused = focused;
                 }
-            },),
-        );
+            }
+        },),
+    );
     app
 }
 
-pub fn server() {
-    println!("Starting host server/client!");
+/// An app with the whole game in it that runs without opening a window or rendering anything,
+/// for bots and tests. Doesn't set up logging.
+pub fn create_headless_app() -> App {
+    let mut app = App::new();
+    app.add_plugins((
+        DefaultPlugins
+            .build()
+            .disable::<WinitPlugin>()
+            .disable::<LogPlugin>()
+            .set(WindowPlugin {
+                // The UI still needs a window to lay itself out in
+                primary_window: Some(Window::default()),
+                exit_condition: ExitCondition::DontExit,
+                close_when_requested: false,
+            })
+            .set(RenderPlugin {
+                render_creation: WgpuSettings {
+                    backends: None,
+                    ..default()
+                }
+                .into(),
+                ..default()
+            }),
+        EguiPlugin,
+    ));
+    add_game_plugins(&mut app);
+    app
+}
 
-    let monitor_width = 2560.0;
-    let monitor_height = 1440.0;
-    let window_width = monitor_width / 2.0;
-    let window_height = monitor_height / 2.0;
-    let position = WindowPosition::At(IVec2::new(
-        (monitor_width - window_width) as i32 / 2,
-        (monitor_height - window_height) as i32 / 2,
+/// The resources the game's plugins expect, filled in from the settings. The platform is up to
+/// the caller.
+pub fn insert_settings<'a>(app: &'a mut App, settings: &Settings) -> &'a mut App {
+    if let Some(key) = settings.key {
+        app.insert_resource(key);
+    }
+    app.insert_resource(settings.link_conditions)
+        .insert_resource(TokenServicePort(settings.token_port))
+        .insert_resource(ServerName(settings.server_name.clone()))
+        .insert_resource(Region(settings.region.clone()))
+        .insert_resource(MasterServer {
+            addr: settings.master_server,
+        })
+        .insert_resource(SpectatorDelay(settings.spectator_delay))
+        .insert_resource(RecordReplays(settings.record_replays))
+        .insert_resource(Autosave(settings.autosave))
+        .insert_resource(AiOpponents(settings.ai_opponents.clone()))
+}
+
+/// Everything that makes up the game, without the engine plugins it runs on. Needs the resources
+/// from [`insert_settings`] as well.
+pub fn add_game_plugins(app: &mut App) {
+    app.add_plugins((
+        (NetworkingPlugin, PlatformPlugin),
+        ServerPlugin,
+        ClientPlugin,
+        GamePlugin,
+        HudPlugin,
+        MinimapPlugin,
+        NetStatsPlugin,
+        ConditionerPlugin,
+        ConnectionPlugin,
+        (DiscoveryPlugin, MasterServerPlugin, BrowserPlugin),
+        (SpectatorPlugin, ReplayPlugin, SavePlugin, AiPlugin),
     ));
+}
+
+pub fn host(settings: &Settings) -> Result<(), String> {
+    let monitor = settings.monitor.as_vec2();
+    let window_size = monitor / 2.0;
+    let position = WindowPosition::At(((monitor - window_size) / 2.0).as_ivec2());
     let resolution =
-        WindowResolution::new(window_width, window_height).with_scale_factor_override(1.0);
-
-    create_app("Bevy AoE - Server".into(), position, resolution, true)
-        .add_systems(
-            Update,
-            move |mut windows: Query<&mut Window>, time: Res<Time>| {
-                if time.elapsed_secs_f64() < 1.0 {
-                    for mut window in &mut windows {
-                        window.focused = true;
-                    }
+        WindowResolution::new(window_size.x, window_size.y).with_scale_factor_override(1.0);
+
+    let mut app = create_app(
+        settings,
+        settings.log_settings("host"),
+        "Bevy AoE - Host".into(),
+        position,
+        resolution,
+        true,
+    );
+    info!(addr = %settings.server_addr, "Starting host server/client");
+    load_match(settings, &mut app)?;
+    app.add_systems(
+        Update,
+        move |mut windows: Query<&mut Window>, time: Res<Time>| {
+            if time.elapsed_secs_f64() < 1.0 {
+                for mut window in &mut windows {
+                    window.focused = true;
                 }
-            },
-        )
-        .insert_state(NetworkState::Host(SocketAddr::new(
-            Ipv4Addr::LOCALHOST.into(),
-            5000,
-        )))
-        .run();
+            }
+        },
+    )
+    .insert_state(NetworkState::Host(settings.server_addr))
+    .run();
+    Ok(())
 }
 
-pub fn client(index: i32) {
-    println!("Starting client!");
-
-    let monitor_width = 2560.0;
-    let monitor_height = 1440.0;
-    let window_width = monitor_width / 4.0;
-    let window_height = monitor_height / 4.0;
-    let position = WindowPosition::At(
-        (
-            monitor_width as i32 / 2 - window_width as i32 * (index - 1),
-            0,
-        )
-            .into(),
+pub fn server(settings: &Settings) -> Result<(), String> {
+    let mut app = create_app(
+        settings,
+        settings.log_settings("server"),
+        "Bevy AoE - Server".into(),
+        WindowPosition::Centered(MonitorSelection::Primary),
+        default(),
+        true,
     );
+    info!(addr = %settings.server_addr, "Starting dedicated server");
+    load_match(settings, &mut app)?;
+    app.insert_state(NetworkState::Server(settings.server_addr))
+        .run();
+    Ok(())
+}
+
+/// Continues the match given with `--load`, if any.
+fn load_match(settings: &Settings, app: &mut App) -> Result<(), String> {
+    let Some(path) = &settings.load_match else {
+        return Ok(());
+    };
+    let save = SaveFile::load(path).map_err(|err| format!("Could not load the match {err}"))?;
+    if settings.server_name.is_none() {
+        app.insert_resource(ServerName(save.server_name.clone()));
+    }
+    app.insert_resource(LoadedMatch(save));
+    Ok(())
+}
+
+pub fn client(
+    settings: &Settings,
+    client_id: u64,
+    slot: u32,
+    connect_token: IssuedConnectToken,
+    role: Role,
+) {
+    let monitor = settings.monitor.as_vec2();
+    let window_size = monitor / 4.0;
+    let position = WindowPosition::At(IVec2::new(
+        monitor.x as i32 / 2 - window_size.x as i32 * (slot as i32 - 1),
+        0,
+    ));
     let resolution =
-        WindowResolution::new(window_width, window_height).with_scale_factor_override(1.0);
+        WindowResolution::new(window_size.x, window_size.y).with_scale_factor_override(1.0);
 
-    create_app("Bevy AoE - client".into(), position, resolution, false)
+    let mut app = create_app(
+        settings,
+        settings.log_settings(format!("client-{client_id}")),
+        "Bevy AoE - client".into(),
+        position,
+        resolution,
+        false,
+    );
+    info!(client_id, server_addr = %settings.server_addr, ?role, "Starting client");
+    app.insert_resource(connect_token)
+        .insert_resource(load_claim_secret(&claim_secret_path(slot)))
+        .insert_resource(JoinAs(role))
         .insert_state(NetworkState::Client {
-            server_addr: SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 5000),
-            client_id: index as u64,
+            server_addr: settings.server_addr,
+            client_id,
         })
         .run();
 }
+
+pub fn replay(settings: &Settings, file: &Path) -> Result<(), String> {
+    let (replay, timeline) =
+        Replay::load(file).map_err(|err| format!("Could not load the replay {err}"))?;
+
+    let mut app = create_app(
+        settings,
+        settings.log_settings("replay"),
+        "Bevy AoE - Replay".into(),
+        WindowPosition::Centered(MonitorSelection::Primary),
+        default(),
+        true,
+    );
+    info!(file = %file.display(), "Playing replay");
+    app.insert_resource(replay)
+        .insert_resource(Playback::new(timeline))
+        .insert_state(NetworkState::Replay)
+        .run();
+    Ok(())
+}
+
+/// Runs bots against the server, without a window.
+pub fn bots(settings: &Settings, args: &BotArgs) -> Result<(), String> {
+    tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::new(&settings.log_level))
+        .init();
+
+    bot::run(settings, args).map_err(|err| format!("Could not run the bots: {err}"))
+}
+
+/// Runs the master server, without a window.
+pub fn master_server(settings: &Settings) -> Result<(), String> {
+    tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::new(&settings.log_level))
+        .init();
+
+    let addr = settings.master_server.unwrap_or(SocketAddr::new(
+        settings.server_addr.ip(),
+        master_server::DEFAULT_MASTER_PORT,
+    ));
+    master_server::run(addr)
+        .map_err(|err| format!("Could not run the master server on {addr}: {err}"))
+}
+
+/// Runs only the token service, without a window.
+pub fn token_service(settings: &Settings) -> Result<(), String> {
+    tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::new(&settings.log_level))
+        .init();
+
+    let key = settings
+        .key
+        .expect("The key is loaded for the token service");
+    let addr = SocketAddr::new(settings.server_addr.ip(), settings.token_port);
+    let service = TokenService::start(addr, settings.server_addr, key)
+        .map_err(|err| format!("Could not start the token service on {addr}: {err}"))?;
+    service.join();
+    Ok(())
+}
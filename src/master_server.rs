@@ -0,0 +1,269 @@
+use std::net::{Ipv4Addr, SocketAddr, UdpSocket};
+use std::time::{Duration, Instant};
+
+use bevy::prelude::*;
+use bevy::time::common_conditions::on_timer;
+use bevy::utils::HashMap;
+use lightyear::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::discovery::{
+    DiscoveryResponder, ServerInfo, ServerName, decode_datagram, encode_datagram, local_server_info,
+};
+use crate::game::player::PlayerId;
+use crate::networking::{LocalPlayerName, NetworkState};
+use crate::token_service::TokenServicePort;
+
+/// Keeps dedicated servers registered with the master server, so they show up in everyone's
+/// server browser.
+pub struct MasterServerPlugin;
+
+impl Plugin for MasterServerPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_computed_state::<IsDedicatedServer>()
+            .init_resource::<Registration>()
+            .add_systems(OnEnter(IsDedicatedServer), register_with_master)
+            .add_systems(
+                Update,
+                (
+                    register_with_master.run_if(on_timer(HEARTBEAT_INTERVAL)),
+                    receive_registration_replies,
+                )
+                    .run_if(in_state(IsDedicatedServer)),
+            )
+            .add_systems(OnExit(IsDedicatedServer), unregister_from_master);
+    }
+}
+
+pub const DEFAULT_MASTER_PORT: u16 = 27900;
+const REGISTER: &[u8] = b"AOE+";
+const UNREGISTER: &[u8] = b"AOE-";
+/// The master server's answer to a heartbeat it listed.
+const REGISTERED: &[u8] = b"AOE#";
+/// The master server's answer to a heartbeat it could not read.
+const REJECTED: &[u8] = b"AOE~";
+pub const QUERY: &[u8] = b"AOE*";
+pub const LISTING: &[u8] = b"AOE=";
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(10);
+/// The master server forgets servers that haven't sent a heartbeat for this long.
+const LISTING_TIMEOUT: Duration = Duration::from_secs(35);
+/// Heartbeats without an answer before we warn that the server is probably not listed.
+const UNANSWERED_WARNING: u32 = 3;
+
+/// Where the master server runs, if there is one.
+#[derive(Resource, Clone, Copy, Debug)]
+pub struct MasterServer {
+    pub addr: Option<SocketAddr>,
+}
+
+/// Region this server says it is in, so players can look for servers close to them.
+#[derive(Resource, Clone, Debug)]
+pub struct Region(pub String);
+
+/// A server as the master server lists it.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Listing {
+    /// Filled in by the master server from where the heartbeat came from, as servers often don't
+    /// know their public address.
+    pub addr: SocketAddr,
+    pub region: String,
+    /// Where the server answers discovery probes, which clients use to measure their ping.
+    pub discovery_port: Option<u16>,
+    pub info: ServerInfo,
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+struct IsDedicatedServer;
+
+impl ComputedStates for IsDedicatedServer {
+    type SourceStates = NetworkState;
+
+    fn compute(network_state: NetworkState) -> Option<Self> {
+        matches!(network_state, NetworkState::Server(_)).then_some(Self)
+    }
+}
+
+/// The game port we registered, so we can unregister after the network state has moved on.
+#[derive(Resource)]
+struct RegisteredPort(u16);
+
+/// Heartbeats go out from here, and the master server's answers come back to it.
+#[derive(Resource, Default)]
+struct Registration {
+    socket: Option<UdpSocket>,
+    /// Heartbeats sent since the master server last answered one.
+    unanswered: u32,
+}
+
+#[allow(clippy::too_many_arguments)]
+fn register_with_master(
+    mut commands: Commands,
+    mut registration: ResMut<Registration>,
+    master: Res<MasterServer>,
+    region: Res<Region>,
+    network_state: Res<State<NetworkState>>,
+    server_name: Res<ServerName>,
+    local_name: Res<LocalPlayerName>,
+    token_port: Res<TokenServicePort>,
+    responder: Option<Res<DiscoveryResponder>>,
+    players: Query<(), (With<PlayerId>, With<Replicating>)>,
+) {
+    let Some(master_addr) = master.addr else {
+        return;
+    };
+    let Some(info) = local_server_info(
+        network_state.get(),
+        &server_name,
+        &local_name,
+        token_port.0,
+        players.iter().count(),
+    ) else {
+        return;
+    };
+    let port = info.game_port;
+    let listing = Listing {
+        addr: SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), info.game_port),
+        region: region.0.clone(),
+        discovery_port: responder.and_then(|responder| responder.port()),
+        info,
+    };
+
+    if registration.socket.is_none() {
+        registration.socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))
+            .and_then(|socket| socket.set_nonblocking(true).map(|()| socket))
+            .inspect_err(|err| warn!("Could not open a socket for the master server: {err}"))
+            .ok();
+    }
+    let (Some(socket), Some(heartbeat)) =
+        (&registration.socket, encode_datagram(REGISTER, &listing))
+    else {
+        return;
+    };
+    match socket.send_to(&heartbeat, master_addr) {
+        Ok(_) => commands.insert_resource(RegisteredPort(port)),
+        Err(err) => warn!(%master_addr, "Could not reach the master server: {err}"),
+    }
+
+    registration.unanswered += 1;
+    if registration.unanswered == UNANSWERED_WARNING {
+        warn!(
+            %master_addr,
+            "The master server is not answering, this server is probably not in the server browser"
+        );
+    }
+}
+
+fn receive_registration_replies(mut registration: ResMut<Registration>, master: Res<MasterServer>) {
+    let Registration {
+        socket: Some(socket),
+        unanswered,
+    } = &mut *registration
+    else {
+        return;
+    };
+
+    let mut buf = [0; 16];
+    while let Ok((len, peer)) = socket.recv_from(&mut buf) {
+        if Some(peer) != master.addr {
+            continue;
+        }
+        match &buf[..len] {
+            REGISTERED => {
+                if *unanswered >= UNANSWERED_WARNING {
+                    info!(master_addr = %peer, "The master server is answering again");
+                } else if *unanswered > 0 {
+                    debug!(master_addr = %peer, "Registered with the master server");
+                }
+                *unanswered = 0;
+            }
+            REJECTED => {
+                error!(
+                    master_addr = %peer,
+                    "The master server could not read our heartbeat, it probably runs another version"
+                );
+                *unanswered = 0;
+            }
+            _ => {}
+        }
+    }
+}
+
+fn unregister_from_master(
+    mut commands: Commands,
+    master: Res<MasterServer>,
+    registered: Option<Res<RegisteredPort>>,
+) {
+    let (Some(master_addr), Some(registered)) = (master.addr, registered) else {
+        return;
+    };
+    commands.remove_resource::<RegisteredPort>();
+    if let Ok(socket) = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)) {
+        let _ = socket.send_to(
+            &[UNREGISTER, &registered.0.to_be_bytes()].concat(),
+            master_addr,
+        );
+    }
+}
+
+/// Runs the master server on `addr` until the process is stopped. Servers register with a
+/// heartbeat holding their [`Listing`], and every query is answered with one datagram per
+/// registered server.
+pub fn run(addr: SocketAddr) -> std::io::Result<()> {
+    let socket = UdpSocket::bind(addr)?;
+    socket.set_read_timeout(Some(Duration::from_secs(1)))?;
+    info!(%addr, "Master server listening");
+
+    let mut servers = HashMap::<SocketAddr, (Listing, Instant)>::new();
+    let mut buf = [0; 2048];
+    loop {
+        servers.retain(|addr, (_, seen)| {
+            let alive = seen.elapsed() < LISTING_TIMEOUT;
+            if !alive {
+                info!(%addr, "Server timed out");
+            }
+            alive
+        });
+
+        let (len, peer) = match socket.recv_from(&mut buf) {
+            Ok(received) => received,
+            Err(err)
+                if matches!(
+                    err.kind(),
+                    std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut
+                ) =>
+            {
+                continue;
+            }
+            Err(err) => {
+                warn!("Master server could not receive: {err}");
+                continue;
+            }
+        };
+        let message = &buf[..len];
+
+        if message.starts_with(REGISTER) {
+            let Some(mut listing) = decode_datagram::<Listing>(REGISTER, message) else {
+                warn!(%peer, "Ignoring a heartbeat that could not be read");
+                let _ = socket.send_to(REJECTED, peer);
+                continue;
+            };
+            listing.addr = SocketAddr::new(peer.ip(), listing.info.game_port);
+            if !servers.contains_key(&listing.addr) {
+                info!(addr = %listing.addr, name = %listing.info.name, "Server registered");
+            }
+            servers.insert(listing.addr, (listing, Instant::now()));
+            let _ = socket.send_to(REGISTERED, peer);
+        } else if let Some(&[high, low]) = message.strip_prefix(UNREGISTER) {
+            let addr = SocketAddr::new(peer.ip(), u16::from_be_bytes([high, low]));
+            if servers.remove(&addr).is_some() {
+                info!(%addr, "Server unregistered");
+            }
+        } else if message == QUERY {
+            for (listing, _) in servers.values() {
+                if let Some(listing) = encode_datagram(LISTING, listing) {
+                    let _ = socket.send_to(&listing, peer);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,95 @@
+use bevy::prelude::*;
+use bevy::render::camera::{ScalingMode, Viewport};
+use bevy::render::view::RenderLayers;
+use bevy::window::PrimaryWindow;
+
+use crate::hud::HUD_HEIGHT;
+
+pub struct MinimapPlugin;
+
+impl Plugin for MinimapPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_systems(Startup, spawn_minimap_camera)
+            .add_systems(Update, update_minimap_viewport);
+    }
+}
+
+/// Render layer only drawn on the minimap, for markers that should stand out there.
+pub const MINIMAP_LAYER: usize = 1;
+
+/// Side length of the minimap in logical pixels.
+const MINIMAP_SIZE: f32 = 200.0;
+const MINIMAP_MARGIN: f32 = 10.0;
+/// How much of the world the minimap shows, in world units.
+const MINIMAP_WORLD_SIZE: f32 = 40.0;
+
+#[derive(Component)]
+pub struct MinimapCamera;
+
+fn spawn_minimap_camera(mut commands: Commands) {
+    commands.spawn((
+        MinimapCamera,
+        Camera2d,
+        Camera {
+            order: 1,
+            is_active: false,
+            clear_color: ClearColorConfig::Custom(Color::srgb(0.1, 0.1, 0.1)),
+            ..default()
+        },
+        OrthographicProjection {
+            scaling_mode: ScalingMode::Fixed {
+                width: MINIMAP_WORLD_SIZE,
+                height: MINIMAP_WORLD_SIZE,
+            },
+            far: 1000.0,
+            near: -1000.0,
+            ..OrthographicProjection::default_2d()
+        },
+        RenderLayers::from_layers(&[0, MINIMAP_LAYER]),
+    ));
+}
+
+/// Keeps the minimap in the bottom right corner above the HUD, hiding it when the window is too
+/// small to fit it.
+fn update_minimap_viewport(
+    windows: Query<&Window, With<PrimaryWindow>>,
+    mut minimap: Query<&mut Camera, With<MinimapCamera>>,
+) {
+    let (Ok(window), Ok(mut camera)) = (windows.get_single(), minimap.get_single_mut()) else {
+        return;
+    };
+
+    let scale = window.scale_factor();
+    let size = (MINIMAP_SIZE * scale) as u32;
+    let right = ((MINIMAP_SIZE + MINIMAP_MARGIN) * scale) as u32;
+    let bottom = ((MINIMAP_SIZE + MINIMAP_MARGIN + HUD_HEIGHT) * scale) as u32;
+
+    let fits = window.physical_width() > right && window.physical_height() > bottom;
+    camera.is_active = fits;
+    camera.viewport = fits.then(|| Viewport {
+        physical_position: UVec2::new(
+            window.physical_width() - right,
+            window.physical_height() - bottom,
+        ),
+        physical_size: UVec2::splat(size),
+        ..default()
+    });
+}
+
+/// Converts a cursor position in the window to a world position, if the cursor is over the
+/// minimap.
+pub fn minimap_to_world(
+    camera: &Camera,
+    transform: &GlobalTransform,
+    cursor: Vec2,
+) -> Option<Vec2> {
+    let rect = camera
+        .logical_viewport_rect()
+        .filter(|_| camera.is_active)?;
+    if !rect.contains(cursor) {
+        return None;
+    }
+
+    let offset = (cursor - rect.center()) / rect.size() * MINIMAP_WORLD_SIZE;
+    Some(transform.translation().truncate() + Vec2::new(offset.x, -offset.y))
+}
@@ -0,0 +1,259 @@
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use bevy::input::common_conditions::input_toggle_active;
+use bevy::prelude::*;
+use bevy::time::common_conditions::on_timer;
+use bevy_egui::EguiContexts;
+use bevy_egui::egui::{Align2, Grid};
+use lightyear::prelude::client::{
+    ClientConfig, ClientConnection, Confirmed, Interpolated, NetClient, Predicted, is_in_rollback,
+};
+use lightyear::prelude::*;
+
+use crate::game::{ServerLoad, ServerMessage, StatsChannel, shared_config};
+use crate::networking::IsClient;
+
+pub struct NetStatsPlugin;
+
+impl Plugin for NetStatsPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<NetStats>()
+            .init_resource::<FrameTimes>()
+            .add_systems(First, start_frame.run_if(is_server))
+            .add_systems(Last, end_frame.run_if(is_server))
+            .add_systems(
+                Update,
+                (
+                    send_heartbeats.run_if(on_timer(HEARTBEAT_INTERVAL)),
+                    send_server_load.run_if(on_timer(Duration::from_secs(1))),
+                )
+                    .run_if(is_server),
+            )
+            .add_systems(FixedUpdate, count_rollback_ticks.run_if(is_in_rollback))
+            .add_systems(
+                Update,
+                (
+                    receive_stats,
+                    sample_net_stats.run_if(on_timer(Duration::from_secs(1))),
+                    show_net_stats.run_if(input_toggle_active(false, KeyCode::F9)),
+                )
+                    .run_if(in_state(IsClient)),
+            )
+            .add_systems(
+                PreUpdate,
+                reset_net_stats.run_if(on_event::<ClientConnectEvent>),
+            )
+            .add_systems(OnExit(IsClient), reset_net_stats);
+    }
+}
+
+/// The server sends a numbered heartbeat this often over an unreliable channel, and clients
+/// estimate packet loss from the gaps in the numbers they receive.
+const HEARTBEAT_INTERVAL: Duration = Duration::from_millis(100);
+/// How many of the most recent heartbeats packet loss is computed over.
+const HEARTBEAT_WINDOW: u32 = 50;
+
+#[derive(Resource, Default)]
+pub struct NetStats {
+    heartbeats: VecDeque<u32>,
+    /// The first heartbeat of this connection, as the server numbers them for all clients.
+    first_heartbeat: Option<u32>,
+    rollback_ticks: u32,
+    rollback_ticks_per_sec: u32,
+    last_bytes: Option<(usize, usize)>,
+    pub bytes_up_per_sec: usize,
+    pub bytes_down_per_sec: usize,
+    /// As last reported by the server.
+    pub server_load: Option<ServerLoad>,
+}
+
+impl NetStats {
+    /// Fraction of the recent heartbeats that never arrived.
+    pub fn packet_loss(&self) -> f32 {
+        let (Some(&newest), Some(first)) = (self.heartbeats.iter().max(), self.first_heartbeat)
+        else {
+            return 0.0;
+        };
+        let expected = (newest.saturating_sub(first) + 1).min(HEARTBEAT_WINDOW);
+        1.0 - self.heartbeats.len() as f32 / expected as f32
+    }
+}
+
+fn send_heartbeats(
+    mut sequence: Local<u32>,
+    mut connection_manager: ResMut<ServerConnectionManager>,
+) {
+    connection_manager
+        .send_message_to_target::<StatsChannel, _>(
+            &ServerMessage::Heartbeat(*sequence),
+            NetworkTarget::All,
+        )
+        .unwrap();
+    *sequence += 1;
+}
+
+/// How long the server's frames took since the load was last sent.
+#[derive(Resource, Default)]
+struct FrameTimes {
+    started: Option<Instant>,
+    total: Duration,
+    max: Duration,
+    frames: u32,
+}
+
+fn start_frame(mut frame_times: ResMut<FrameTimes>) {
+    frame_times.started = Some(Instant::now());
+}
+
+fn end_frame(mut frame_times: ResMut<FrameTimes>) {
+    let Some(started) = frame_times.started.take() else {
+        return;
+    };
+    let elapsed = started.elapsed();
+    frame_times.total += elapsed;
+    frame_times.max = frame_times.max.max(elapsed);
+    frame_times.frames += 1;
+}
+
+fn send_server_load(
+    mut frame_times: ResMut<FrameTimes>,
+    replicated: Query<(), With<Replicating>>,
+    mut connection_manager: ResMut<ServerConnectionManager>,
+) {
+    let FrameTimes {
+        total, max, frames, ..
+    } = std::mem::take(&mut *frame_times);
+    let load = ServerLoad {
+        frame_us: (total / frames.max(1)).as_micros() as u32,
+        max_frame_us: max.as_micros() as u32,
+        entities: replicated.iter().count() as u32,
+    };
+    connection_manager
+        .send_message_to_target::<StatsChannel, _>(&ServerMessage::Load(load), NetworkTarget::All)
+        .unwrap();
+}
+
+fn receive_stats(
+    mut message_reader: EventReader<ClientMessageEvent<ServerMessage>>,
+    mut stats: ResMut<NetStats>,
+) {
+    for event in message_reader.read() {
+        let sequence = match event.message {
+            ServerMessage::Heartbeat(sequence) => sequence,
+            ServerMessage::Load(load) => {
+                stats.server_load = Some(load);
+                continue;
+            }
+            _ => continue,
+        };
+        stats.first_heartbeat.get_or_insert(sequence);
+        stats.heartbeats.push_back(sequence);
+        let newest = stats.heartbeats.iter().copied().max().unwrap_or(sequence);
+        stats
+            .heartbeats
+            .retain(|&old| old + HEARTBEAT_WINDOW > newest);
+    }
+}
+
+/// Every connection starts out with clean numbers, or a reconnect would read as all the packets
+/// in between being lost.
+fn reset_net_stats(mut stats: ResMut<NetStats>) {
+    *stats = NetStats::default();
+}
+
+fn count_rollback_ticks(mut stats: ResMut<NetStats>) {
+    stats.rollback_ticks += 1;
+}
+
+fn sample_net_stats(mut stats: ResMut<NetStats>, connection: Res<ClientConnection>) {
+    stats.rollback_ticks_per_sec = std::mem::take(&mut stats.rollback_ticks);
+
+    let Some(io_stats) = connection.io().map(|io| io.stats()) else {
+        return;
+    };
+    let bytes = (io_stats.bytes_sent, io_stats.bytes_received);
+    if let Some((sent, received)) = stats.last_bytes {
+        stats.bytes_up_per_sec = bytes.0.saturating_sub(sent);
+        stats.bytes_down_per_sec = bytes.1.saturating_sub(received);
+    }
+    stats.last_bytes = Some(bytes);
+}
+
+fn show_net_stats(
+    mut contexts: EguiContexts,
+    stats: Res<NetStats>,
+    connection_manager: Res<ClientConnectionManager>,
+    client_config: Res<ClientConfig>,
+    predicted: Query<(), With<Predicted>>,
+    interpolated: Query<(), With<Interpolated>>,
+    confirmed: Query<(), With<Confirmed>>,
+) {
+    // Measured by this client, so it's up to date rather than what the server last sent
+    let ping_manager = &connection_manager.ping_manager;
+    let input_delay = client_config.prediction.minimum_input_delay_ticks;
+    let tick_ms = shared_config(Mode::Separate)
+        .tick
+        .tick_duration
+        .as_secs_f32()
+        * 1000.0;
+
+    bevy_egui::egui::Window::new("Network")
+        .anchor(Align2::RIGHT_CENTER, (0.0, 0.0))
+        .resizable([false, false])
+        .collapsible(false)
+        .show(contexts.ctx_mut(), |ui| {
+            Grid::new("net_stats").num_columns(2).show(ui, |ui| {
+                let rows = [
+                    ("RTT", format!("{} ms", ping_manager.rtt().as_millis())),
+                    (
+                        "Jitter",
+                        format!("{} ms", ping_manager.jitter().as_millis()),
+                    ),
+                    (
+                        "Packet loss",
+                        format!("{:.1} %", stats.packet_loss() * 100.0),
+                    ),
+                    (
+                        "Input delay",
+                        format!(
+                            "{input_delay} ticks ({:.0} ms)",
+                            input_delay as f32 * tick_ms
+                        ),
+                    ),
+                    (
+                        "Rollbacks",
+                        format!("{} ticks/s", stats.rollback_ticks_per_sec),
+                    ),
+                    (
+                        "Server frame",
+                        stats.server_load.map_or("-".into(), |load| {
+                            format!(
+                                "{:.1} ms (max {:.1} ms)",
+                                load.frame_us as f32 / 1000.0,
+                                load.max_frame_us as f32 / 1000.0
+                            )
+                        }),
+                    ),
+                    ("Upload", format_rate(stats.bytes_up_per_sec)),
+                    ("Download", format_rate(stats.bytes_down_per_sec)),
+                    ("Predicted", predicted.iter().count().to_string()),
+                    ("Interpolated", interpolated.iter().count().to_string()),
+                    ("Confirmed", confirmed.iter().count().to_string()),
+                ];
+                for (label, value) in rows {
+                    ui.label(label);
+                    ui.label(value);
+                    ui.end_row();
+                }
+            });
+        });
+}
+
+pub fn format_rate(bytes_per_sec: usize) -> String {
+    if bytes_per_sec >= 1024 {
+        format!("{:.1} KiB/s", bytes_per_sec as f32 / 1024.0)
+    } else {
+        format!("{bytes_per_sec} B/s")
+    }
+}
@@ -1,34 +1,58 @@
-use lightyear::prelude::SteamworksClient;
-use parking_lot::RwLock;
 use std::net::SocketAddr;
-use std::sync::Arc;
 
 use bevy::prelude::*;
 use bevy_egui::EguiContexts;
-use bevy_egui::egui::{Align2, Style, TextEdit};
-use steamworks::{FriendFlags, SteamId};
+use bevy_egui::egui::{Align2, Color32, Style, TextEdit};
 
-#[derive(Resource, Deref)]
-pub struct SteamClient(pub Arc<RwLock<SteamworksClient>>);
+use crate::discovery::{LanServers, show_lan_servers};
+use crate::game::Role;
+use crate::game::handshake::BuildInfo;
+use crate::game::player::PlayerName;
+use crate::platform::{FriendId, Friends, Platform};
+use crate::save::{LoadMatch, SavedMatches};
+use crate::spectator::JoinAs;
+use crate::token_service::TokenServicePort;
+
+/// The name this client asks the server to display for it.
+#[derive(Resource, Deref, DerefMut, Default)]
+pub struct LocalPlayerName(pub String);
 
 pub struct NetworkingPlugin;
 
 impl Plugin for NetworkingPlugin {
     fn build(&self, app: &mut App) {
-        app.init_state::<NetworkState>().add_systems(
-            Update,
-            show_networking_menu.run_if(in_state(NetworkState::Disconnected)),
-        );
+        app.init_state::<NetworkState>()
+            .init_resource::<LocalPlayerName>()
+            .init_resource::<DisconnectReason>()
+            .add_systems(OnExit(NetworkState::Disconnected), clear_disconnect_reason)
+            .add_systems(
+                Update,
+                show_networking_menu.run_if(in_state(NetworkState::Disconnected)),
+            );
     }
 }
 
+fn clear_disconnect_reason(mut disconnect_reason: ResMut<DisconnectReason>) {
+    disconnect_reason.0 = None;
+}
+
+#[allow(clippy::too_many_arguments)]
 pub fn show_networking_menu(
     mut locals: Local<Option<String>>,
     mut contexts: EguiContexts,
-    steam_client: Res<SteamClient>,
+    platform: Res<Platform>,
+    friends: Res<Friends>,
+    mut local_name: ResMut<LocalPlayerName>,
+    mut join_as: ResMut<JoinAs>,
+    disconnect_reason: Res<DisconnectReason>,
+    lan_servers: Res<LanServers>,
+    saved_matches: Res<SavedMatches>,
+    mut load_match: EventWriter<LoadMatch>,
+    mut token_port: ResMut<TokenServicePort>,
     mut next_network_state: ResMut<NextState<NetworkState>>,
 ) {
     let addr = locals.get_or_insert("127.0.0.1:5000".into());
+    let host_addr = addr.parse::<SocketAddr>().ok();
     bevy_egui::egui::Window::new("Network menu")
         .anchor(Align2::CENTER_CENTER, (0.0, 0.0))
         .default_size((400.0, 300.0))
@@ -41,13 +65,35 @@ pub fn show_networking_menu(
             };
             ui.set_style(style);
 
+            if let Some(reason) = &disconnect_reason.0 {
+                ui.colored_label(Color32::from_rgb(255, 80, 80), reason);
+                ui.separator();
+            }
+
             ui.horizontal(|ui| {
                 ui.vertical(|ui| {
+                    ui.horizontal(|ui| {
+                        ui.label("Name");
+                        ui.add_sized(
+                            (150.0, 20.0),
+                            TextEdit::singleline(&mut local_name.0).char_limit(PlayerName::MAX_LEN),
+                        );
+                    });
+
                     ui.horizontal(|ui| {
                         ui.label("Address");
                         ui.add_sized((150.0, 20.0), TextEdit::singleline(addr));
                     });
 
+                    let mut spectate = join_as.0 == Role::Spectator;
+                    if ui.checkbox(&mut spectate, "Join as spectator").changed() {
+                        join_as.0 = if spectate {
+                            Role::Spectator
+                        } else {
+                            Role::Player
+                        };
+                    }
+
                     ui.horizontal(|ui| match addr.parse::<SocketAddr>() {
                         Ok(addr) => {
                             if ui.button("Host").clicked() {
@@ -69,42 +115,79 @@ pub fn show_networking_menu(
                 ui.separator();
 
                 ui.vertical(|ui| {
-                    ui.label("Steam friends");
-                    for friend in steam_client
-                        .read()
-                        .get_client()
-                        .friends()
-                        .get_friends(FriendFlags::all())
-                    {
-                        if let Some(game_info) = friend.game_played() {
-                            if game_info.game.app_id().0 == 480
-                                && ui.button(friend.name()).clicked()
-                            {
-                                next_network_state.set(NetworkState::ClientSteam {
-                                    friend: friend.id(),
-                                });
-                            }
+                    ui.label(format!("{} friends", platform.name()));
+                    if !friends.iter().any(|friend| friend.lobby.is_some()) {
+                        ui.label("No friends in game");
+                    }
+                    for friend in friends.iter() {
+                        if let Some(lobby) = friend.lobby
+                            && ui.button(&friend.name).clicked()
+                        {
+                            platform.join_lobby(lobby);
                         }
                     }
                 });
+
+                ui.separator();
+
+                ui.vertical(|ui| {
+                    ui.label("LAN games");
+                    if let Some((server_addr, port)) = show_lan_servers(ui, &lan_servers) {
+                        token_port.0 = port;
+                        next_network_state.set(NetworkState::Client {
+                            server_addr,
+                            client_id: rand::random(),
+                        });
+                    }
+                });
+
+                if !saved_matches.is_empty() {
+                    ui.separator();
+
+                    ui.vertical(|ui| {
+                        ui.label("Continue a match");
+                        for saved in saved_matches.iter() {
+                            let button = ui.add_enabled(
+                                host_addr.is_some(),
+                                bevy_egui::egui::Button::new(&saved.name),
+                            );
+                            if let (true, Some(addr)) = (button.clicked(), host_addr) {
+                                load_match.send(LoadMatch {
+                                    path: saved.path.clone(),
+                                    addr,
+                                });
+                            }
+                        }
+                    });
+                }
             });
+
+            ui.separator();
+            ui.small(format!("Version {}", BuildInfo::local()));
         });
 }
 
+/// Why we last ended up back in the menu, shown there until the next attempt to connect.
+#[derive(Resource, Default)]
+pub struct DisconnectReason(pub Option<String>);
+
 #[derive(Debug, Clone, Copy, Default, Eq, PartialEq, Hash, States)]
 pub enum NetworkState {
     #[default]
     Disconnected,
     Host(SocketAddr),
-    #[expect(unused)]
     Server(SocketAddr),
     Client {
         server_addr: SocketAddr,
         client_id: u64,
     },
-    ClientSteam {
-        friend: SteamId,
+    /// Connected to a friend through the platform, see [`crate::platform`].
+    ClientP2p {
+        friend: FriendId,
+        virtual_port: i32,
     },
+    /// Watching a recorded match, see [`crate::replay`].
+    Replay,
 }
 
 #[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
@@ -116,7 +199,7 @@ impl ComputedStates for IsClient {
     fn compute(network_state: NetworkState) -> Option<Self> {
         use NetworkState::*;
         match network_state {
-            Host { .. } | Client { .. } | ClientSteam { .. } => Some(Self),
+            Host { .. } | Client { .. } | ClientP2p { .. } => Some(Self),
             _ => None,
         }
     }
@@ -0,0 +1,304 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use bevy::prelude::*;
+use bevy::time::common_conditions::on_timer;
+use lightyear::prelude::{LinkConditionerConfig, client, server};
+
+use crate::discovery::{ServerInfo, ServerName, local_server_info};
+use crate::game::handshake::BuildInfo;
+use crate::networking::{DisconnectReason, IsServer, LocalPlayerName, NetworkState};
+
+#[cfg(feature = "steam")]
+pub mod steam;
+
+/// What the platform the game was started from offers: who the player is, their friends, lobbies
+/// to bring them into a game, and connections to them. Everything is optional, so the game runs
+/// the same without a platform, e.g. on a dedicated server or in tests.
+pub trait PlatformServices: Send + Sync + 'static {
+    /// Name of the platform, to show next to what it provides.
+    fn name(&self) -> &'static str;
+
+    /// The name the player goes by on the platform.
+    fn persona_name(&self) -> Option<String>;
+
+    /// Friends that are online right now.
+    fn friends(&self) -> Vec<Friend>;
+
+    /// Invites a friend to the lobby this player is in.
+    fn invite(&self, friend: FriendId) -> Result<(), String>;
+
+    /// Lets the platform do its work, returning what happened since the last update.
+    fn update(&self) -> Vec<PlatformEvent>;
+
+    /// Opens a lobby for the game this player hosts. Answered with [`PlatformEvent::LobbyCreated`]
+    /// or [`PlatformEvent::LobbyFailed`].
+    fn create_lobby(&self, settings: &MatchSettings);
+
+    /// Answered with [`PlatformEvent::LobbyJoined`] or [`PlatformEvent::LobbyFailed`].
+    fn join_lobby(&self, lobby: LobbyId);
+
+    /// Leaves the lobby this player is in, if any.
+    fn leave_lobby(&self);
+
+    /// How to connect to a friend's game through the platform's relays.
+    fn client_net_config(
+        &self,
+        friend: FriendId,
+        virtual_port: i32,
+        conditioner: Option<LinkConditionerConfig>,
+    ) -> Option<client::NetConfig>;
+
+    /// How to accept connections from friends through the platform's relays, next to the
+    /// regular UDP ones.
+    fn server_net_config(
+        &self,
+        conditioner: Option<LinkConditionerConfig>,
+    ) -> Option<server::NetConfig>;
+}
+
+/// Identifies a player on the platform, e.g. a Steam id.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+pub struct FriendId(pub u64);
+
+/// Identifies a lobby on the platform.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+pub struct LobbyId(pub u64);
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Friend {
+    pub id: FriendId,
+    pub name: String,
+    /// The lobby of the game they're in, if they're playing this game and it can be joined.
+    pub lobby: Option<LobbyId>,
+}
+
+/// What a lobby tells the friends joining it about the game.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MatchSettings {
+    pub name: String,
+    pub map: String,
+    pub max_players: u32,
+    pub build: BuildInfo,
+}
+
+impl From<ServerInfo> for MatchSettings {
+    fn from(info: ServerInfo) -> Self {
+        Self {
+            name: info.name,
+            map: info.map,
+            max_players: info.max_players,
+            build: info.build,
+        }
+    }
+}
+
+/// Only platforms with lobbies send these, and without Steam only the tests' mock platform has them.
+#[derive(Event, Debug, Clone, PartialEq)]
+#[cfg_attr(not(any(feature = "steam", test)), expect(dead_code))]
+pub enum PlatformEvent {
+    LobbyCreated(LobbyId),
+    /// The player accepted an invite, or picked a friend's game in the platform's overlay.
+    JoinRequested(LobbyId),
+    LobbyJoined {
+        lobby: LobbyId,
+        host: FriendId,
+        virtual_port: i32,
+        settings: MatchSettings,
+    },
+    LobbyFailed(String),
+}
+
+/// What invites ask the friend's game to do, followed by the lobby id. Steam passes the same on
+/// the command line to games it starts for an invite.
+pub const CONNECT_LOBBY: &str = "+connect_lobby";
+
+/// Reads the lobby out of a [`CONNECT_LOBBY`] connect string.
+pub fn parse_connect_lobby(connect: &str) -> Option<LobbyId> {
+    let mut args = connect.split_whitespace();
+    args.find(|&arg| arg == CONNECT_LOBBY)?;
+    args.next()?.parse().ok().map(LobbyId)
+}
+
+/// The platform this app was started with.
+#[derive(Resource, Clone, Deref)]
+pub struct Platform(pub Arc<dyn PlatformServices>);
+
+impl Platform {
+    /// Steam with the given app id when it's compiled in, running, and `headless` isn't set,
+    /// otherwise no platform.
+    #[cfg_attr(not(feature = "steam"), expect(unused_variables))]
+    pub fn detect(headless: bool, steam_app_id: u32) -> Self {
+        #[cfg(feature = "steam")]
+        if !headless {
+            match steam::SteamPlatform::new(steam_app_id) {
+                Ok(steam) => return Self(Arc::new(steam)),
+                Err(err) => warn!("Steam is not available, playing without it: {err}"),
+            }
+        }
+        Self(Arc::new(NoPlatform))
+    }
+}
+
+/// Playing without a platform: no friends or lobbies, and connections only go over UDP.
+pub struct NoPlatform;
+
+impl PlatformServices for NoPlatform {
+    fn name(&self) -> &'static str {
+        "Offline"
+    }
+
+    fn persona_name(&self) -> Option<String> {
+        None
+    }
+
+    fn friends(&self) -> Vec<Friend> {
+        Vec::new()
+    }
+
+    fn invite(&self, _friend: FriendId) -> Result<(), String> {
+        Err("Not connected to a platform that can send invites".into())
+    }
+
+    fn update(&self) -> Vec<PlatformEvent> {
+        Vec::new()
+    }
+
+    fn create_lobby(&self, _settings: &MatchSettings) {}
+
+    fn join_lobby(&self, _lobby: LobbyId) {}
+
+    fn leave_lobby(&self) {}
+
+    fn client_net_config(
+        &self,
+        _friend: FriendId,
+        _virtual_port: i32,
+        _conditioner: Option<LinkConditionerConfig>,
+    ) -> Option<client::NetConfig> {
+        None
+    }
+
+    fn server_net_config(
+        &self,
+        _conditioner: Option<LinkConditionerConfig>,
+    ) -> Option<server::NetConfig> {
+        None
+    }
+}
+
+/// Fills in the player name from the platform, keeps the list of friends up to date so the menus
+/// don't ask the platform every frame, and opens a lobby for friends to join while hosting.
+pub struct PlatformPlugin;
+
+impl Plugin for PlatformPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<Friends>()
+            .add_event::<PlatformEvent>()
+            .add_systems(Startup, use_persona_name)
+            .add_systems(
+                OnEnter(NetworkState::Disconnected),
+                (refresh_friends, leave_lobby),
+            )
+            .add_systems(OnEnter(IsServer), create_lobby)
+            .add_systems(
+                Update,
+                (
+                    refresh_friends.run_if(on_timer(FRIENDS_REFRESH_INTERVAL)),
+                    (update_platform, handle_lobby_events).chain(),
+                ),
+            );
+    }
+}
+
+const FRIENDS_REFRESH_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Friends that are online, as of the last refresh.
+#[derive(Resource, Default, Deref)]
+pub struct Friends(Vec<Friend>);
+
+fn use_persona_name(platform: Res<Platform>, mut local_name: ResMut<LocalPlayerName>) {
+    if local_name.is_empty()
+        && let Some(name) = platform.persona_name()
+    {
+        local_name.0 = name;
+    }
+}
+
+fn refresh_friends(platform: Res<Platform>, mut friends: ResMut<Friends>) {
+    let refreshed = platform.friends();
+    if friends.0 != refreshed {
+        friends.0 = refreshed;
+    }
+}
+
+fn update_platform(platform: Res<Platform>, mut events: EventWriter<PlatformEvent>) {
+    events.send_batch(platform.update());
+}
+
+fn create_lobby(
+    platform: Res<Platform>,
+    network_state: Res<State<NetworkState>>,
+    server_name: Res<ServerName>,
+    local_name: Res<LocalPlayerName>,
+) {
+    // Dedicated servers are found through the server browser instead
+    if !matches!(network_state.get(), NetworkState::Host(_)) {
+        return;
+    }
+    if let Some(info) = local_server_info(network_state.get(), &server_name, &local_name, 0, 0) {
+        platform.create_lobby(&info.into());
+    }
+}
+
+fn leave_lobby(platform: Res<Platform>) {
+    platform.leave_lobby();
+}
+
+fn handle_lobby_events(
+    mut events: EventReader<PlatformEvent>,
+    platform: Res<Platform>,
+    network_state: Res<State<NetworkState>>,
+    mut disconnect_reason: ResMut<DisconnectReason>,
+    mut next_network_state: ResMut<NextState<NetworkState>>,
+) {
+    for event in events.read() {
+        match event {
+            PlatformEvent::LobbyCreated(lobby) => debug!(lobby = lobby.0, "Lobby open"),
+            &PlatformEvent::JoinRequested(lobby) => {
+                if *network_state.get() == NetworkState::Disconnected {
+                    info!(lobby = lobby.0, "Joining lobby");
+                    platform.join_lobby(lobby);
+                } else {
+                    warn!(
+                        lobby = lobby.0,
+                        "Leave the current match to join another lobby"
+                    );
+                }
+            }
+            PlatformEvent::LobbyJoined {
+                host,
+                virtual_port,
+                settings,
+                ..
+            } => {
+                if !BuildInfo::local().is_compatible(&settings.build) {
+                    let reason = BuildInfo::mismatch_reason(&settings.build);
+                    warn!("{reason}");
+                    disconnect_reason.0 = Some(reason);
+                    platform.leave_lobby();
+                    continue;
+                }
+                info!(name = %settings.name, map = %settings.map, "Joined lobby, connecting to the host");
+                next_network_state.set(NetworkState::ClientP2p {
+                    friend: *host,
+                    virtual_port: *virtual_port,
+                });
+            }
+            PlatformEvent::LobbyFailed(reason) => {
+                warn!("{reason}");
+                disconnect_reason.0 = Some(reason.clone());
+            }
+        }
+    }
+}
@@ -0,0 +1,263 @@
+use std::ffi::{CStr, c_void};
+use std::sync::Arc;
+
+use bevy::log::info;
+use lightyear::prelude::{LinkConditionerConfig, SteamworksClient, client, server};
+use parking_lot::{Mutex, RwLock};
+use steamworks::{
+    Callback, CallbackHandle, ClientManager, FriendFlags, FriendState, GameLobbyJoinRequested,
+    LobbyType, SteamId, sys,
+};
+
+use super::{
+    CONNECT_LOBBY, Friend, FriendId, LobbyId, MatchSettings, PlatformEvent, PlatformServices,
+    parse_connect_lobby,
+};
+use crate::game::handshake::BuildInfo;
+
+/// Steam P2P port the server listens on, which is put in the lobby for clients to connect to.
+const VIRTUAL_PORT: i32 = 5001;
+
+pub struct SteamPlatform {
+    client: Arc<RwLock<SteamworksClient>>,
+    app_id: u32,
+    state: Arc<Mutex<SteamState>>,
+    _callbacks: Vec<CallbackHandle>,
+}
+
+/// What Steam's callbacks have told us, until the game picks it up.
+#[derive(Default)]
+struct SteamState {
+    lobby: Option<LobbyId>,
+    events: Vec<PlatformEvent>,
+}
+
+impl SteamPlatform {
+    /// Connects to the running Steam client, failing if there is none.
+    pub fn new(app_id: u32) -> Result<Self, String> {
+        // Lightyear panics when Steam isn't running, so find out first. Steam counts
+        // initializations, so this one only has to live until lightyear's has been made
+        let _probe = steamworks::Client::<ClientManager>::init_app(app_id)
+            .map_err(|err| format!("Could not connect to the Steam client: {err}"))?;
+        let client = SteamworksClient::new_with_app_id(app_id);
+        let steam = client.get_client();
+
+        let state = Arc::<Mutex<SteamState>>::default();
+        // Joining a friend from the overlay or the friends list
+        let join_state = state.clone();
+        let join_requested = steam.register_callback(move |request: GameLobbyJoinRequested| {
+            join_state
+                .lock()
+                .events
+                .push(PlatformEvent::JoinRequested(LobbyId(
+                    request.lobby_steam_id.raw(),
+                )));
+        });
+        // Accepting an invite sent while the game was already running
+        let invite_state = state.clone();
+        let invite_accepted =
+            steam.register_callback(move |request: GameRichPresenceJoinRequested| {
+                if let Some(lobby) = parse_connect_lobby(&request.connect) {
+                    invite_state
+                        .lock()
+                        .events
+                        .push(PlatformEvent::JoinRequested(lobby));
+                }
+            });
+
+        Ok(Self {
+            client: Arc::new(RwLock::new(client)),
+            app_id,
+            state,
+            _callbacks: vec![join_requested, invite_accepted],
+        })
+    }
+}
+
+/// An invite accepted while the game was already running, which steamworks doesn't wrap.
+struct GameRichPresenceJoinRequested {
+    connect: String,
+}
+
+unsafe impl Callback for GameRichPresenceJoinRequested {
+    const ID: i32 = sys::GameRichPresenceJoinRequested_t_k_iCallback as i32;
+    const SIZE: i32 = std::mem::size_of::<sys::GameRichPresenceJoinRequested_t>() as i32;
+
+    unsafe fn from_raw(raw: *mut c_void) -> Self {
+        // SAFETY: Steam hands callbacks with this id a `GameRichPresenceJoinRequested_t`, whose
+        // connect string is nul terminated
+        let request = unsafe { &*(raw as *const sys::GameRichPresenceJoinRequested_t) };
+        let connect = unsafe { CStr::from_ptr(request.m_rgchConnect.as_ptr()) };
+        Self {
+            connect: connect.to_string_lossy().into_owned(),
+        }
+    }
+}
+
+impl PlatformServices for SteamPlatform {
+    fn name(&self) -> &'static str {
+        "Steam"
+    }
+
+    fn persona_name(&self) -> Option<String> {
+        Some(self.client.read().get_client().friends().name())
+    }
+
+    fn friends(&self) -> Vec<Friend> {
+        self.client
+            .read()
+            .get_client()
+            .friends()
+            .get_friends(FriendFlags::IMMEDIATE)
+            .into_iter()
+            .filter(|friend| friend.state() != FriendState::Offline)
+            .map(|friend| Friend {
+                id: FriendId(friend.id().raw()),
+                name: friend.name(),
+                lobby: friend
+                    .game_played()
+                    .filter(|game| game.game.app_id().0 == self.app_id && game.lobby.raw() != 0)
+                    .map(|game| LobbyId(game.lobby.raw())),
+            })
+            .collect()
+    }
+
+    fn invite(&self, friend: FriendId) -> Result<(), String> {
+        let lobby = self
+            .state
+            .lock()
+            .lobby
+            .ok_or("There is no lobby to invite to yet")?;
+        let client = self.client.read().get_client();
+        client
+            .friends()
+            .get_friend(SteamId::from_raw(friend.0))
+            .invite_user_to_game(&format!("{CONNECT_LOBBY} {}", lobby.0));
+        Ok(())
+    }
+
+    fn update(&self) -> Vec<PlatformEvent> {
+        self.client.write().get_single().run_callbacks();
+        std::mem::take(&mut self.state.lock().events)
+    }
+
+    fn create_lobby(&self, settings: &MatchSettings) {
+        let client = self.client.read().get_client();
+        let state = self.state.clone();
+        let settings = settings.clone();
+        let matchmaking = client.matchmaking();
+        matchmaking.create_lobby(LobbyType::FriendsOnly, settings.max_players, move |lobby| {
+            let event = match lobby {
+                Ok(lobby) => {
+                    let matchmaking = client.matchmaking();
+                    for (key, value) in [
+                        ("virtual_port", VIRTUAL_PORT.to_string()),
+                        ("name", settings.name),
+                        ("map", settings.map),
+                        ("max_players", settings.max_players.to_string()),
+                        ("version", settings.build.version),
+                        (
+                            "protocol_id",
+                            format!("{:016x}", settings.build.protocol_id),
+                        ),
+                    ] {
+                        matchmaking.set_lobby_data(lobby, key, &value);
+                    }
+                    info!(lobby = lobby.raw(), "Created Steam lobby");
+                    state.lock().lobby = Some(LobbyId(lobby.raw()));
+                    PlatformEvent::LobbyCreated(LobbyId(lobby.raw()))
+                }
+                Err(err) => {
+                    PlatformEvent::LobbyFailed(format!("Could not create a Steam lobby: {err}"))
+                }
+            };
+            state.lock().events.push(event);
+        });
+    }
+
+    fn join_lobby(&self, lobby: LobbyId) {
+        let client = self.client.read().get_client();
+        let state = self.state.clone();
+        let matchmaking = client.matchmaking();
+        matchmaking.join_lobby(steamworks::LobbyId::from_raw(lobby.0), move |joined| {
+            let event = match joined {
+                Ok(joined) => {
+                    let matchmaking = client.matchmaking();
+                    let data = |key| {
+                        matchmaking
+                            .lobby_data(joined, key)
+                            .map(|value| value.to_string())
+                            .unwrap_or_default()
+                    };
+                    let settings = MatchSettings {
+                        name: data("name"),
+                        map: data("map"),
+                        max_players: data("max_players").parse().unwrap_or_default(),
+                        build: BuildInfo {
+                            version: data("version"),
+                            protocol_id: u64::from_str_radix(&data("protocol_id"), 16)
+                                .unwrap_or_default(),
+                        },
+                    };
+                    state.lock().lobby = Some(lobby);
+                    PlatformEvent::LobbyJoined {
+                        lobby,
+                        host: FriendId(matchmaking.lobby_owner(joined).raw()),
+                        virtual_port: data("virtual_port").parse().unwrap_or(VIRTUAL_PORT),
+                        settings,
+                    }
+                }
+                Err(()) => PlatformEvent::LobbyFailed("Could not join the Steam lobby".into()),
+            };
+            state.lock().events.push(event);
+        });
+    }
+
+    fn leave_lobby(&self) {
+        let Some(lobby) = self.state.lock().lobby.take() else {
+            return;
+        };
+        info!(lobby = lobby.0, "Leaving Steam lobby");
+        self.client
+            .read()
+            .get_client()
+            .matchmaking()
+            .leave_lobby(steamworks::LobbyId::from_raw(lobby.0));
+    }
+
+    fn client_net_config(
+        &self,
+        friend: FriendId,
+        virtual_port: i32,
+        conditioner: Option<LinkConditionerConfig>,
+    ) -> Option<client::NetConfig> {
+        Some(client::NetConfig::Steam {
+            steamworks_client: Some(self.client.clone()),
+            config: client::SteamConfig {
+                socket_config: client::SocketConfig::P2P {
+                    virtual_port,
+                    steam_id: friend.0,
+                },
+                app_id: self.app_id,
+            },
+            conditioner,
+        })
+    }
+
+    fn server_net_config(
+        &self,
+        conditioner: Option<LinkConditionerConfig>,
+    ) -> Option<server::NetConfig> {
+        Some(server::NetConfig::Steam {
+            steamworks_client: Some(self.client.clone()),
+            config: server::SteamConfig {
+                app_id: self.app_id,
+                socket_config: server::SocketConfig::P2P {
+                    virtual_port: VIRTUAL_PORT,
+                },
+                ..Default::default()
+            },
+            conditioner,
+        })
+    }
+}
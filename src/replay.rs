@@ -0,0 +1,332 @@
+use std::fs::File;
+use std::io::{BufReader, BufWriter, ErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use bevy::prelude::*;
+use bevy_egui::EguiContexts;
+use bevy_egui::egui::{ComboBox, Slider, TopBottomPanel};
+use lightyear::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::discovery::MAP_NAME;
+use crate::game::handshake::BuildInfo;
+use crate::game::snapshot::{
+    Playback, SNAPSHOT_INTERVAL, Snapshot, SnapshotSource, Timeline, apply_snapshot,
+};
+use crate::game::{ClientMessage, InputHandling, shared_config};
+use crate::networking::{IsServer, NetworkState};
+use crate::spectator::Spectating;
+
+/// Records matches on the server and plays them back in [`NetworkState::Replay`].
+///
+/// A replay holds the same snapshots spectators are sent, so seeking anywhere is as cheap as
+/// playing. The messages clients sent are recorded next to them, to see what led up to a bug.
+pub struct ReplayPlugin;
+
+impl Plugin for ReplayPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<RecordReplays>()
+            .add_systems(OnEnter(IsServer), start_recording)
+            .add_systems(OnExit(IsServer), stop_recording)
+            .add_systems(
+                FixedUpdate,
+                (record_messages, record_snapshot)
+                    .chain()
+                    .after(InputHandling)
+                    .run_if(resource_exists::<ReplayRecorder>),
+            )
+            .add_systems(OnEnter(NetworkState::Replay), start_playback)
+            .add_systems(
+                Update,
+                (show_playback_controls, advance_playback)
+                    .chain()
+                    .before(apply_snapshot)
+                    .run_if(in_state(NetworkState::Replay)),
+            );
+    }
+}
+
+/// Directory replays are recorded to.
+pub const REPLAY_DIR: &str = "replays";
+const MAGIC: &[u8] = b"AOEREPLAY1";
+const SPEEDS: [f32; 5] = [0.5, 1.0, 2.0, 4.0, 8.0];
+
+/// Whether servers started by this app record their matches.
+#[derive(Resource, Clone, Copy, Debug, Default)]
+pub struct RecordReplays(pub bool);
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ReplayHeader {
+    pub build: BuildInfo,
+    pub map: String,
+    /// Seconds since the Unix epoch.
+    pub started_at: u64,
+    pub tick_duration: f64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+enum Record {
+    Snapshot(Snapshot),
+    Message {
+        tick: u16,
+        from: ClientId,
+        message: ClientMessage,
+    },
+}
+
+#[derive(Resource)]
+struct ReplayRecorder {
+    path: PathBuf,
+    writer: BufWriter<File>,
+}
+
+impl ReplayRecorder {
+    fn create(path: PathBuf) -> Result<Self, String> {
+        let write = || -> std::io::Result<BufWriter<File>> {
+            std::fs::create_dir_all(REPLAY_DIR)?;
+            let mut writer = BufWriter::new(File::create(&path)?);
+            writer.write_all(MAGIC)?;
+            Ok(writer)
+        };
+        let mut writer = write().map_err(|err| format!("{}: {err}", path.display()))?;
+
+        let started_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let header = ReplayHeader {
+            build: BuildInfo::local(),
+            map: MAP_NAME.into(),
+            started_at,
+            tick_duration: shared_config(Mode::HostServer)
+                .tick
+                .tick_duration
+                .as_secs_f64(),
+        };
+        bincode::serialize_into(&mut writer, &header)
+            .map_err(|err| format!("{}: {err}", path.display()))?;
+        Ok(Self { path, writer })
+    }
+
+    fn write(&mut self, record: &Record) -> Result<(), String> {
+        bincode::serialize_into(&mut self.writer, record)
+            .map_err(|err| format!("{}: {err}", self.path.display()))
+    }
+}
+
+fn start_recording(mut commands: Commands, record: Res<RecordReplays>) {
+    if !record.0 {
+        return;
+    }
+    let started_at = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let path = Path::new(REPLAY_DIR).join(format!("{started_at}.replay"));
+    match ReplayRecorder::create(path) {
+        Ok(recorder) => {
+            info!(path = %recorder.path.display(), "Recording replay");
+            commands.insert_resource(recorder);
+        }
+        Err(err) => warn!("Could not start recording a replay: {err}"),
+    }
+}
+
+fn stop_recording(mut commands: Commands, recorder: Option<ResMut<ReplayRecorder>>) {
+    let Some(mut recorder) = recorder else {
+        return;
+    };
+    match recorder.writer.flush() {
+        Ok(()) => info!(path = %recorder.path.display(), "Saved replay"),
+        Err(err) => warn!(path = %recorder.path.display(), "Could not save replay: {err}"),
+    }
+    commands.remove_resource::<ReplayRecorder>();
+}
+
+fn record_messages(
+    mut commands: Commands,
+    mut recorder: ResMut<ReplayRecorder>,
+    mut message_reader: EventReader<ServerMessageEvent<ClientMessage>>,
+    tick_manager: Res<TickManager>,
+) {
+    let tick = tick_manager.tick().0;
+    for event in message_reader.read() {
+        let record = Record::Message {
+            tick,
+            from: event.from(),
+            message: event.message.clone(),
+        };
+        if let Err(err) = recorder.write(&record) {
+            warn!("Stopped recording the replay: {err}");
+            commands.remove_resource::<ReplayRecorder>();
+            return;
+        }
+    }
+}
+
+fn record_snapshot(
+    mut commands: Commands,
+    mut recorder: ResMut<ReplayRecorder>,
+    source: SnapshotSource,
+    tick_manager: Res<TickManager>,
+) {
+    let tick = tick_manager.tick().0;
+    if !tick.is_multiple_of(SNAPSHOT_INTERVAL) {
+        return;
+    }
+
+    if let Err(err) = recorder.write(&Record::Snapshot(source.take(tick))) {
+        warn!("Stopped recording the replay: {err}");
+        commands.remove_resource::<ReplayRecorder>();
+    }
+}
+
+/// A recorded match, read back from a replay file. Its snapshots are watched through
+/// [`Playback`].
+#[derive(Resource, Debug)]
+pub struct Replay {
+    pub header: ReplayHeader,
+    /// What clients sent, with the seconds from the start of the replay it was sent at.
+    messages: Vec<(f64, ClientId, ClientMessage)>,
+}
+
+impl Replay {
+    pub fn load(path: &Path) -> Result<(Self, Timeline), String> {
+        let error = |err: &dyn std::fmt::Display| format!("{}: {err}", path.display());
+        let mut reader = BufReader::new(File::open(path).map_err(|err| error(&err))?);
+
+        let mut magic = [0; MAGIC.len()];
+        reader.read_exact(&mut magic).map_err(|err| error(&err))?;
+        if magic != MAGIC {
+            return Err(error(&"Not a replay"));
+        }
+        let header: ReplayHeader =
+            bincode::deserialize_from(&mut reader).map_err(|err| error(&err))?;
+        if !BuildInfo::local().is_compatible(&header.build) {
+            warn!(
+                "Replay was recorded with version {}, it might not play back correctly",
+                header.build
+            );
+        }
+
+        let mut timeline = None::<Timeline>;
+        let mut messages = Vec::new();
+        loop {
+            match bincode::deserialize_from::<_, Record>(&mut reader) {
+                Ok(Record::Snapshot(snapshot)) => match &mut timeline {
+                    Some(timeline) => timeline.push(snapshot),
+                    None => timeline = Some(Timeline::new(snapshot, header.tick_duration)),
+                },
+                Ok(Record::Message {
+                    tick,
+                    from,
+                    message,
+                }) => {
+                    // Anything from before the first snapshot goes at the start
+                    let time = timeline
+                        .as_ref()
+                        .map_or(0.0, |timeline| timeline.time_at(tick));
+                    messages.push((time, from, message));
+                }
+                Err(err) => match *err {
+                    bincode::ErrorKind::Io(ref io) if io.kind() == ErrorKind::UnexpectedEof => {
+                        break;
+                    }
+                    // A server that crashed leaves half a record at the end
+                    _ if timeline.is_some() => {
+                        warn!("Replay ends early: {err}");
+                        break;
+                    }
+                    _ => return Err(error(&err)),
+                },
+            }
+        }
+        let timeline = timeline.ok_or_else(|| error(&"The replay is empty"))?;
+        Ok((Self { header, messages }, timeline))
+    }
+}
+
+fn start_playback(mut commands: Commands, replay: Res<Replay>, playback: Res<Playback>) {
+    let header = &replay.header;
+    info!(
+        version = %header.build,
+        map = %header.map,
+        seconds = playback.timeline().duration(),
+        "Playing replay"
+    );
+    // The spectator camera and player list work just as well for replays
+    commands.insert_resource(Spectating {
+        delay: default(),
+        following: None,
+    });
+}
+
+fn show_playback_controls(
+    mut contexts: EguiContexts,
+    replay: Res<Replay>,
+    mut playback: ResMut<Playback>,
+    keypress: Res<ButtonInput<KeyCode>>,
+    mut exit: EventWriter<AppExit>,
+) {
+    let ctx = contexts.ctx_mut();
+    let duration = playback.timeline().duration();
+    if keypress.just_pressed(KeyCode::Space) && !ctx.wants_keyboard_input() {
+        playback.toggle_pause();
+    }
+
+    TopBottomPanel::bottom("replay").show(ctx, |ui| {
+        let playback = &mut *playback;
+        ui.horizontal(|ui| {
+            let label = if playback.paused { "Play" } else { "Pause" };
+            if ui.button(label).clicked() {
+                playback.toggle_pause();
+            }
+            ComboBox::from_id_salt("speed")
+                .selected_text(format!("{}x", playback.speed))
+                .show_ui(ui, |ui| {
+                    for speed in SPEEDS {
+                        ui.selectable_value(&mut playback.speed, speed, format!("{speed}x"));
+                    }
+                });
+            ui.spacing_mut().slider_width = (ui.available_width() - 200.0).max(100.0);
+            let position = format!("{} / {}", format_time(playback.time), format_time(duration));
+            ui.add(
+                Slider::new(&mut playback.time, 0.0..=duration)
+                    .show_value(false)
+                    .text(position),
+            );
+            if ui.button("Quit").clicked() {
+                exit.send(AppExit::Success);
+            }
+        });
+
+        // What players did just before now
+        let recent = replay
+            .messages
+            .iter()
+            .filter(|(time, ..)| *time <= playback.time && playback.time - *time < 5.0)
+            .rev()
+            .take(4)
+            .collect::<Vec<_>>();
+        for (time, from, message) in recent.into_iter().rev() {
+            ui.small(format!("{} {from}: {message:?}", format_time(*time)));
+        }
+    });
+}
+
+fn format_time(seconds: f64) -> String {
+    let seconds = seconds as u64;
+    format!("{}:{:02}", seconds / 60, seconds % 60)
+}
+
+fn advance_playback(mut playback: ResMut<Playback>, time: Res<Time>) {
+    if playback.paused {
+        return;
+    }
+    let duration = playback.timeline().duration();
+    playback.time = (playback.time + time.delta_secs_f64() * playback.speed as f64).min(duration);
+    if playback.time >= duration {
+        playback.paused = true;
+    }
+}
@@ -0,0 +1,532 @@
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use bevy::prelude::*;
+use bevy::utils::HashSet;
+use lightyear::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::discovery::{MAP_NAME, ServerName};
+use crate::game::chat::SystemChat;
+use crate::game::handshake::BuildInfo;
+use crate::game::minion::{MinionPosition, MinionTarget, UnitKind};
+use crate::game::player::{PlayerColor, PlayerId, PlayerName, PlayerPosition, Team};
+use crate::game::resource::{Apples, Item, ItemPos, Scoreboard};
+use crate::game::status::Health;
+use crate::game::{ClaimSecret, ClientMessage, InputHandling, OwnedBy, Role};
+use crate::networking::{IsServer, NetworkState};
+use crate::server::replicate_to_owner;
+
+/// Saves the match the server runs, and continues saved matches later.
+///
+/// Players that aren't back yet keep their slot: their units stay on the map, and when a client
+/// joins with their [`ClaimSecret`] it takes over where they left off.
+pub struct SavePlugin;
+
+impl Plugin for SavePlugin {
+    fn build(&self, app: &mut App) {
+        app.add_event::<SaveMatch>()
+            .add_event::<LoadMatch>()
+            .init_resource::<SavedMatches>()
+            .init_resource::<Autosave>()
+            .add_systems(OnEnter(NetworkState::Disconnected), refresh_saved_matches)
+            .add_systems(
+                Update,
+                (
+                    load_match.run_if(in_state(NetworkState::Disconnected)),
+                    (autosave, save_match).chain().run_if(in_state(IsServer)),
+                ),
+            )
+            .add_systems(
+                FixedUpdate,
+                claim_saved_slots
+                    .after(InputHandling)
+                    .run_if(resource_exists::<SavedSlots>),
+            )
+            .add_systems(OnExit(IsServer), forget_loaded_match);
+    }
+}
+
+/// Directory matches are saved to.
+pub const SAVE_DIR: &str = "saves";
+const MAGIC: &[u8] = b"AOESAVE";
+/// Bump whenever anything in [`SaveFile`] changes, saves of other versions are refused.
+const SAVE_VERSION: u32 = 2;
+const AUTOSAVE_NAME: &str = "autosave";
+
+/// Asks the server to save the match under the given name.
+#[derive(Event, Clone, Debug)]
+pub struct SaveMatch(pub String);
+
+impl SaveMatch {
+    /// Saves under a name of its own, so earlier saves are kept.
+    pub fn timestamped() -> Self {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        Self(format!("match-{now}"))
+    }
+}
+
+/// Asks to host the match saved in `path` on `addr`.
+#[derive(Event, Clone, Debug)]
+pub struct LoadMatch {
+    pub path: PathBuf,
+    pub addr: SocketAddr,
+}
+
+/// How often the server saves the match on its own, if at all.
+#[derive(Resource, Clone, Copy, Debug, Default)]
+pub struct Autosave(pub Option<Duration>);
+
+/// Everything needed to continue a match.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SaveFile {
+    /// Seconds since the Unix epoch.
+    pub saved_at: u64,
+    /// Only informative, the save version decides whether it can be loaded.
+    pub build: BuildInfo,
+    pub server_name: Option<String>,
+    pub map: String,
+    players: Vec<SavedPlayer>,
+    units: Vec<SavedUnit>,
+    items: Vec<(Item, ItemPos)>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct SavedPlayer {
+    client_id: ClientId,
+    claim: ClaimSecret,
+    name: PlayerName,
+    color: PlayerColor,
+    team: Team,
+    position: PlayerPosition,
+    points: u64,
+    apples: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct SavedUnit {
+    kind: UnitKind,
+    position: MinionPosition,
+    target: MinionTarget,
+    health: Health,
+    owner: OwnedBy,
+    color: PlayerColor,
+}
+
+impl SaveFile {
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let error = |err: &dyn std::fmt::Display| format!("{}: {err}", path.display());
+        let mut reader = BufReader::new(File::open(path).map_err(|err| error(&err))?);
+
+        let mut magic = [0; MAGIC.len()];
+        reader.read_exact(&mut magic).map_err(|err| error(&err))?;
+        if magic != MAGIC {
+            return Err(error(&"Not a saved match"));
+        }
+        let version: u32 = bincode::deserialize_from(&mut reader).map_err(|err| error(&err))?;
+        if version != SAVE_VERSION {
+            return Err(error(&format!(
+                "Saved by a version of the game that used save format {version}, this one uses \
+                 {SAVE_VERSION}"
+            )));
+        }
+        bincode::deserialize_from(&mut reader).map_err(|err| error(&err))
+    }
+
+    /// Writes to a temporary file first, so a failed save doesn't destroy the previous one.
+    pub fn save(&self, path: &Path) -> Result<(), String> {
+        let error = |err: &dyn std::fmt::Display| format!("{}: {err}", path.display());
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir).map_err(|err| error(&err))?;
+        }
+        let temp = path.with_extension("save.tmp");
+        let mut writer = BufWriter::new(File::create(&temp).map_err(|err| error(&err))?);
+        writer.write_all(MAGIC).map_err(|err| error(&err))?;
+        bincode::serialize_into(&mut writer, &SAVE_VERSION).map_err(|err| error(&err))?;
+        bincode::serialize_into(&mut writer, self).map_err(|err| error(&err))?;
+        writer
+            .into_inner()
+            .map_err(|err| error(&err.error()))?
+            .sync_all()
+            .map_err(|err| error(&err))?;
+        std::fs::rename(&temp, path).map_err(|err| error(&err))
+    }
+}
+
+/// Where a match saved under `name` goes.
+pub fn save_path(name: &str) -> PathBuf {
+    Path::new(SAVE_DIR).join(format!("{name}.save"))
+}
+
+/// The saved match the server continues, until it stops.
+#[derive(Resource, Debug)]
+pub struct LoadedMatch(pub SaveFile);
+
+/// Players of the loaded match that haven't come back yet. Kept on the server.
+#[derive(Resource, Debug, Default)]
+pub struct SavedSlots {
+    unclaimed: Vec<SavedPlayer>,
+    /// Clients that already took over a slot, so they don't take another one.
+    claimed: HashSet<ClientId>,
+}
+
+impl SavedSlots {
+    /// Which slot a player joining as `client_id` with `claim` comes back to, if any. The host's
+    /// own client and AI players keep their client ids from one match to the next, so they're
+    /// found by those. Other clients get new ones every time and are found by their secret.
+    fn find(&self, client_id: ClientId, claim: ClaimSecret) -> Option<usize> {
+        self.unclaimed.iter().position(|slot| {
+            if client_id.is_local() {
+                slot.client_id == client_id
+            } else {
+                slot.claim == claim
+            }
+        })
+    }
+
+    /// The team of the slot a player joining as `client_id` with `claim` comes back to, if any.
+    pub fn team_for(&self, client_id: ClientId, claim: ClaimSecret) -> Option<Team> {
+        self.find(client_id, claim)
+            .map(|index| self.unclaimed[index].team)
+    }
+
+    /// The teams of the players that aren't back yet.
+    pub fn unclaimed_teams(&self) -> impl Iterator<Item = &Team> {
+        self.unclaimed.iter().map(|slot| &slot.team)
+    }
+}
+
+/// Where the [`ClaimSecret`] of the player in `slot` is kept. Local clients started together each
+/// have a slot of their own, so they don't take each other's places in saved matches.
+pub fn claim_secret_path(slot: u32) -> PathBuf {
+    match slot {
+        0 => PathBuf::from("claim.secret"),
+        slot => PathBuf::from(format!("claim-{slot}.secret")),
+    }
+}
+
+/// Reads this player's [`ClaimSecret`] from `path`, writing a new one there if there's none yet.
+/// If that fails they get one for this run only, and can't take their slots in saved matches back
+/// after a restart.
+pub fn load_claim_secret(path: &Path) -> ClaimSecret {
+    let secret = match std::fs::read_to_string(path) {
+        Ok(hex) => u64::from_str_radix(hex.trim(), 16)
+            .map(ClaimSecret)
+            .map_err(|err| format!("Invalid {}: {err}", path.display())),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+            let secret = ClaimSecret::default();
+            std::fs::write(path, format!("{:016x}\n", secret.0))
+                .map(|()| secret)
+                .map_err(|err| format!("Could not write {}: {err}", path.display()))
+        }
+        Err(err) => Err(format!("Could not read {}: {err}", path.display())),
+    };
+    secret.unwrap_or_else(|err| {
+        warn!("{err}, saved matches won't know this player after a restart");
+        ClaimSecret::default()
+    })
+}
+
+/// Puts the world of a saved match back, instead of the usual starting world.
+pub fn restore_match(commands: &mut Commands, save: &SaveFile) {
+    info!(
+        players = save.players.len(),
+        units = save.units.len(),
+        "Continuing saved match"
+    );
+    for &(item, pos) in &save.items {
+        commands.spawn((item, pos, server::Replicate::default()));
+    }
+    for unit in &save.units {
+        spawn_unit(commands, unit, unit.owner.0);
+    }
+    let scoreboard = save
+        .players
+        .iter()
+        .map(|player| (player.client_id, player.points))
+        .collect();
+    commands.spawn((Scoreboard(scoreboard), server::Replicate::default()));
+    let apples = save
+        .players
+        .iter()
+        .map(|player| (player.client_id, player.apples))
+        .collect();
+    commands.spawn((Apples(apples), server::Replicate::default()));
+    commands.insert_resource(SavedSlots {
+        unclaimed: save.players.clone(),
+        claimed: HashSet::new(),
+    });
+}
+
+fn spawn_unit(commands: &mut Commands, unit: &SavedUnit, owner: ClientId) {
+    commands.spawn((
+        Name::new(format!("{} - {owner}", unit.kind.name())),
+        unit.position,
+        unit.target,
+        unit.kind,
+        unit.health,
+        unit.color,
+        OwnedBy(owner),
+        replicate_to_owner(owner),
+    ));
+}
+
+fn forget_loaded_match(mut commands: Commands) {
+    commands.remove_resource::<LoadedMatch>();
+    commands.remove_resource::<SavedSlots>();
+}
+
+#[derive(Debug, Clone)]
+pub struct SavedMatch {
+    pub name: String,
+    pub path: PathBuf,
+}
+
+/// Matches in [`SAVE_DIR`], most recent first.
+#[derive(Resource, Default, Deref)]
+pub struct SavedMatches(Vec<SavedMatch>);
+
+fn refresh_saved_matches(mut saved_matches: ResMut<SavedMatches>) {
+    let Ok(entries) = std::fs::read_dir(SAVE_DIR) else {
+        saved_matches.0.clear();
+        return;
+    };
+    let mut saves = entries
+        .filter_map(Result::ok)
+        .map(|entry| entry.path())
+        .filter(|path| {
+            path.extension()
+                .is_some_and(|extension| extension == "save")
+        })
+        .filter_map(|path| {
+            let modified = path.metadata().and_then(|meta| meta.modified()).ok()?;
+            let name = path.file_stem()?.to_string_lossy().into_owned();
+            Some((modified, SavedMatch { name, path }))
+        })
+        .collect::<Vec<_>>();
+    saves.sort_by(|(a, _), (b, _)| b.cmp(a));
+    saved_matches.0 = saves.into_iter().map(|(_, save)| save).collect();
+}
+
+fn load_match(
+    mut commands: Commands,
+    mut events: EventReader<LoadMatch>,
+    mut server_name: ResMut<ServerName>,
+    mut next_network_state: ResMut<NextState<NetworkState>>,
+) {
+    let Some(LoadMatch { path, addr }) = events.read().last() else {
+        return;
+    };
+    match SaveFile::load(path) {
+        Ok(save) => {
+            info!(path = %path.display(), "Loading saved match");
+            if server_name.0.is_none() {
+                server_name.0.clone_from(&save.server_name);
+            }
+            commands.insert_resource(LoadedMatch(save));
+            next_network_state.set(NetworkState::Host(*addr));
+        }
+        Err(err) => warn!("Could not load the match {err}"),
+    }
+}
+
+fn autosave(
+    mut last_save: Local<f64>,
+    interval: Res<Autosave>,
+    mut save: EventWriter<SaveMatch>,
+    time: Res<Time>,
+) {
+    let Some(interval) = interval.0 else {
+        return;
+    };
+    let now = time.elapsed_secs_f64();
+    if *last_save == 0.0 {
+        *last_save = now;
+    } else if now - *last_save >= interval.as_secs_f64() {
+        *last_save = now;
+        save.send(SaveMatch(AUTOSAVE_NAME.into()));
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn save_match(
+    mut events: EventReader<SaveMatch>,
+    players: Query<
+        (
+            &PlayerId,
+            &ClaimSecret,
+            &PlayerName,
+            &PlayerColor,
+            &Team,
+            &PlayerPosition,
+        ),
+        With<Replicating>,
+    >,
+    units: Query<
+        (
+            &UnitKind,
+            &MinionPosition,
+            &MinionTarget,
+            &Health,
+            &OwnedBy,
+            &PlayerColor,
+        ),
+        With<Replicating>,
+    >,
+    items: Query<(&Item, &ItemPos), With<Replicating>>,
+    scoreboard: Query<&Scoreboard, With<Replicating>>,
+    apples: Query<&Apples, With<Replicating>>,
+    slots: Option<Res<SavedSlots>>,
+    server_name: Res<ServerName>,
+    mut system_chat: EventWriter<SystemChat>,
+) {
+    let Some(SaveMatch(name)) = events.read().last() else {
+        return;
+    };
+    let scores = scoreboard.get_single().ok();
+    let apples = apples.get_single().ok();
+    let mut saved_players = players
+        .iter()
+        .map(
+            |(&PlayerId(client_id), &claim, name, &color, &team, &position)| SavedPlayer {
+                client_id,
+                claim,
+                name: name.clone(),
+                color,
+                team,
+                position,
+                points: scores
+                    .and_then(|scores| scores.get(&client_id).copied())
+                    .unwrap_or(0),
+                apples: apples
+                    .and_then(|apples| apples.get(&client_id).copied())
+                    .unwrap_or(0),
+            },
+        )
+        .collect::<Vec<_>>();
+    // Players that haven't come back since the last load keep their slot in this save too
+    saved_players.extend(
+        slots
+            .iter()
+            .flat_map(|slots| slots.unclaimed.iter().cloned()),
+    );
+
+    let save = SaveFile {
+        saved_at: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs(),
+        build: BuildInfo::local(),
+        server_name: server_name.0.clone(),
+        map: MAP_NAME.into(),
+        players: saved_players,
+        units: units
+            .iter()
+            .map(
+                |(&kind, &position, &target, &health, &owner, &color)| SavedUnit {
+                    kind,
+                    position,
+                    target,
+                    health,
+                    owner,
+                    color,
+                },
+            )
+            .collect(),
+        items: items.iter().map(|(&item, &pos)| (item, pos)).collect(),
+    };
+
+    let path = save_path(name);
+    match save.save(&path) {
+        Ok(()) => {
+            info!(path = %path.display(), "Saved match");
+            if name != AUTOSAVE_NAME {
+                system_chat.send(SystemChat("The match was saved".into()));
+            }
+        }
+        Err(err) => warn!("Could not save the match {err}"),
+    }
+}
+
+/// Hands saved slots to the clients that come back for them, see [`SavedSlots::find`]. They were
+/// put on the slot's team as they joined.
+#[allow(clippy::too_many_arguments)]
+fn claim_saved_slots(
+    mut commands: Commands,
+    mut slots: ResMut<SavedSlots>,
+    mut message_reader: EventReader<ServerMessageEvent<ClientMessage>>,
+    mut players: Query<(&PlayerId, &mut PlayerColor, &mut PlayerPosition), With<Replicating>>,
+    units: Query<
+        (
+            Entity,
+            &UnitKind,
+            &MinionPosition,
+            &MinionTarget,
+            &Health,
+            &OwnedBy,
+        ),
+        (With<Replicating>, Without<PlayerId>),
+    >,
+    mut scoreboard: Query<&mut Scoreboard>,
+    mut apples: Query<&mut Apples>,
+    mut system_chat: EventWriter<SystemChat>,
+) {
+    for event in message_reader.read() {
+        let client_id = event.from();
+        if slots.claimed.contains(&client_id) {
+            continue;
+        }
+        let ClientMessage::Join(Role::Player, claim) = event.message else {
+            continue;
+        };
+        let Some(index) = slots.find(client_id, claim) else {
+            continue;
+        };
+        // Spectators don't get a slot
+        let Some((_, mut color, mut position)) = players
+            .iter_mut()
+            .find(|(player_id, ..)| player_id.0 == client_id)
+        else {
+            continue;
+        };
+
+        let slot = slots.unclaimed.swap_remove(index);
+        slots.claimed.insert(client_id);
+        info!(%client_id, saved_id = %slot.client_id, name = %slot.name.0, "Player took over their saved slot");
+        *color = slot.color;
+        *position = slot.position;
+
+        if let Ok(mut scoreboard) = scoreboard.get_single_mut() {
+            scoreboard.remove(&slot.client_id);
+            scoreboard.insert(client_id, slot.points);
+        }
+        if let Ok(mut apples) = apples.get_single_mut() {
+            apples.remove(&slot.client_id);
+            apples.insert(client_id, slot.apples);
+        }
+
+        // Units are replicated to their owner, so they're spawned anew for a different client
+        if slot.client_id != client_id {
+            let owned = units.iter().filter(|(.., owner)| owner.0 == slot.client_id);
+            for (entity, &kind, &position, &target, &health, _) in owned {
+                commands.entity(entity).despawn_recursive();
+                let unit = SavedUnit {
+                    kind,
+                    position,
+                    target,
+                    health,
+                    owner: OwnedBy(client_id),
+                    color: slot.color,
+                };
+                spawn_unit(&mut commands, &unit, client_id);
+            }
+        }
+        system_chat.send(SystemChat(format!("{} is back", slot.name.0)));
+    }
+}
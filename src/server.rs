@@ -2,41 +2,44 @@ use std::net::{Ipv4Addr, SocketAddr};
 use std::time::Duration;
 
 use bevy::prelude::*;
+use bevy::time::common_conditions::on_timer;
 use bevy::utils::HashMap;
+use leafwing_input_manager::prelude::*;
 use lightyear::prelude::server::{
     ControlledBy, IoConfig, NetConfig, NetcodeConfig, Replicate, ServerCommands, ServerConfig,
     ServerTransport, SyncTarget,
 };
 use lightyear::prelude::*;
-use lightyear::shared::events::components::InputEvent;
 
-use crate::SteamClient;
+use crate::auth::ServerKey;
+use crate::conditioner::LinkConditions;
+use crate::discovery::ServerInfo;
 use crate::game::{
-    ClientMessage, InputHandling, KEY, PROTOCOL_ID,
-    minion::MinionTarget,
-    player::{Inputs, PlayerColor, PlayerId, PlayerPosition, shared_movement_behaviour},
-    resource::{Item, ItemPos, Scoreboard},
+    Channel1, ClientMessage, Dismissed, InputHandling, OwnedBy, PROTOCOL_ID, Role, ServerMessage,
+    Spectators,
+    chat::SystemChat,
+    handshake::Handshaken,
+    minion::{MinionPosition, MinionTarget, UnitKind},
+    player::{
+        Ping, PlayerActions, PlayerColor, PlayerId, PlayerName, PlayerPosition, Team,
+        shared_movement_behaviour,
+    },
+    resource::{Apples, Item, ItemPos, STARTING_APPLES, Scoreboard},
     shared_config,
+    status::Health,
 };
 use crate::networking::{IsServer, NetworkState};
-
-use self::server::SteamConfig;
+use crate::platform::Platform;
+use crate::save::{LoadedMatch, SavedSlots, restore_match};
+use crate::token_service::{TokenService, TokenServicePort};
 
 pub struct ServerPlugin;
 
 impl Plugin for ServerPlugin {
     fn build(&self, app: &mut App) {
-        let link_conditioner = LinkConditionerConfig {
-            incoming_latency: Duration::from_millis(200),
-            incoming_jitter: Duration::from_millis(0),
-            incoming_loss: 0.0,
-        };
         let server_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0);
-        let io_config = IoConfig::from_transport(ServerTransport::UdpSocket(server_addr))
-            .with_conditioner(link_conditioner);
-        let netcode_config = NetcodeConfig::default()
-            .with_protocol_id(PROTOCOL_ID)
-            .with_key(KEY);
+        let io_config = IoConfig::from_transport(ServerTransport::UdpSocket(server_addr));
+        let netcode_config = NetcodeConfig::default().with_protocol_id(*PROTOCOL_ID);
 
         let net_config = NetConfig::Netcode {
             config: netcode_config,
@@ -55,53 +58,84 @@ impl Plugin for ServerPlugin {
 
         app.add_plugins(server::ServerPlugins::new(server_config))
             .init_resource::<Global>()
+            .init_resource::<Spectators>()
+            .init_resource::<Dismissed>()
+            .init_resource::<SpectatorDelay>()
             .add_computed_state::<IsServer>()
             .add_systems(
                 FixedUpdate,
-                (handle_connections, handle_inputs.in_set(InputHandling)).chain(),
+                (
+                    handle_connections,
+                    handle_joins,
+                    handle_disconnections,
+                    handle_inputs.in_set(InputHandling),
+                )
+                    .chain(),
+            )
+            .add_systems(
+                Update,
+                (
+                    update_pings.run_if(on_timer(Duration::from_secs(1))),
+                    disconnect_dismissed,
+                )
+                    .run_if(is_server),
             )
-            .add_systems(OnEnter(IsServer), start_server);
+            .add_systems(OnEnter(IsServer), start_server)
+            .add_systems(OnExit(IsServer), stop_server);
     }
 }
 
+#[allow(clippy::too_many_arguments)]
 fn start_server(
     mut commands: Commands,
     network_state: Res<State<NetworkState>>,
     mut server_config: ResMut<ServerConfig>,
-    steam_client: Res<SteamClient>,
+    platform: Res<Platform>,
+    link_conditions: Res<LinkConditions>,
+    server_key: Option<Res<ServerKey>>,
+    token_port: Res<TokenServicePort>,
+    loaded_match: Option<Res<LoadedMatch>>,
+    server_io: Option<Res<ServerIo>>,
 ) {
+    // Hosting from the menu has no key loaded. One made up for the session does, as clients get
+    // their tokens from the token service running with it
+    let server_key = server_key.map_or_else(|| ServerKey(rand::random()), |key| *key);
+
     // Start server
     match network_state.get() {
         &NetworkState::Host(addr) | &NetworkState::Server(addr) => {
-            let link_conditioner = LinkConditionerConfig {
-                incoming_latency: Duration::from_millis(200),
-                incoming_jitter: Duration::from_millis(0),
-                incoming_loss: 0.0,
+            let io_config = match server_io {
+                Some(server_io) => server_io.0.clone(),
+                None => {
+                    let token_addr = SocketAddr::new(addr.ip(), token_port.0);
+                    match TokenService::start(token_addr, addr, server_key) {
+                        Ok(token_service) => commands.insert_resource(token_service),
+                        Err(err) => {
+                            warn!(%token_addr, "Could not start the token service: {err}")
+                        }
+                    }
+
+                    IoConfig {
+                        conditioner: link_conditions.server.to_config(),
+                        ..IoConfig::from_transport(ServerTransport::UdpSocket(addr))
+                    }
+                }
             };
-            let io_config = IoConfig::from_transport(ServerTransport::UdpSocket(addr))
-                .with_conditioner(link_conditioner.clone());
             let netcode_config = NetcodeConfig::default()
-                .with_protocol_id(PROTOCOL_ID)
-                .with_key(KEY);
+                .with_protocol_id(*PROTOCOL_ID)
+                .with_key(server_key.0);
 
             let net_config = NetConfig::Netcode {
                 config: netcode_config,
                 io: io_config,
             };
 
-            let steam_config = NetConfig::Steam {
-                steamworks_client: Some(steam_client.clone()),
-                config: SteamConfig {
-                    app_id: 480, // The steam test app id
-                    socket_config: server::SocketConfig::P2P { virtual_port: 5001 },
-                    ..default()
-                },
-                conditioner: Some(link_conditioner),
-            };
+            let mut net = vec![net_config];
+            net.extend(platform.server_net_config(link_conditions.server.to_config()));
 
             *server_config = ServerConfig {
                 shared: shared_config(Mode::HostServer),
-                net: vec![net_config, steam_config],
+                net,
                 replication: ReplicationConfig {
                     send_interval: Duration::from_millis(40),
                     ..default()
@@ -111,15 +145,37 @@ fn start_server(
         }
         _ => return,
     };
+    info!("Starting server");
     commands.start_server();
 
     // Set up game world
+    if let Some(loaded_match) = loaded_match {
+        restore_match(&mut commands, &loaded_match.0);
+        return;
+    }
     commands.spawn((
         Item::Apple,
         ItemPos(Vec2::new(2.0, 2.0)),
         Replicate::default(),
     ));
     commands.spawn((Scoreboard(HashMap::new()), Replicate::default()));
+    commands.spawn((Apples(HashMap::new()), Replicate::default()));
+}
+
+fn stop_server(
+    mut commands: Commands,
+    mut global: ResMut<Global>,
+    mut spectators: ResMut<Spectators>,
+    mut dismissed: ResMut<Dismissed>,
+    mut handshaken: ResMut<Handshaken>,
+) {
+    info!("Stopping server");
+    commands.stop_server();
+    commands.remove_resource::<TokenService>();
+    global.client_id_to_entity_id.clear();
+    spectators.0.clear();
+    dismissed.0.clear();
+    handshaken.0.clear();
 }
 
 #[derive(Resource, Default)]
@@ -127,113 +183,371 @@ struct Global {
     pub client_id_to_entity_id: HashMap<ClientId, Entity>,
 }
 
+/// How far behind the match spectators are kept, so they can't tell players what the other team
+/// is up to.
+#[derive(Resource, Clone, Copy, Debug, Default)]
+pub struct SpectatorDelay(pub Duration);
+
+/// Replaces the UDP socket the server listens on, e.g. with in-memory channels in tests. No token
+/// service is started then, as nobody could reach it.
+#[derive(Resource, Clone)]
+pub struct ServerIo(pub IoConfig);
+
+/// Replication for an entity controlled by `client_id`: predicted by its owner and interpolated
+/// by everyone else.
+pub(crate) fn replicate_to_owner(client_id: ClientId) -> Replicate {
+    Replicate {
+        sync: SyncTarget {
+            prediction: NetworkTarget::Single(client_id),
+            interpolation: NetworkTarget::AllExceptSingle(client_id),
+        },
+        controlled_by: ControlledBy {
+            target: NetworkTarget::Single(client_id),
+            ..default()
+        },
+        // Children are only visuals, like health bars, that every app adds for itself
+        hierarchy: ReplicateHierarchy {
+            enabled: false,
+            recursive: false,
+        },
+        ..default()
+    }
+}
+
+/// The name a player has until their client tells us what they want to be called.
+fn placeholder_name(client_id: ClientId) -> PlayerName {
+    PlayerName(format!("Player {client_id}"))
+}
+
 fn handle_connections(
-    mut commands: Commands,
     mut connections: EventReader<ServerConnectEvent>,
+    tick_manager: Res<TickManager>,
+) {
+    for connection in connections.read() {
+        // Players are spawned once the client says how it wants to join
+        info!(client_id = %connection.client_id, tick = tick_manager.tick().0, "Client connected");
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn handle_joins(
+    mut commands: Commands,
+    mut message_reader: EventReader<ServerMessageEvent<ClientMessage>>,
     mut global: ResMut<Global>,
+    mut spectators: ResMut<Spectators>,
     mut scoreboard: Query<&mut Scoreboard>,
+    mut apples: Query<&mut Apples>,
+    teams: Query<&Team, (With<PlayerId>, With<Replicating>)>,
+    spectator_delay: Res<SpectatorDelay>,
+    mut connection_manager: ResMut<ServerConnectionManager>,
+    mut dismissed: ResMut<Dismissed>,
+    handshaken: Res<Handshaken>,
+    saved_slots: Option<Res<SavedSlots>>,
+    mut system_chat: EventWriter<SystemChat>,
+    time: Res<Time>,
+    tick_manager: Res<TickManager>,
 ) {
-    for connection in connections.read() {
-        let client_id = connection.client_id;
+    let messages = message_reader.read().collect::<Vec<_>>();
+    // Players of a saved match that aren't back yet still have their place on their team
+    let unclaimed = saved_slots.iter().flat_map(|slots| slots.unclaimed_teams());
+    let mut team_sizes = [0; 2];
+    for team in teams.iter().chain(unclaimed) {
+        if let Some(size) = team_sizes.get_mut(team.0 as usize) {
+            *size += 1;
+        }
+    }
+    for event in &messages {
+        let ClientMessage::Join(role, claim) = event.message else {
+            continue;
+        };
+        let client_id = event.from();
+        // AI players and the host's own client run in this app, so there's no build to check
+        if !client_id.is_local() && !handshaken.0.contains(&client_id) {
+            warn!(%client_id, "Ignored a join from a client that hasn't passed the handshake");
+            continue;
+        }
+        if global.client_id_to_entity_id.contains_key(&client_id)
+            || spectators.0.contains_key(&client_id)
+        {
+            continue;
+        }
+
+        // Clients send their name right after joining, so it usually comes in the same tick
+        let name = messages
+            .iter()
+            .filter(|other| other.from() == client_id)
+            .find_map(|other| match &other.message {
+                ClientMessage::SetName(name) => {
+                    Some(PlayerName::sanitized(name)).filter(|name| !name.is_empty())
+                }
+                _ => None,
+            })
+            .unwrap_or_else(|| placeholder_name(client_id));
+
+        if role == Role::Spectator {
+            info!(%client_id, tick = tick_manager.tick().0, name = %name.0, "Client joined as a spectator");
+            system_chat.send(SystemChat(format!("{} is spectating", name.0)));
+            spectators.0.insert(client_id, name);
+            let delay_ms = spectator_delay.0.as_millis() as u32;
+            connection_manager
+                .send_message_to_target::<Channel1, _>(
+                    &ServerMessage::Spectating { delay_ms },
+                    NetworkTarget::Single(client_id),
+                )
+                .unwrap();
+            continue;
+        }
+
+        // AI players count too, they take a slot like anyone else
+        if global.client_id_to_entity_id.len() >= ServerInfo::MAX_PLAYERS as usize {
+            info!(%client_id, "Refused a player, the match is full");
+            connection_manager
+                .send_message_to_target::<Channel1, _>(
+                    &ServerMessage::Refused("The match is full".into()),
+                    NetworkTarget::Single(client_id),
+                )
+                .unwrap();
+            dismissed.0.insert(client_id, time.elapsed_secs_f64());
+            continue;
+        }
+
         scoreboard.single_mut().insert(client_id, 0);
+        apples.single_mut().insert(client_id, STARTING_APPLES);
 
+        // Players back for their saved slot are counted on its team already. Everyone else fills
+        // up whichever team is smaller, so teams stay even as players come and go
+        let saved_team = saved_slots
+            .as_ref()
+            .and_then(|slots| slots.team_for(client_id, claim));
+        let team = saved_team.unwrap_or_else(|| {
+            let team = Team(if team_sizes[1] < team_sizes[0] { 1 } else { 0 });
+            team_sizes[team.0 as usize] += 1;
+            team
+        });
+        info!(%client_id, tick = tick_manager.tick().0, team = team.0, name = %name.0, "Client joined as a player");
+        system_chat.send(SystemChat(format!("{} joined the game", name.0)));
         let entity = commands.spawn((
             Name::new(format!("Player - {client_id}")),
             PlayerId(client_id),
+            name,
+            team,
+            claim,
+            Ping::default(),
             PlayerPosition(Vec2::ZERO),
             PlayerColor(Color::linear_rgb(
                 rand::random(),
                 rand::random(),
                 rand::random(),
             )),
-            Replicate {
-                sync: SyncTarget {
-                    prediction: NetworkTarget::Single(client_id),
-                    interpolation: NetworkTarget::AllExceptSingle(client_id),
-                },
-                controlled_by: ControlledBy {
-                    target: NetworkTarget::Single(client_id),
-                    ..default()
-                },
-                ..default()
-            },
+            ActionState::<PlayerActions>::default(),
+            replicate_to_owner(client_id),
         ));
 
         global.client_id_to_entity_id.insert(client_id, entity.id());
     }
 }
 
+#[allow(clippy::too_many_arguments)]
+fn handle_disconnections(
+    mut commands: Commands,
+    mut disconnections: EventReader<ServerDisconnectEvent>,
+    mut global: ResMut<Global>,
+    mut spectators: ResMut<Spectators>,
+    mut dismissed: ResMut<Dismissed>,
+    mut handshaken: ResMut<Handshaken>,
+    names: Query<&PlayerName>,
+    mut system_chat: EventWriter<SystemChat>,
+    tick_manager: Res<TickManager>,
+) {
+    for disconnection in disconnections.read() {
+        let client_id = disconnection.client_id;
+        info!(%client_id, tick = tick_manager.tick().0, "Client disconnected");
+        spectators.0.remove(&client_id);
+        dismissed.0.remove(&client_id);
+        handshaken.0.remove(&client_id);
+        let Some(player) = global.client_id_to_entity_id.remove(&client_id) else {
+            continue;
+        };
+        if let Ok(name) = names.get(player) {
+            system_chat.send(SystemChat(format!("{} left the game", name.0)));
+        }
+        commands.entity(player).despawn_recursive();
+    }
+}
+
+/// How long dismissed clients get to leave on their own.
+const DISMISS_GRACE_SECS: f64 = 1.0;
+
+fn disconnect_dismissed(
+    mut commands: Commands,
+    mut dismissed: ResMut<Dismissed>,
+    connection_manager: Res<ServerConnectionManager>,
+    time: Res<Time>,
+) {
+    let now = time.elapsed_secs_f64();
+    dismissed.0.retain(|&client_id, &mut since| {
+        if now - since < DISMISS_GRACE_SECS {
+            return true;
+        }
+        // AI players have no connection to close
+        if connection_manager.connection(client_id).is_ok() {
+            info!(%client_id, "Disconnecting a dismissed client");
+            commands.disconnect(client_id);
+        }
+        false
+    });
+}
+
+#[allow(clippy::too_many_arguments)]
 fn handle_inputs(
     mut commands: Commands,
-    mut positions: Query<&mut PlayerPosition>,
-    mut input_reader: EventReader<InputEvent<Inputs, ClientId>>,
+    mut players: Query<
+        (
+            &PlayerId,
+            &PlayerColor,
+            &mut PlayerPosition,
+            &ActionState<PlayerActions>,
+        ),
+        With<Replicating>,
+    >,
+    mut names: Query<&mut PlayerName>,
     mut message_reader: EventReader<ServerMessageEvent<ClientMessage>>,
-    mut minion_targets: Query<&mut MinionTarget>,
+    mut minions: Query<(
+        &mut MinionTarget,
+        &MinionPosition,
+        &OwnedBy,
+        &UnitKind,
+        &PlayerColor,
+    )>,
+    mut apples: Query<&mut Apples>,
     global: Res<Global>,
+    mut spectators: ResMut<Spectators>,
     time: Res<Time<Fixed>>,
+    tick_manager: Res<TickManager>,
 ) {
-    for input in input_reader.read() {
-        let client_id = input.from();
-        if let Some(input) = input.input() {
-            if let Some(player_entity) = global.client_id_to_entity_id.get(&client_id) {
-                if let Ok(mut position) = positions.get_mut(*player_entity) {
-                    shared_movement_behaviour(
-                        input,
-                        &mut commands,
-                        &mut position,
-                        &time,
-                        client_id,
-                        (
-                            Replicate {
-                                sync: SyncTarget {
-                                    prediction: NetworkTarget::Single(client_id),
-                                    interpolation: NetworkTarget::AllExceptSingle(client_id),
-                                },
-                                controlled_by: ControlledBy {
-                                    target: NetworkTarget::Single(client_id),
-                                    ..default()
-                                },
-                                ..default()
-                            },
-                            PreSpawnedPlayerObject::default(),
-                        ),
-                    );
-                    //&Inputs::Spawn(pos, color) => {
-                    //    commands.spawn((
-                    //        Name::new(format!("Minion - {client_id}")),
-                    //        MinionPosition(pos),
-                    //        MinionTarget(Vec2::new(4.0, 4.0)),
-                    //        PlayerColor(color),
-                    //        Replicate {
-                    //            sync: SyncTarget {
-                    //                prediction: NetworkTarget::Single(client_id),
-                    //                interpolation: NetworkTarget::AllExceptSingle(client_id),
-                    //            },
-                    //            controlled_by: ControlledBy {
-                    //                target: NetworkTarget::Single(client_id),
-                    //                ..default()
-                    //            },
-                    //            ..default()
-                    //        },
-                    //        OwnedBy(client_id),
-                    //        PreSpawnedPlayerObject::default(),
-                    //    ));
-                    //}
-                    //Inputs::None => {}
-                }
-            }
-        }
+    let tick = tick_manager.tick();
+    for (&PlayerId(client_id), &color, mut position, action_state) in &mut players {
+        let Ok(mut apples) = apples.get_single_mut() else {
+            continue;
+        };
+        shared_movement_behaviour(
+            action_state,
+            &mut commands,
+            &mut position,
+            color,
+            apples.entry(client_id).or_default(),
+            &time,
+            tick,
+            client_id,
+            (
+                replicate_to_owner(client_id),
+                PreSpawnedPlayerObject::default(),
+            ),
+        );
     }
 
     for event in message_reader.read() {
+        let client_id = event.from();
+        // Spectators only get to chat and pick a name
+        if spectators.0.contains_key(&client_id)
+            && !matches!(
+                event.message,
+                ClientMessage::SetName(_) | ClientMessage::Chat(..)
+            )
+        {
+            continue;
+        }
         match &event.message {
-            ClientMessage::Target(minions, target) => {
-                for &minion in minions {
-                    if let Ok(mut minion_target) = minion_targets.get_mut(minion) {
+            ClientMessage::Target(targets, target) => {
+                debug!(%client_id, tick = tick.0, units = targets.len(), %target, "Target command");
+                for &minion in targets {
+                    if let Ok((mut minion_target, _, owner, kind, _)) = minions.get_mut(minion)
+                        && owner.0 == client_id
+                        && !kind.is_building()
+                    {
                         minion_target.0 = *target;
                     }
                 }
             }
+            ClientMessage::Stop(targets) => {
+                debug!(%client_id, tick = tick.0, units = targets.len(), "Stop command");
+                for &minion in targets {
+                    if let Ok((mut minion_target, pos, owner, ..)) = minions.get_mut(minion)
+                        && owner.0 == client_id
+                    {
+                        minion_target.0 = pos.0;
+                    }
+                }
+            }
+            &ClientMessage::Train(ref producers, kind) => {
+                for &producer in producers {
+                    let Ok((_, &pos, &owner, producer_kind, &color)) = minions.get(producer) else {
+                        continue;
+                    };
+                    if owner.0 != client_id || !producer_kind.produces().contains(&kind) {
+                        continue;
+                    }
+                    let Ok(mut apples) = apples.get_single_mut() else {
+                        continue;
+                    };
+                    let apples = apples.entry(client_id).or_default();
+                    if *apples < kind.cost() {
+                        debug!(%client_id, ?kind, apples = *apples, "Not enough apples to train");
+                        continue;
+                    }
+                    *apples -= kind.cost();
+
+                    let spawn_pos = pos.0 + Vec2::new(0.0, -1.0);
+                    info!(%client_id, tick = tick.0, ?kind, pos = %spawn_pos, "Training unit");
+                    commands.spawn((
+                        Name::new(format!("{} - {client_id}", kind.name())),
+                        MinionPosition(spawn_pos),
+                        MinionTarget(spawn_pos),
+                        kind,
+                        Health::new(kind.max_health()),
+                        color,
+                        owner,
+                        replicate_to_owner(client_id),
+                    ));
+                }
+            }
+            ClientMessage::SetName(name) => {
+                let name = PlayerName::sanitized(name);
+                if name.is_empty() {
+                    continue;
+                }
+                if let Some(&player) = global.client_id_to_entity_id.get(&client_id) {
+                    if let Ok(mut player_name) = names.get_mut(player)
+                        && let Some(from) = player_name.replace_if_neq(name.clone())
+                    {
+                        info!(%client_id, from = %from.0, to = %name.0, "Player renamed");
+                    }
+                } else if let Some(spectator_name) = spectators.0.get_mut(&client_id)
+                    && *spectator_name != name
+                {
+                    // Spectators have names too, for the chat
+                    info!(%client_id, from = %spectator_name.0, to = %name.0, "Spectator renamed");
+                    *spectator_name = name;
+                }
+            }
+            ClientMessage::Join(..) | ClientMessage::Chat(..) | ClientMessage::Ping(_) => {}
+        }
+    }
+}
+
+fn update_pings(
+    mut players: Query<(&PlayerId, &mut Ping)>,
+    connection_manager: Res<ServerConnectionManager>,
+) {
+    for (player_id, mut ping) in &mut players {
+        if let Ok(connection) = connection_manager.connection(player_id.0) {
+            let new_ping = Ping {
+                rtt: connection.rtt().as_millis() as u32,
+                jitter: connection.jitter().as_millis() as u32,
+            };
+            if *ping != new_ping {
+                *ping = new_ping;
+            }
         }
     }
 }
@@ -0,0 +1,340 @@
+use std::collections::VecDeque;
+use std::time::Duration;
+
+use bevy::ecs::system::SystemParam;
+use bevy::input::mouse::MouseWheel;
+use bevy::prelude::*;
+use bevy_egui::EguiContexts;
+use bevy_egui::egui::Align2;
+use lightyear::prelude::server::{ReplicationSet, ReplicationTarget};
+use lightyear::prelude::*;
+
+use crate::game::minion::UnitKind;
+use crate::game::player::{PlayerId, PlayerName, PlayerPosition};
+use crate::game::resource::Apples;
+use crate::game::snapshot::{Playback, SNAPSHOT_INTERVAL, SnapshotSource, apply_snapshot};
+use crate::game::{
+    Channel1, InputHandling, OwnedBy, PingChannel, Relevant, Role, ServerMessage, Spectators,
+};
+use crate::minimap::MinimapCamera;
+use crate::networking::{IsClient, IsServer};
+use crate::server::SpectatorDelay;
+
+/// Lets clients watch a match without playing: the camera moves freely or follows a player, and
+/// everything is shown as far behind the match as the server asks.
+///
+/// Spectators get nothing replicated. The server sends them snapshots of the match instead, and
+/// the chat and pings that go with them, but only once the [`SpectatorDelay`] has passed, so a
+/// spectator can't tell players what the other team is up to.
+pub struct SpectatorPlugin;
+
+impl Plugin for SpectatorPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<JoinAs>()
+            .init_resource::<HeldBack>()
+            .add_systems(
+                Update,
+                (
+                    release_to_spectators.run_if(is_server),
+                    receive_spectating.run_if(in_state(IsClient)),
+                    // A host spectating its own match already has all of it
+                    receive_snapshots
+                        .run_if(in_state(IsClient))
+                        .run_if(not(is_server)),
+                    follow_match
+                        .before(apply_snapshot)
+                        .run_if(in_state(IsClient))
+                        .run_if(resource_exists::<Playback>),
+                    (move_camera, show_spectator_panel)
+                        .chain()
+                        .run_if(resource_exists::<Spectating>),
+                ),
+            )
+            .add_systems(
+                FixedUpdate,
+                snapshot_for_spectators
+                    .after(InputHandling)
+                    .run_if(is_server),
+            )
+            .add_systems(
+                PostUpdate,
+                (
+                    hide_spawned_from_spectators,
+                    hide_all_from_spectators.run_if(resource_changed::<Spectators>),
+                )
+                    .before(ReplicationSet::All)
+                    .run_if(is_server),
+            )
+            .add_systems(OnExit(IsServer), forget_held_back)
+            .add_systems(OnExit(IsClient), stop_spectating);
+    }
+}
+
+const CAMERA_SPEED: f32 = 12.0;
+const MIN_ZOOM: f32 = 0.5;
+const MAX_ZOOM: f32 = 4.0;
+
+/// The role this client asks for when it connects.
+#[derive(Resource, Clone, Copy, Debug, Default)]
+pub struct JoinAs(pub Role);
+
+/// Messages for the spectators, with when they were held back.
+#[derive(Resource, Default)]
+struct HeldBack(VecDeque<(f64, ServerMessage)>);
+
+/// Sends spectators what happens in the match once the [`SpectatorDelay`] has passed, while
+/// players get it right away.
+#[derive(SystemParam)]
+pub struct SpectatorFeed<'w> {
+    spectators: Res<'w, Spectators>,
+    held_back: ResMut<'w, HeldBack>,
+    time: Res<'w, Time<Real>>,
+}
+
+impl SpectatorFeed<'_> {
+    /// Everyone but the spectators, to send to right away what they get from
+    /// [`Self::hold_back`].
+    pub fn players(&self) -> NetworkTarget {
+        NetworkTarget::from_exclude(self.spectators.0.keys().copied())
+    }
+
+    /// Queues `message` for every spectator.
+    pub fn hold_back(&mut self, message: ServerMessage) {
+        if self.spectators.0.is_empty() {
+            return;
+        }
+        let now = self.time.elapsed_secs_f64();
+        self.held_back.0.push_back((now, message));
+    }
+}
+
+/// Present while this client spectates.
+#[derive(Resource, Debug)]
+pub struct Spectating {
+    pub delay: Duration,
+    /// The player the camera follows, or `None` for a free camera.
+    pub following: Option<ClientId>,
+}
+
+/// Keeps replication of newly spawned entities away from spectators, they only see the match from
+/// the snapshots.
+fn hide_spawned_from_spectators(
+    spectators: Res<Spectators>,
+    mut spawned: Query<&mut ReplicationTarget, Added<ReplicationTarget>>,
+) {
+    hide_from_spectators(&spectators, &mut spawned);
+}
+
+/// Moves replication of everything away from spectators, or back to them, as they come and go.
+fn hide_all_from_spectators(
+    spectators: Res<Spectators>,
+    mut replicated: Query<&mut ReplicationTarget>,
+) {
+    hide_from_spectators(&spectators, &mut replicated);
+}
+
+fn hide_from_spectators<'a>(
+    spectators: &Spectators,
+    replication_targets: impl IntoIterator<Item = Mut<'a, ReplicationTarget>>,
+) {
+    let players = NetworkTarget::from_exclude(spectators.0.keys().copied());
+    for mut replication_target in replication_targets {
+        if replication_target.target != players {
+            replication_target.target = players.clone();
+        }
+    }
+}
+
+fn snapshot_for_spectators(
+    mut feed: SpectatorFeed,
+    source: SnapshotSource,
+    tick_manager: Res<TickManager>,
+) {
+    let tick = tick_manager.tick().0;
+    if tick.is_multiple_of(SNAPSHOT_INTERVAL) && !feed.spectators.0.is_empty() {
+        feed.hold_back(ServerMessage::Snapshot(source.take(tick)));
+    }
+}
+
+fn release_to_spectators(
+    mut held_back: ResMut<HeldBack>,
+    spectators: Res<Spectators>,
+    delay: Res<SpectatorDelay>,
+    mut connection_manager: ResMut<ServerConnectionManager>,
+    time: Res<Time<Real>>,
+) {
+    let release_before = time.elapsed_secs_f64() - delay.0.as_secs_f64();
+    let target = NetworkTarget::Only(spectators.0.keys().copied().collect());
+    while let Some((_, message)) = held_back
+        .0
+        .pop_front_if(|(held_at, _)| *held_at <= release_before)
+    {
+        let sent = match message {
+            ServerMessage::Ping(_) => connection_manager
+                .send_message_to_target::<PingChannel, _>(&message, target.clone()),
+            _ => connection_manager.send_message_to_target::<Channel1, _>(&message, target.clone()),
+        };
+        if let Err(err) = sent {
+            warn!("Could not send spectators what happened: {err}");
+        }
+    }
+}
+
+fn forget_held_back(mut held_back: ResMut<HeldBack>) {
+    held_back.0.clear();
+}
+
+fn receive_spectating(
+    mut commands: Commands,
+    mut message_reader: EventReader<ClientMessageEvent<ServerMessage>>,
+) {
+    for event in message_reader.read() {
+        let ServerMessage::Spectating { delay_ms } = event.message else {
+            continue;
+        };
+        info!(delay_ms, "Spectating");
+        commands.insert_resource(Spectating {
+            delay: Duration::from_millis(delay_ms.into()),
+            following: None,
+        });
+    }
+}
+
+/// Plays the snapshots the server sends as they come in.
+fn receive_snapshots(
+    mut commands: Commands,
+    mut message_reader: EventReader<ClientMessageEvent<ServerMessage>>,
+    playback: Option<ResMut<Playback>>,
+) {
+    let mut snapshots = message_reader
+        .read()
+        .filter_map(|event| match &event.message {
+            ServerMessage::Snapshot(snapshot) => Some(snapshot.clone()),
+            _ => None,
+        });
+    match playback {
+        Some(mut playback) => {
+            for snapshot in snapshots {
+                playback.push(snapshot);
+            }
+        }
+        None => {
+            let Some(first) = snapshots.next() else {
+                return;
+            };
+            let mut playback = Playback::live(first);
+            for snapshot in snapshots {
+                playback.push(snapshot);
+            }
+            commands.insert_resource(playback);
+        }
+    }
+}
+
+fn follow_match(mut playback: ResMut<Playback>, time: Res<Time>) {
+    playback.follow(time.delta_secs_f64());
+}
+
+/// Also puts the camera back where players expect it.
+fn stop_spectating(
+    mut commands: Commands,
+    mut camera: Query<(&mut Transform, &mut OrthographicProjection), Without<MinimapCamera>>,
+) {
+    commands.remove_resource::<Spectating>();
+    commands.remove_resource::<Playback>();
+    if let Ok((mut transform, mut projection)) = camera.get_single_mut() {
+        transform.translation = Vec3::ZERO;
+        projection.scale = 1.0;
+    }
+}
+
+fn move_camera(
+    mut contexts: EguiContexts,
+    mut spectating: ResMut<Spectating>,
+    mut camera: Query<(&mut Transform, &mut OrthographicProjection), Without<MinimapCamera>>,
+    mut wheel: EventReader<MouseWheel>,
+    players: Query<(&PlayerId, &PlayerPosition), Relevant>,
+    keypress: Res<ButtonInput<KeyCode>>,
+    time: Res<Time>,
+) {
+    let Ok((mut transform, mut projection)) = camera.get_single_mut() else {
+        return;
+    };
+
+    let ctx = contexts.ctx_mut();
+    let scroll = wheel.read().map(|event| event.y).sum::<f32>();
+    if !ctx.is_pointer_over_area() && scroll != 0.0 {
+        projection.scale = (projection.scale * 0.9f32.powf(scroll)).clamp(MIN_ZOOM, MAX_ZOOM);
+    }
+
+    let mut direction = Vec2::ZERO;
+    if !ctx.wants_keyboard_input() {
+        for (keys, step) in [
+            ([KeyCode::KeyW, KeyCode::ArrowUp], Vec2::Y),
+            ([KeyCode::KeyS, KeyCode::ArrowDown], -Vec2::Y),
+            ([KeyCode::KeyA, KeyCode::ArrowLeft], -Vec2::X),
+            ([KeyCode::KeyD, KeyCode::ArrowRight], Vec2::X),
+        ] {
+            if keypress.any_pressed(keys) {
+                direction += step;
+            }
+        }
+    }
+    // Moving the camera by hand lets go of whoever it followed
+    if direction != Vec2::ZERO {
+        spectating.following = None;
+        let step = direction.normalize() * CAMERA_SPEED * projection.scale * time.delta_secs();
+        transform.translation += step.extend(0.0);
+    } else if let Some(following) = spectating.following {
+        match players.iter().find(|(id, _)| id.0 == following) {
+            Some((_, position)) => {
+                transform.translation = position.extend(transform.translation.z);
+            }
+            // They left
+            None => spectating.following = None,
+        }
+    }
+}
+
+fn show_spectator_panel(
+    mut contexts: EguiContexts,
+    mut spectating: ResMut<Spectating>,
+    players: Query<(&PlayerId, &PlayerName), Relevant>,
+    units: Query<&OwnedBy, (With<UnitKind>, Relevant)>,
+    apples: Query<&Apples>,
+) {
+    let mut players = players.iter().collect::<Vec<_>>();
+    players.sort_by(|(_, a), (_, b)| a.0.cmp(&b.0));
+
+    bevy_egui::egui::Window::new("Spectating")
+        .anchor(Align2::LEFT_TOP, (10.0, 10.0))
+        .resizable(false)
+        .collapsible(true)
+        .show(contexts.ctx_mut(), |ui| {
+            if !spectating.delay.is_zero() {
+                ui.label(format!(
+                    "{:.1} s behind the match",
+                    spectating.delay.as_secs_f32()
+                ));
+            }
+            ui.small("WASD to move, scroll to zoom");
+            ui.separator();
+
+            ui.selectable_value(&mut spectating.following, None, "Free camera");
+            for (id, name) in players {
+                ui.selectable_value(&mut spectating.following, Some(id.0), &name.0);
+            }
+
+            if let Some(following) = spectating.following {
+                let apples = apples
+                    .get_single()
+                    .ok()
+                    .and_then(|apples| apples.get(&following).copied())
+                    .unwrap_or(0);
+                let population = units.iter().filter(|owner| owner.0 == following).count();
+                ui.separator();
+                ui.label(format!("Apples: {apples}"));
+                ui.label(format!("Population: {population}"));
+            }
+        });
+}
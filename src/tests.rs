@@ -0,0 +1,207 @@
+//! Runs a server and clients in this process, connected through in-memory channels instead of
+//! sockets and stepped one tick at a time, so tests can check what gets replicated where. Nothing
+//! is rendered and there's no platform, so no window, GPU or Steam is needed.
+
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+
+use bevy::ecs::query::{QueryFilter, ROQueryItem, ReadOnlyQueryData};
+use bevy::prelude::*;
+use bevy::time::TimeUpdateStrategy;
+use bevy::utils::Instant;
+use lightyear::prelude::client::{ClientTransport, NetworkingState as ClientNetworkingState};
+use lightyear::prelude::server::ServerTransport;
+use lightyear::prelude::*;
+use lightyear::transport::LOCAL_SOCKET;
+
+use crate::auth::{IssuedConnectToken, ServerKey};
+use crate::client::ClientIo;
+use crate::game::minion::{MinionPosition, MinionTarget, UnitKind};
+use crate::game::player::{PlayerColor, PlayerId};
+use crate::game::status::Health;
+use crate::game::{OwnedBy, Role, Spectators, shared_config};
+use crate::master_server::{MasterServer, Region};
+use crate::networking::{LocalPlayerName, NetworkState};
+use crate::platform::{NoPlatform, Platform};
+use crate::server::{ServerIo, replicate_to_owner};
+use crate::spectator::JoinAs;
+use crate::token_service::TokenServicePort;
+
+mod ai;
+mod discovery;
+mod platform;
+mod protocol;
+mod replication;
+mod units;
+
+/// Nothing listens here, it only has to match the connect tokens. Clients see every packet over
+/// their channel as coming from [`LOCAL_SOCKET`], and drop any not from the token's server.
+const SERVER_ADDR: SocketAddr = LOCAL_SOCKET;
+/// Ticks to wait for every client to connect and join before giving up.
+const CONNECT_TICKS: usize = 64 * 10;
+
+/// A dedicated server and its clients, advanced together one tick at a time.
+pub struct TestMatch {
+    pub server: App,
+    pub clients: Vec<App>,
+    now: Instant,
+}
+
+impl TestMatch {
+    /// A server with a player for each client, all connected and joined.
+    pub fn new(clients: usize) -> Self {
+        Self::with_roles(&vec![Role::Player; clients])
+    }
+
+    /// A server with a client joining in each of `roles`, all connected and joined.
+    pub fn with_roles(roles: &[Role]) -> Self {
+        let key = ServerKey(rand::random());
+        let mut channels = Vec::new();
+        let mut clients = Vec::new();
+        for (index, &role) in roles.iter().enumerate() {
+            let (to_client, from_server) = crossbeam_channel::unbounded();
+            let (to_server, from_client) = crossbeam_channel::unbounded();
+            // Only tells the server's connections apart
+            let client_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 10000 + index as u16);
+            channels.push((client_addr, from_client, to_client));
+
+            let client_id = Self::client_id(index);
+            let token = key
+                .issue_token(SERVER_ADDR, client_id)
+                .and_then(|token| token.try_into_bytes().map_err(|err| err.to_string()))
+                .unwrap();
+            let mut client = headless_app();
+            client
+                .insert_resource(ClientIo(client::IoConfig::from_transport(
+                    ClientTransport::LocalChannel {
+                        recv: from_server,
+                        send: to_server,
+                    },
+                )))
+                .insert_resource(IssuedConnectToken(Some(token.to_vec())))
+                .insert_resource(LocalPlayerName(format!("Client {index}")))
+                .insert_resource(JoinAs(role))
+                .insert_state(NetworkState::Client {
+                    server_addr: SERVER_ADDR,
+                    client_id,
+                });
+            clients.push(client);
+        }
+
+        let mut server = headless_app();
+        server
+            .insert_resource(key)
+            .insert_resource(ServerIo(server::IoConfig::from_transport(
+                ServerTransport::Channels { channels },
+            )))
+            .insert_state(NetworkState::Server(SERVER_ADDR));
+
+        let mut test_match = Self {
+            server,
+            clients,
+            now: Instant::now(),
+        };
+        for app in test_match.apps() {
+            app.finish();
+            app.cleanup();
+        }
+
+        let players = roles.iter().filter(|&&role| role == Role::Player).count();
+        let spectators = roles.len() - players;
+        let joined = test_match.run_until(CONNECT_TICKS, |test_match| {
+            let connected = test_match.clients.iter().all(|client| {
+                *client.world().resource::<State<ClientNetworkingState>>()
+                    == ClientNetworkingState::Connected
+            });
+            connected
+                && query::<&PlayerId, With<Replicating>>(&mut test_match.server).len() == players
+                && test_match.server.world().resource::<Spectators>().0.len() == spectators
+        });
+        assert!(
+            joined,
+            "Clients did not connect within {CONNECT_TICKS} ticks"
+        );
+        test_match
+    }
+
+    /// The client id of the client at `index` in [`Self::clients`].
+    pub fn client_id(index: usize) -> u64 {
+        index as u64 + 1
+    }
+
+    fn apps(&mut self) -> impl Iterator<Item = &mut App> {
+        std::iter::once(&mut self.server).chain(&mut self.clients)
+    }
+
+    /// Advances the server and then every client by one tick.
+    pub fn tick(&mut self) {
+        self.now += tick_duration();
+        let now = self.now;
+        for app in self.apps() {
+            app.insert_resource(TimeUpdateStrategy::ManualInstant(now));
+            app.update();
+        }
+    }
+
+    pub fn run(&mut self, ticks: usize) {
+        for _ in 0..ticks {
+            self.tick();
+        }
+    }
+
+    /// Ticks until `done` returns true, or `max_ticks` have passed. Returns whether it got done.
+    pub fn run_until(&mut self, max_ticks: usize, mut done: impl FnMut(&mut Self) -> bool) -> bool {
+        for _ in 0..max_ticks {
+            if done(self) {
+                return true;
+            }
+            self.tick();
+        }
+        done(self)
+    }
+
+    /// Spawns a unit on the server for the client at `owner`, like one it trained.
+    pub fn spawn_unit(&mut self, owner: usize, kind: UnitKind, position: Vec2) -> Entity {
+        let client_id = ClientId::Netcode(Self::client_id(owner));
+        let color = query::<(&PlayerId, &PlayerColor), With<Replicating>>(&mut self.server)
+            .into_iter()
+            .find(|(player_id, _)| player_id.0 == client_id)
+            .map(|(_, &color)| color)
+            .expect("The owner has no player");
+        self.server
+            .world_mut()
+            .spawn((
+                Name::new(format!("{} - {client_id}", kind.name())),
+                MinionPosition(position),
+                MinionTarget(position),
+                kind,
+                Health::new(kind.max_health()),
+                color,
+                OwnedBy(client_id),
+                replicate_to_owner(client_id),
+            ))
+            .id()
+    }
+}
+
+fn tick_duration() -> Duration {
+    shared_config(Mode::Separate).tick.tick_duration
+}
+
+/// A headless app that runs without a platform.
+fn headless_app() -> App {
+    let mut app = crate::create_headless_app();
+    app.insert_resource(Platform(Arc::new(NoPlatform)))
+        .insert_resource(TokenServicePort(0))
+        .insert_resource(Region("test".into()))
+        .insert_resource(MasterServer { addr: None });
+    app
+}
+
+/// Everything in `app` that matches the query.
+pub fn query<D: ReadOnlyQueryData, F: QueryFilter>(app: &mut App) -> Vec<ROQueryItem<'_, D>> {
+    let world = app.world_mut();
+    let mut state = world.query_filtered::<D, F>();
+    state.iter(world).collect()
+}
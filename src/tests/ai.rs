@@ -0,0 +1,98 @@
+use bevy::ecs::event::EventCursor;
+use bevy::prelude::*;
+use lightyear::prelude::client::Interpolated;
+use lightyear::prelude::*;
+
+use super::{TestMatch, query};
+use crate::ai::{AddAi, Ai, Difficulty};
+use crate::game::minion::{MinionPosition, UnitKind};
+use crate::game::player::{PlayerId, PlayerName};
+use crate::game::resource::Scoreboard;
+use crate::game::status::Health;
+use crate::game::{ClientMessage, OwnedBy};
+
+#[test]
+fn ai_players_join_and_spawn_units() {
+    let mut test_match = TestMatch::new(1);
+    test_match
+        .server
+        .world_mut()
+        .send_event(AddAi(Difficulty::Hard));
+
+    let joined = test_match.run_until(64 * 3, |test_match| {
+        query::<&PlayerName, With<Interpolated>>(&mut test_match.clients[0])
+            .iter()
+            .any(|name| name.0 == "AI 1 (Hard)")
+    });
+    assert!(joined, "the client never saw the AI player");
+
+    let ai = query::<&Ai, ()>(&mut test_match.server)[0].client_id;
+    let spawned = test_match.run_until(64 * 3, |test_match| {
+        query::<&OwnedBy, (With<MinionPosition>, With<Replicating>)>(&mut test_match.server)
+            .iter()
+            .any(|owner| owner.0 == ai)
+    });
+    assert!(spawned, "the AI never spawned a unit");
+    assert!(
+        query::<&PlayerId, With<Replicating>>(&mut test_match.server)
+            .iter()
+            .any(|player_id| player_id.0 == ai)
+    );
+}
+
+#[test]
+fn ai_gathering_raises_its_score() {
+    let mut test_match = TestMatch::with_roles(&[]);
+    test_match
+        .server
+        .world_mut()
+        .send_event(AddAi(Difficulty::Hard));
+    test_match.run(2);
+    let ai = query::<&Ai, ()>(&mut test_match.server)[0].client_id;
+
+    let scored = test_match.run_until(64 * 30, |test_match| {
+        query::<&Scoreboard, With<Replicating>>(&mut test_match.server)
+            .iter()
+            .any(|scoreboard| scoreboard.get(&ai).is_some_and(|&apples| apples > 0))
+    });
+    assert!(scored, "the AI never gathered an apple");
+}
+
+#[test]
+fn easy_ai_never_attacks() {
+    let mut test_match = TestMatch::new(1);
+    // Out of the way of the apples, so only an attack would reach them
+    let positions = [Vec2::new(-8.0, -8.0), Vec2::new(8.0, 8.0)];
+    let defenders = positions.map(|position| test_match.spawn_unit(0, UnitKind::Minion, position));
+    test_match
+        .server
+        .world_mut()
+        .send_event(AddAi(Difficulty::Easy));
+
+    let mut cursor = EventCursor::<ServerMessageEvent<ClientMessage>>::default();
+    for _ in 0..64 * 15 {
+        test_match.tick();
+        let world = test_match.server.world();
+        let ais = world
+            .iter_entities()
+            .filter_map(|entity| entity.get::<Ai>())
+            .map(|ai| ai.client_id)
+            .collect::<Vec<_>>();
+        let messages = world.resource::<Events<ServerMessageEvent<ClientMessage>>>();
+        for event in cursor.read(messages) {
+            if let ClientMessage::Target(_, target) = event.message
+                && ais.contains(&event.from())
+            {
+                assert!(
+                    positions.iter().all(|enemy| enemy.distance(target) > 3.0),
+                    "the easy AI sent units at {target}, next to the other team"
+                );
+            }
+        }
+    }
+
+    for defender in defenders {
+        let health = test_match.server.world().get::<Health>(defender).unwrap();
+        assert!(!health.is_damaged(), "the easy AI attacked");
+    }
+}
@@ -0,0 +1,22 @@
+use crate::discovery::{REPLY, ServerInfo, decode_datagram, encode_datagram};
+use crate::game::handshake::BuildInfo;
+
+#[test]
+fn server_info_round_trips_any_protocol_id() {
+    for protocol_id in [0, i64::MAX as u64 + 1, u64::MAX] {
+        let info = ServerInfo {
+            name: "Test server".into(),
+            map: "Meadow".into(),
+            players: 3,
+            max_players: ServerInfo::MAX_PLAYERS,
+            build: BuildInfo {
+                version: "1.0.0".into(),
+                protocol_id,
+            },
+            game_port: 5000,
+            token_port: 5001,
+        };
+        let datagram = encode_datagram(REPLY, &info).expect("Server info could not be encoded");
+        assert_eq!(decode_datagram::<ServerInfo>(REPLY, &datagram), Some(info));
+    }
+}
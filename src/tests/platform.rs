@@ -0,0 +1,184 @@
+use std::sync::{Arc, Mutex};
+
+use bevy::prelude::*;
+use bevy::utils::HashMap;
+use lightyear::prelude::{LinkConditionerConfig, client, server};
+
+use super::headless_app;
+use crate::game::handshake::BuildInfo;
+use crate::networking::{DisconnectReason, NetworkState};
+use crate::platform::{
+    Friend, FriendId, LobbyId, MatchSettings, Platform, PlatformEvent, PlatformServices,
+};
+
+/// A platform with lobbies that only exist in memory, answering requests on its next update like
+/// a real one would.
+#[derive(Default)]
+struct MockPlatform {
+    /// Who hosts each lobby that can be joined, and what they play.
+    lobbies: HashMap<LobbyId, (FriendId, MatchSettings)>,
+    /// The lobby this player is in.
+    lobby: Mutex<Option<LobbyId>>,
+    events: Mutex<Vec<PlatformEvent>>,
+}
+
+impl MockPlatform {
+    fn with_lobby(lobby: LobbyId, host: FriendId, build: BuildInfo) -> Self {
+        let settings = MatchSettings {
+            name: "Friendly match".into(),
+            map: "Meadow".into(),
+            max_players: 4,
+            build,
+        };
+        Self {
+            lobbies: HashMap::from_iter([(lobby, (host, settings))]),
+            ..default()
+        }
+    }
+
+    /// Has the platform tell the game about `event` on its next update.
+    fn send(&self, event: PlatformEvent) {
+        self.events.lock().unwrap().push(event);
+    }
+
+    fn lobby(&self) -> Option<LobbyId> {
+        *self.lobby.lock().unwrap()
+    }
+}
+
+impl PlatformServices for MockPlatform {
+    fn name(&self) -> &'static str {
+        "Mock"
+    }
+
+    fn persona_name(&self) -> Option<String> {
+        None
+    }
+
+    fn friends(&self) -> Vec<Friend> {
+        Vec::new()
+    }
+
+    fn invite(&self, _friend: FriendId) -> Result<(), String> {
+        Ok(())
+    }
+
+    fn update(&self) -> Vec<PlatformEvent> {
+        std::mem::take(&mut self.events.lock().unwrap())
+    }
+
+    fn create_lobby(&self, _settings: &MatchSettings) {
+        let lobby = LobbyId(1);
+        *self.lobby.lock().unwrap() = Some(lobby);
+        self.send(PlatformEvent::LobbyCreated(lobby));
+    }
+
+    fn join_lobby(&self, lobby: LobbyId) {
+        let Some((host, settings)) = self.lobbies.get(&lobby) else {
+            self.send(PlatformEvent::LobbyFailed("No such lobby".into()));
+            return;
+        };
+        *self.lobby.lock().unwrap() = Some(lobby);
+        self.send(PlatformEvent::LobbyJoined {
+            lobby,
+            host: *host,
+            virtual_port: 3,
+            settings: settings.clone(),
+        });
+    }
+
+    fn leave_lobby(&self) {
+        *self.lobby.lock().unwrap() = None;
+    }
+
+    fn client_net_config(
+        &self,
+        _friend: FriendId,
+        _virtual_port: i32,
+        _conditioner: Option<LinkConditionerConfig>,
+    ) -> Option<client::NetConfig> {
+        None
+    }
+
+    fn server_net_config(
+        &self,
+        _conditioner: Option<LinkConditionerConfig>,
+    ) -> Option<server::NetConfig> {
+        None
+    }
+}
+
+/// A game sitting in the menu on `platform`.
+fn menu_app(platform: &Arc<MockPlatform>) -> App {
+    let mut app = headless_app();
+    app.insert_resource(Platform(platform.clone()))
+        .insert_state(NetworkState::Disconnected);
+    app.finish();
+    app.cleanup();
+    app.update();
+    app
+}
+
+/// Where the game is going next, if anywhere.
+fn next_state(app: &App) -> Option<NetworkState> {
+    match app.world().resource::<NextState<NetworkState>>() {
+        NextState::Pending(state) => Some(*state),
+        NextState::Unchanged => None,
+    }
+}
+
+#[test]
+fn accepting_an_invite_connects_to_the_host() {
+    let (lobby, host) = (LobbyId(7), FriendId(42));
+    let platform = Arc::new(MockPlatform::with_lobby(lobby, host, BuildInfo::local()));
+    let mut app = menu_app(&platform);
+
+    platform.send(PlatformEvent::JoinRequested(lobby));
+    app.update();
+    assert_eq!(platform.lobby(), Some(lobby));
+    app.update();
+
+    assert_eq!(
+        next_state(&app),
+        Some(NetworkState::ClientP2p {
+            friend: host,
+            virtual_port: 3,
+        })
+    );
+    assert_eq!(app.world().resource::<DisconnectReason>().0, None);
+}
+
+#[test]
+fn lobbies_of_incompatible_builds_are_left() {
+    let lobby = LobbyId(7);
+    let build = BuildInfo {
+        version: "0.0.0".into(),
+        protocol_id: 0,
+    };
+    let platform = Arc::new(MockPlatform::with_lobby(lobby, FriendId(42), build));
+    let mut app = menu_app(&platform);
+
+    platform.send(PlatformEvent::JoinRequested(lobby));
+    app.update();
+    app.update();
+
+    assert_eq!(platform.lobby(), None);
+    assert_eq!(next_state(&app), None);
+    assert!(app.world().resource::<DisconnectReason>().0.is_some());
+}
+
+#[test]
+fn failing_to_join_a_lobby_says_why() {
+    let platform = Arc::new(MockPlatform::default());
+    let mut app = menu_app(&platform);
+
+    platform.send(PlatformEvent::JoinRequested(LobbyId(7)));
+    app.update();
+    app.update();
+
+    assert_eq!(next_state(&app), None);
+    assert_eq!(
+        app.world().resource::<DisconnectReason>().0.as_deref(),
+        Some("No such lobby")
+    );
+}
@@ -0,0 +1,28 @@
+use crate::game::handshake::BuildInfo;
+use crate::game::{PROTOCOL_ID, protocol_id, protocol_registrations};
+
+#[test]
+fn protocol_id_follows_the_registrations() {
+    let registrations = protocol_registrations();
+    assert_eq!(protocol_id(&registrations), *PROTOCOL_ID);
+
+    let mut reordered = registrations.clone();
+    reordered.swap(1, 2);
+    let mut extended = registrations.clone();
+    extended.push("component aoe::game::NewComponent Simple".into());
+    let mut resynced = registrations.clone();
+    let once = resynced
+        .iter_mut()
+        .find(|registration| registration.ends_with(" Once"))
+        .expect("Some component is only synced once");
+    *once = once.replace(" Once", " Full");
+
+    for changed in [&reordered, &extended, &resynced] {
+        assert_ne!(changed, &registrations);
+        let other = BuildInfo {
+            version: env!("CARGO_PKG_VERSION").into(),
+            protocol_id: protocol_id(changed),
+        };
+        assert!(!BuildInfo::local().is_compatible(&other));
+    }
+}
@@ -0,0 +1,190 @@
+use std::time::Duration;
+
+use bevy::prelude::*;
+use lightyear::prelude::client::{Confirmed, Interpolated, Predicted};
+use lightyear::prelude::*;
+
+use super::{TestMatch, query};
+use crate::ai::{AddAi, Ai, Difficulty};
+use crate::discovery::ServerInfo;
+use crate::game::minion::{MinionPosition, UnitKind};
+use crate::game::player::{PlayerId, PlayerName, Team};
+use crate::game::resource::Scoreboard;
+use crate::game::{Channel1, ClaimSecret, ClientMessage, Replayed, Role};
+use crate::server::SpectatorDelay;
+use crate::spectator::Spectating;
+
+#[test]
+fn every_client_sees_every_player() {
+    let mut test_match = TestMatch::new(2);
+    test_match.run(64);
+
+    for (index, client) in test_match.clients.iter_mut().enumerate() {
+        let own = ClientId::Netcode(TestMatch::client_id(index));
+        let predicted = query::<&PlayerId, With<Predicted>>(client);
+        assert_eq!(
+            predicted.len(),
+            1,
+            "client {index} predicts only its own player"
+        );
+        assert_eq!(predicted[0].0, own);
+
+        let interpolated = query::<&PlayerId, With<Interpolated>>(client);
+        assert_eq!(
+            interpolated.len(),
+            1,
+            "client {index} interpolates the other player"
+        );
+        assert_ne!(interpolated[0].0, own);
+    }
+}
+
+#[test]
+fn names_are_replicated() {
+    let mut test_match = TestMatch::new(2);
+    let replicated = test_match.run_until(64, |test_match| {
+        let names = query::<&PlayerName, With<Interpolated>>(&mut test_match.clients[0]);
+        names.iter().any(|name| name.0 == "Client 1")
+    });
+    assert!(replicated, "client 0 never saw client 1's name");
+}
+
+#[test]
+fn move_order_reaches_the_server_and_minions_converge() {
+    let mut test_match = TestMatch::new(1);
+    let minion = test_match.spawn_unit(0, UnitKind::Minion, Vec2::ZERO);
+
+    let arrived = test_match.run_until(64, |test_match| {
+        !query::<Entity, (With<MinionPosition>, With<Confirmed>)>(&mut test_match.clients[0])
+            .is_empty()
+    });
+    assert!(arrived, "the minion was never replicated to its owner");
+
+    // Orders name the confirmed entity, which the server maps back to its own
+    let client = &mut test_match.clients[0];
+    let confirmed = query::<Entity, (With<MinionPosition>, With<Confirmed>)>(client)[0];
+    let target = Vec2::new(3.0, 0.0);
+    client
+        .world_mut()
+        .resource_mut::<ClientConnectionManager>()
+        .send_message::<Channel1, _>(&ClientMessage::Target(vec![confirmed], target))
+        .unwrap();
+
+    let converged = test_match.run_until(64 * 6, |test_match| {
+        let server_position = *test_match
+            .server
+            .world()
+            .get::<MinionPosition>(minion)
+            .unwrap();
+        let client_positions =
+            query::<&MinionPosition, With<Predicted>>(&mut test_match.clients[0]);
+        server_position.distance(target) < 0.01
+            && client_positions
+                .iter()
+                .all(|position| position.distance(target) < 0.01)
+    });
+    assert!(converged, "the minion didn't end up where it was sent");
+}
+
+#[test]
+fn scoreboard_counts_apples_picked_by_minions() {
+    let mut test_match = TestMatch::new(1);
+    // The apple lies at (2, 2)
+    test_match.spawn_unit(0, UnitKind::Minion, Vec2::new(2.0, 2.0));
+
+    let own = ClientId::Netcode(TestMatch::client_id(0));
+    let scored = test_match.run_until(64 * 3, |test_match| {
+        query::<&Scoreboard, ()>(&mut test_match.clients[0])
+            .iter()
+            .any(|scoreboard| scoreboard.get(&own).is_some_and(|&apples| apples > 0))
+    });
+    assert!(scored, "the scoreboard on the client never went up");
+}
+
+#[test]
+fn spectators_get_no_player() {
+    let mut test_match = TestMatch::with_roles(&[Role::Player, Role::Spectator]);
+    test_match.run(64);
+
+    let spectator = &mut test_match.clients[1];
+    assert!(spectator.world().contains_resource::<Spectating>());
+    assert!(query::<&PlayerId, With<Predicted>>(spectator).is_empty());
+    // They watch from snapshots rather than replication
+    assert!(query::<&PlayerId, With<Interpolated>>(spectator).is_empty());
+    assert_eq!(query::<&PlayerId, With<Replayed>>(spectator).len(), 1);
+}
+
+#[test]
+fn spectators_see_the_match_late() {
+    let mut test_match = TestMatch::with_roles(&[Role::Player, Role::Spectator]);
+    test_match
+        .server
+        .insert_resource(SpectatorDelay(Duration::from_secs(1)));
+    test_match.spawn_unit(0, UnitKind::Minion, Vec2::ZERO);
+
+    // Half a second in the player has long seen it, and the spectator hasn't yet
+    test_match.run(32);
+    assert!(!query::<&MinionPosition, With<Confirmed>>(&mut test_match.clients[0]).is_empty());
+    assert!(query::<&MinionPosition, With<Replayed>>(&mut test_match.clients[1]).is_empty());
+
+    let seen = test_match.run_until(64 * 2, |test_match| {
+        !query::<&MinionPosition, With<Replayed>>(&mut test_match.clients[1]).is_empty()
+    });
+    assert!(seen, "the spectator never saw the unit");
+}
+
+#[test]
+fn teams_stay_even_until_the_match_is_full() {
+    let mut test_match = TestMatch::new(1);
+    for _ in 0..ServerInfo::MAX_PLAYERS {
+        test_match
+            .server
+            .world_mut()
+            .send_event(AddAi(Difficulty::Easy));
+    }
+    let full = test_match.run_until(64, |test_match| {
+        query::<&PlayerId, With<Replicating>>(&mut test_match.server).len()
+            == ServerInfo::MAX_PLAYERS as usize
+    });
+    assert!(full, "the AI players never filled the match");
+
+    // Anyone else is turned away, AI or not
+    let late = ClientId::Local(1000);
+    test_match
+        .server
+        .world_mut()
+        .send_event(ServerMessageEvent::new(
+            ClientMessage::Join(Role::Player, ClaimSecret::default()),
+            late,
+        ));
+    test_match.run(8);
+
+    let teams = query::<&Team, (With<PlayerId>, With<Replicating>)>(&mut test_match.server);
+    assert_eq!(teams.len(), ServerInfo::MAX_PLAYERS as usize);
+    assert_eq!(
+        teams.iter().filter(|team| team.0 == 0).count(),
+        teams.iter().filter(|team| team.0 == 1).count(),
+    );
+    assert_eq!(
+        query::<&Ai, ()>(&mut test_match.server).len(),
+        ServerInfo::MAX_PLAYERS as usize - 1
+    );
+}
+
+#[test]
+fn only_clients_past_the_handshake_can_join() {
+    let mut test_match = TestMatch::new(1);
+    let stranger = ClientId::Netcode(TestMatch::client_id(1));
+    test_match
+        .server
+        .world_mut()
+        .send_event(ServerMessageEvent::new(
+            ClientMessage::Join(Role::Player, ClaimSecret::default()),
+            stranger,
+        ));
+    test_match.run(8);
+
+    let players = query::<&PlayerId, With<Replicating>>(&mut test_match.server);
+    assert_eq!(players.len(), 1);
+    assert!(players.iter().all(|player| player.0 != stranger));
+}
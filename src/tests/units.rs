@@ -0,0 +1,151 @@
+use bevy::ecs::system::RunSystemOnce;
+use bevy::prelude::*;
+use leafwing_input_manager::prelude::ActionState;
+use lightyear::prelude::*;
+
+use super::{TestMatch, query};
+use crate::game::minion::UnitKind;
+use crate::game::player::{PlayerActions, PlayerColor, PlayerPosition, shared_movement_behaviour};
+use crate::game::resource::Apples;
+use crate::game::status::Health;
+use crate::game::{ClientMessage, OwnedBy};
+
+/// Sets how many apples the client at `index` has on the server.
+fn set_apples(test_match: &mut TestMatch, index: usize, apples: u64) {
+    let client_id = ClientId::Netcode(TestMatch::client_id(index));
+    let world = test_match.server.world_mut();
+    let mut all_apples = world.query::<&mut Apples>().single_mut(world);
+    all_apples.insert(client_id, apples);
+}
+
+fn apples(test_match: &mut TestMatch, index: usize) -> u64 {
+    let client_id = ClientId::Netcode(TestMatch::client_id(index));
+    query::<&Apples, With<Replicating>>(&mut test_match.server)[0]
+        .get(&client_id)
+        .copied()
+        .unwrap_or(0)
+}
+
+fn count(test_match: &mut TestMatch, kind: UnitKind) -> usize {
+    query::<&UnitKind, (With<OwnedBy>, With<Replicating>)>(&mut test_match.server)
+        .into_iter()
+        .filter(|&&other| other == kind)
+        .count()
+}
+
+/// Sends the server a message as if the client at `index` had.
+fn send(test_match: &mut TestMatch, index: usize, message: ClientMessage) {
+    let client_id = ClientId::Netcode(TestMatch::client_id(index));
+    test_match
+        .server
+        .world_mut()
+        .send_event(ServerMessageEvent::new(message, client_id));
+}
+
+#[test]
+fn training_costs_apples() {
+    let mut test_match = TestMatch::new(1);
+    let minion = test_match.spawn_unit(0, UnitKind::Minion, Vec2::new(-5.0, -5.0));
+    set_apples(&mut test_match, 0, UnitKind::Barracks.cost());
+
+    send(
+        &mut test_match,
+        0,
+        ClientMessage::Train(vec![minion], UnitKind::Barracks),
+    );
+    test_match.run(2);
+    assert_eq!(count(&mut test_match, UnitKind::Barracks), 1);
+    assert_eq!(apples(&mut test_match, 0), 0);
+
+    // Nothing left to pay for another
+    send(
+        &mut test_match,
+        0,
+        ClientMessage::Train(vec![minion], UnitKind::Barracks),
+    );
+    test_match.run(2);
+    assert_eq!(count(&mut test_match, UnitKind::Barracks), 1);
+}
+
+#[test]
+fn spawning_costs_apples() {
+    let mut world = World::new();
+    let spawn = |mut apples: u64| {
+        move |mut commands: Commands| {
+            let mut action_state = ActionState::<PlayerActions>::default();
+            action_state.press(&PlayerActions::Spawn);
+            shared_movement_behaviour(
+                &action_state,
+                &mut commands,
+                &mut PlayerPosition(Vec2::ZERO),
+                PlayerColor(Color::WHITE),
+                &mut apples,
+                &Time::default(),
+                Tick(0),
+                ClientId::Local(1),
+                (),
+            );
+            apples
+        }
+    };
+
+    let left = world
+        .run_system_once(spawn(UnitKind::Minion.cost()))
+        .unwrap();
+    assert_eq!(left, 0);
+    let left = world
+        .run_system_once(spawn(UnitKind::Minion.cost() - 1))
+        .unwrap();
+    assert_eq!(left, UnitKind::Minion.cost() - 1);
+    assert_eq!(world.query::<&UnitKind>().iter(&world).count(), 1);
+}
+
+#[test]
+fn barracks_train_minions() {
+    let mut test_match = TestMatch::new(1);
+    let barracks = test_match.spawn_unit(0, UnitKind::Barracks, Vec2::new(-5.0, -5.0));
+    set_apples(&mut test_match, 0, UnitKind::Minion.cost());
+
+    send(
+        &mut test_match,
+        0,
+        ClientMessage::Train(vec![barracks], UnitKind::Minion),
+    );
+    test_match.run(2);
+    assert_eq!(count(&mut test_match, UnitKind::Minion), 1);
+    assert_eq!(apples(&mut test_match, 0), 0);
+}
+
+#[test]
+fn enemies_in_range_fight_to_the_death() {
+    let mut test_match = TestMatch::new(2);
+    // The two clients are on different teams
+    let allies = [
+        test_match.spawn_unit(0, UnitKind::Minion, Vec2::new(5.0, -5.0)),
+        test_match.spawn_unit(0, UnitKind::Minion, Vec2::new(5.0, -4.5)),
+    ];
+    let fighters = [
+        test_match.spawn_unit(0, UnitKind::Minion, Vec2::new(-5.0, -5.0)),
+        test_match.spawn_unit(1, UnitKind::Minion, Vec2::new(-5.0, -4.5)),
+    ];
+
+    test_match.run(64);
+    for fighter in fighters {
+        let health = test_match.server.world().get::<Health>(fighter).unwrap();
+        assert!(
+            health.is_damaged(),
+            "enemies next to each other didn't fight"
+        );
+    }
+
+    let dead = test_match.run_until(64 * 10, |test_match| {
+        fighters
+            .iter()
+            .all(|&fighter| test_match.server.world().get_entity(fighter).is_err())
+    });
+    assert!(dead, "the fight never ended");
+    for ally in allies {
+        let health = test_match.server.world().get::<Health>(ally).unwrap();
+        assert!(!health.is_damaged(), "allies hurt each other");
+    }
+}
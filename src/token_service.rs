@@ -0,0 +1,196 @@
+use std::io::{BufRead, BufReader, ErrorKind, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use bevy::prelude::*;
+use lightyear::connection::netcode::{CONNECT_TOKEN_BYTES, ConnectToken};
+
+use crate::auth::ServerKey;
+use crate::game::handshake::BuildInfo;
+
+/// How long a client waits for the token service before giving up.
+const FETCH_TIMEOUT: Duration = Duration::from_secs(2);
+/// How often the service checks whether it should stop while no one is connecting.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+/// Longest request the service reads, plenty for a build line.
+const MAX_REQUEST_BYTES: u64 = 256;
+/// Most clients served at once, others are turned away until one is done.
+const MAX_HANDLERS: usize = 32;
+
+/// TCP port of the token service, on the same host as the game server.
+#[derive(Resource, Clone, Copy, Debug)]
+pub struct TokenServicePort(pub u16);
+
+/// Hands out connect tokens over TCP, so clients never need the server's private key. A client
+/// connects and sends a line with its [`BuildInfo`]. If it is compatible the service answers with
+/// `OK` and a token for a client id it picks, otherwise with `INCOMPATIBLE` and its own build.
+/// Runs on a background thread until dropped.
+#[derive(Resource)]
+pub struct TokenService {
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl TokenService {
+    /// Listens on `addr` and issues tokens for the game server at `server_addr`.
+    pub fn start(
+        addr: SocketAddr,
+        server_addr: SocketAddr,
+        key: ServerKey,
+    ) -> std::io::Result<Self> {
+        let listener = TcpListener::bind(addr)?;
+        listener.set_nonblocking(true)?;
+        info!(%addr, %server_addr, "Token service listening");
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let handlers = Arc::new(AtomicUsize::new(0));
+        let thread = std::thread::spawn({
+            let stop = stop.clone();
+            move || {
+                while !stop.load(Ordering::Relaxed) {
+                    match listener.accept() {
+                        // Each on its own thread, so a slow client can't hold up the others
+                        Ok((stream, peer)) => {
+                            if handlers.fetch_add(1, Ordering::AcqRel) >= MAX_HANDLERS {
+                                handlers.fetch_sub(1, Ordering::AcqRel);
+                                warn!(%peer, "Token service is busy, dropped a connection");
+                                continue;
+                            }
+                            let handlers = handlers.clone();
+                            std::thread::spawn(move || {
+                                if let Err(err) = issue(stream, server_addr, key) {
+                                    warn!(%peer, "Could not issue connect token: {err}");
+                                }
+                                handlers.fetch_sub(1, Ordering::AcqRel);
+                            });
+                        }
+                        Err(err) if err.kind() == ErrorKind::WouldBlock => {
+                            std::thread::sleep(POLL_INTERVAL);
+                        }
+                        Err(err) => warn!("Token service could not accept a connection: {err}"),
+                    }
+                }
+            }
+        });
+
+        Ok(Self {
+            stop,
+            thread: Some(thread),
+        })
+    }
+
+    /// Blocks until the service stops, which only happens if its thread panics.
+    pub fn join(mut self) {
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Drop for TokenService {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+fn issue(stream: TcpStream, server_addr: SocketAddr, key: ServerKey) -> Result<(), String> {
+    stream
+        .set_nonblocking(false)
+        .map_err(|err| err.to_string())?;
+    stream
+        .set_read_timeout(Some(FETCH_TIMEOUT))
+        .map_err(|err| err.to_string())?;
+
+    // A line longer than this is cut short and fails to parse
+    let mut reader = BufReader::new(stream.take(MAX_REQUEST_BYTES));
+    let mut line = String::new();
+    reader.read_line(&mut line).map_err(|err| err.to_string())?;
+    let client = parse_build_info(&line).ok_or("Invalid request")?;
+    let mut stream = reader.into_inner().into_inner();
+
+    let local = BuildInfo::local();
+    if !local.is_compatible(&client) {
+        info!(version = %client, "Refused a token to an incompatible client");
+        return write!(stream, "INCOMPATIBLE {}", format_build_info(&local))
+            .map_err(|err| err.to_string());
+    }
+
+    let client_id = rand::random();
+    let token = key.issue_token(server_addr, client_id)?;
+    let bytes = token.try_into_bytes().map_err(|err| err.to_string())?;
+    stream
+        .write_all(b"OK\n")
+        .and_then(|()| stream.write_all(&bytes))
+        .map_err(|err| err.to_string())?;
+    info!(client_id, "Issued connect token");
+    Ok(())
+}
+
+#[derive(Debug)]
+pub enum FetchError {
+    Io(std::io::Error),
+    /// The server runs this build, which we can't connect to.
+    Incompatible(BuildInfo),
+}
+
+impl From<std::io::Error> for FetchError {
+    fn from(err: std::io::Error) -> Self {
+        FetchError::Io(err)
+    }
+}
+
+impl std::fmt::Display for FetchError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FetchError::Io(err) => err.fmt(f),
+            FetchError::Incompatible(server) => f.write_str(&BuildInfo::mismatch_reason(server)),
+        }
+    }
+}
+
+/// Asks the token service at `addr` for a connect token.
+pub fn fetch_token(addr: SocketAddr) -> Result<ConnectToken, FetchError> {
+    let mut stream = TcpStream::connect_timeout(&addr, FETCH_TIMEOUT)?;
+    stream.set_read_timeout(Some(FETCH_TIMEOUT))?;
+    writeln!(stream, "{}", format_build_info(&BuildInfo::local()))?;
+
+    let mut reader = BufReader::new(stream);
+    let mut status = String::new();
+    reader.read_line(&mut status)?;
+    if let Some(server) = status.strip_prefix("INCOMPATIBLE ") {
+        let server = parse_build_info(server).ok_or_else(invalid_response)?;
+        return Err(FetchError::Incompatible(server));
+    }
+    if status.trim_end() != "OK" {
+        return Err(invalid_response().into());
+    }
+
+    let mut bytes = [0; CONNECT_TOKEN_BYTES];
+    reader.read_exact(&mut bytes)?;
+    ConnectToken::try_from_bytes(&bytes).map_err(|_| invalid_response().into())
+}
+
+fn invalid_response() -> std::io::Error {
+    std::io::Error::new(
+        ErrorKind::InvalidData,
+        "invalid response from token service",
+    )
+}
+
+fn format_build_info(build: &BuildInfo) -> String {
+    format!("{:016x} {}", build.protocol_id, build.version)
+}
+
+fn parse_build_info(line: &str) -> Option<BuildInfo> {
+    let (protocol_id, version) = line.trim_end().split_once(' ')?;
+    Some(BuildInfo {
+        version: version.into(),
+        protocol_id: u64::from_str_radix(protocol_id, 16).ok()?,
+    })
+}